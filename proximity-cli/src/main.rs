@@ -1,18 +1,41 @@
 use futures::StreamExt;
 use human_format::{Formatter, Scales};
-use proximity_grpc::proximity_db_client::ProximityDbClient;
-use proximity_grpc::{AddRequest, ListRequest, Point as GrpcPoint, SearchRequest};
+use proximity_client::{Client, ClientConfig};
 use rand::distributions::Standard;
 use rand::Rng;
 use stats::MinMax;
-use std::time::Instant;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use tonic::transport::Channel;
-use tonic::Request;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
-enum Opt {
+struct Opt {
+    #[structopt(long, default_value = "http://[::1]:50051", env = "PROXIMITY_ADDRESS")]
+    /// The server to connect to. Use an https:// address to connect with TLS
+    address: String,
+    #[structopt(long, env = "PROXIMITY_TOKEN")]
+    /// A bearer token to authenticate with, for servers that require one
+    token: Option<String>,
+    #[structopt(long, env = "PROXIMITY_TLS_CA")]
+    /// A PEM encoded CA certificate to verify the server with
+    tls_ca: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_DOMAIN")]
+    /// The name to expect in the server's certificate, if it isn't the address's host
+    tls_domain: Option<String>,
+    #[structopt(long, env = "PROXIMITY_TLS_CERT", requires = "tls-key")]
+    /// A PEM encoded client certificate, for servers that require one
+    tls_cert: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_KEY", requires = "tls-cert")]
+    /// The PEM encoded private key for the client certificate
+    tls_key: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
     Fill {
         name: String,
         #[structopt(short, long)]
@@ -23,6 +46,9 @@ enum Opt {
         parallel: usize,
         #[structopt(short, long, default_value = "1")]
         batch_size: usize,
+        #[structopt(long, default_value = "0")]
+        /// Expire the points after this many seconds. 0 uses the constellation's default
+        ttl: u64,
     },
 
     List {
@@ -37,22 +63,37 @@ enum Opt {
         #[structopt(short, long, default_value = "0.1")]
         within: f32,
     },
+
+    Stats,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Opt::from_args();
-    let client = ProximityDbClient::connect("http://[::1]:50051").await?;
-    match opt {
-        Opt::Fill {
+    let mut config = config(&opt)?;
+    if let Command::Fill {
+        parallel,
+        batch_size,
+        ..
+    } = opt.command
+    {
+        config = config
+            .with_parallelism(parallel)
+            .with_batch_size(batch_size);
+    }
+    let client = config.connect().await?;
+    match opt.command {
+        Command::Fill {
             name,
             dimensions,
             number,
             parallel,
             batch_size,
-        } => fill(client, name, dimensions, number, parallel, batch_size).await,
-        Opt::List { prefix } => list(client, prefix).await,
-        Opt::Search {
+            ttl,
+        } => fill(client, name, dimensions, number, parallel, batch_size, ttl).await,
+        Command::List { prefix } => list(client, prefix).await,
+        Command::Stats => stats(client).await,
+        Command::Search {
             name,
             dimensions,
             within,
@@ -60,32 +101,46 @@ async fn main() -> anyhow::Result<()> {
     }
 }
 
+fn config(opt: &Opt) -> anyhow::Result<ClientConfig> {
+    let mut config = ClientConfig::new(opt.address.clone());
+    if opt.address.starts_with("https://") || opt.tls_ca.is_some() || opt.tls_cert.is_some() {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca) = &opt.tls_ca {
+            tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let Some(domain) = &opt.tls_domain {
+            tls = tls.domain_name(domain.clone());
+        }
+        if let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) {
+            tls = tls.identity(Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ));
+        }
+        config = config.with_tls(tls);
+    }
+    if let Some(token) = &opt.token {
+        config = config.with_token(token);
+    }
+    Ok(config)
+}
+
 async fn search(
-    mut client: ProximityDbClient<Channel>,
+    client: Client,
     name: String,
     dimensions: usize,
     within: f32,
 ) -> anyhow::Result<()> {
     let rng = rand::thread_rng();
-    let random_point = GrpcPoint {
-        coords: rng.sample_iter(Standard).take(dimensions).collect(),
-    };
-
-    let result_stream = client
-        .search(Request::new(SearchRequest {
-            distance: within,
-            name,
-            point: Some(random_point),
-        }))
-        .await?;
+    let random_point = rng.sample_iter(Standard).take(dimensions).collect();
 
-    let mut inbound = result_stream.into_inner();
+    let mut results = client.search(name, random_point, within).await?;
 
     let mut stats = MinMax::new();
     println!("Searching...");
     let now = Instant::now();
-    while let Some(feature) = inbound.message().await? {
-        stats.add(feature.distance);
+    while let Some(neighbour) = results.next().await {
+        stats.add(neighbour?.distance);
     }
     println!("Elapsed: {:?}", now.elapsed());
     println!("Total results: {}", stats.len());
@@ -96,51 +151,31 @@ async fn search(
 }
 
 async fn fill(
-    client: ProximityDbClient<Channel>,
+    client: Client,
     name: String,
     dimensions: usize,
     number: usize,
     parallel: usize,
     batch_size: usize,
+    ttl_seconds: u64,
 ) -> anyhow::Result<()> {
     let rng = rand::thread_rng();
 
     // Create our random points
-    let mut items: Vec<Vec<GrpcPoint>> = vec![];
-    for _ in (0..number).step_by(batch_size) {
-        items.push(
-            (0..batch_size)
-                .map(|_| GrpcPoint {
-                    coords: rng.sample_iter(Standard).take(dimensions).collect(),
-                })
-                .collect(),
-        );
-    }
-
-    let mut request_futures = futures::stream::iter(items.into_iter().map(|batch| {
-        async {
-            // This is super cheap, see https://github.com/hyperium/tonic/issues/285
-            let mut cloned_client = client.clone();
-            cloned_client
-                .add(Request::new(futures::stream::iter(vec![AddRequest {
-                    name: name.clone(),
-                    points: batch,
-                }])))
-                .await?;
-            // See https://github.com/rust-lang/rust/issues/63502#issuecomment-520647948
-            Ok::<(), anyhow::Error>(())
-        }
-    }))
-    .buffer_unordered(parallel);
+    let points: Vec<Vec<f32>> = (0..number)
+        .map(|_| rng.sample_iter(Standard).take(dimensions).collect())
+        .collect();
 
     println!(
-        "Sending {} batches of {} points, with {} parallel requests",
+        "Sending {} points in batches of {}, with {} parallel requests",
         number, batch_size, parallel
     );
+    let ttl = match ttl_seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
     let started = Instant::now();
-    while let Some(feature) = request_futures.next().await {
-        feature?;
-    }
+    client.add_with_ttl(name, points, ttl).await?;
     let elapsed = started.elapsed();
     println!("Completed in {}ms", elapsed.as_millis());
     println!(
@@ -150,16 +185,14 @@ async fn fill(
     Ok(())
 }
 
-async fn list(mut client: ProximityDbClient<Channel>, prefix: String) -> anyhow::Result<()> {
+async fn list(client: Client, prefix: String) -> anyhow::Result<()> {
     let mut bytes_formatter = Formatter::new();
     bytes_formatter.with_scales(Scales::Binary());
 
     let mut count_formatter = Formatter::new();
     count_formatter.with_decimals(1);
 
-    let result = client.list(Request::new(ListRequest { prefix })).await?;
-    let mut result_stream = result.into_inner();
-    while let Some(feature) = result_stream.message().await? {
+    for feature in client.list(prefix).await? {
         println!(" - name : {}", feature.name);
         println!("   dims : {}", feature.dimensions);
         println!("   count: {}", count_formatter.format(feature.count as f64));
@@ -167,6 +200,33 @@ async fn list(mut client: ProximityDbClient<Channel>, prefix: String) -> anyhow:
             "   size : {}",
             bytes_formatter.format(feature.memory_size as f64)
         );
+        if feature.pending_expiry > 0 {
+            println!(
+                "   expiring: {}",
+                count_formatter.format(feature.pending_expiry as f64)
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn stats(client: Client) -> anyhow::Result<()> {
+    let mut bytes_formatter = Formatter::new();
+    bytes_formatter.with_scales(Scales::Binary());
+
+    let stats = client.stats().await?;
+    println!("constellations: {}", stats.constellations);
+    println!("count         : {}", stats.count);
+    match stats.memory_limit {
+        None => println!(
+            "size          : {}",
+            bytes_formatter.format(stats.memory_size as f64)
+        ),
+        Some(limit) => println!(
+            "size          : {} of {}",
+            bytes_formatter.format(stats.memory_size as f64),
+            bytes_formatter.format(limit as f64)
+        ),
     }
     Ok(())
 }
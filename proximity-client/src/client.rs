@@ -0,0 +1,340 @@
+use crate::config::ClientConfig;
+use crate::retry::{retry, Backoff};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use proximity_grpc::proximity_db_client::ProximityDbClient;
+use proximity_grpc::{
+    AddRequest, CreateRequest, DeleteRequest, DescribeRequest, DescribeResponse, ListRequest,
+    Point as GrpcPoint, QuotaUsage, SearchRequest, SearchResponse, StatsRequest,
+};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tonic::transport::Channel;
+use tonic::{Code, Interceptor, Status, Streaming};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Invalid address {0:?}")]
+    InvalidAddress(String),
+    #[error("The token can't be sent in a header")]
+    InvalidToken,
+    #[error("Could not connect within {0:?}")]
+    ConnectTimeout(Duration),
+    #[error("Could not connect: {0}")]
+    Connect(#[from] tonic::transport::Error),
+    #[error("{:?}: {}", .0.code(), .0.message())]
+    Status(#[from] Status),
+}
+
+impl ClientError {
+    /// The code the server returned, if the request got that far.
+    pub fn code(&self) -> Option<Code> {
+        match self {
+            ClientError::Status(status) => Some(status.code()),
+            _ => None,
+        }
+    }
+}
+
+/// A constellation, as described by the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constellation {
+    pub name: String,
+    pub dimensions: usize,
+    pub count: u64,
+    /// Deleted points that haven't been compacted away yet.
+    pub dead_count: u64,
+    pub memory_size: u64,
+    /// How long points last by default, if they expire.
+    pub ttl: Option<Duration>,
+    /// Points that have expired but haven't been removed yet.
+    pub pending_expiry: u64,
+    pub quotas: Vec<QuotaUsage>,
+}
+
+impl From<DescribeResponse> for Constellation {
+    fn from(response: DescribeResponse) -> Self {
+        Constellation {
+            name: response.name,
+            dimensions: response.dimensions as usize,
+            count: response.count,
+            dead_count: response.dead_count,
+            memory_size: response.memory_size,
+            ttl: match response.ttl_seconds {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            pending_expiry: response.pending_expiry,
+            quotas: response.quotas,
+        }
+    }
+}
+
+/// A point found by a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbour {
+    pub distance: f32,
+    pub point: Vec<f32>,
+}
+
+impl From<SearchResponse> for Neighbour {
+    fn from(response: SearchResponse) -> Self {
+        Neighbour {
+            distance: response.distance,
+            point: response.point.map_or_else(Vec::new, |point| point.coords),
+        }
+    }
+}
+
+/// The points found by a search, as the server finds them.
+pub struct SearchResults {
+    inner: Streaming<SearchResponse>,
+}
+
+impl Stream for SearchResults {
+    type Item = Result<Neighbour, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|next| next.map(|result| result.map(Into::into).map_err(Into::into)))
+    }
+}
+
+/// Totals across every constellation on the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub constellations: u64,
+    pub count: u64,
+    pub memory_size: u64,
+    pub memory_limit: Option<u64>,
+}
+
+/// A connection to a server. Cloning it is cheap, and clones share the connection.
+#[derive(Clone)]
+pub struct Client {
+    inner: ProximityDbClient<Channel>,
+    config: Arc<ClientConfig>,
+}
+
+impl Client {
+    pub(crate) fn new(channel: Channel, interceptor: Interceptor, config: ClientConfig) -> Self {
+        Client {
+            inner: ProximityDbClient::with_interceptor(channel, interceptor),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Connects to the server at `address` with the default config.
+    pub async fn connect(address: impl Into<String>) -> Result<Self, ClientError> {
+        ClientConfig::new(address).connect().await
+    }
+
+    fn backoff(&self) -> &Backoff {
+        &self.config.backoff
+    }
+
+    /// Creates an empty constellation, whose points expire after `ttl` by default.
+    pub async fn create(
+        &self,
+        name: impl Into<String>,
+        dimensions: usize,
+        ttl: Option<Duration>,
+    ) -> Result<Constellation, ClientError> {
+        let request = CreateRequest {
+            name: name.into(),
+            dimensions: dimensions as u64,
+            ttl_seconds: ttl.map_or(0, |ttl| ttl.as_secs()),
+        };
+        let response = retry(self.backoff(), || {
+            let mut client = self.inner.clone();
+            let request = request.clone();
+            async move { client.create(request).await }
+        })
+        .await?;
+        Ok(response.into_inner().into())
+    }
+
+    /// Adds points, creating the constellation if it doesn't exist. Returns how many were added.
+    pub async fn add(
+        &self,
+        name: impl Into<String>,
+        points: Vec<Vec<f32>>,
+    ) -> Result<u64, ClientError> {
+        self.add_with_ttl(name, points, None).await
+    }
+
+    /// Adds points that expire after `ttl`, or the constellation's default TTL if that's `None`.
+    ///
+    /// The points are sent in batches, several at a time. If one batch fails, the others may
+    /// still have been added. Batches aren't retried, since a batch that failed may have been
+    /// added anyway and would be added twice.
+    pub async fn add_with_ttl(
+        &self,
+        name: impl Into<String>,
+        points: Vec<Vec<f32>>,
+        ttl: Option<Duration>,
+    ) -> Result<u64, ClientError> {
+        let name = name.into();
+        let ttl_seconds = ttl.map_or(0, |ttl| ttl.as_secs());
+        let requests = batches(points, self.config.batch_size)
+            .into_iter()
+            .map(|points| AddRequest {
+                name: name.clone(),
+                points,
+                ttl_seconds,
+                ..Default::default()
+            });
+        stream::iter(requests)
+            .map(|request| self.add_batch(request))
+            .buffer_unordered(self.config.parallelism)
+            .try_fold(0, |total, added| async move { Ok(total + added) })
+            .await
+    }
+
+    async fn add_batch(&self, request: AddRequest) -> Result<u64, ClientError> {
+        let mut client = self.inner.clone();
+        let response = client.add(stream::iter(vec![request])).await?;
+        Ok(response.into_inner().total_added)
+    }
+
+    /// Finds every point within `distance` of `point`.
+    pub async fn search(
+        &self,
+        name: impl Into<String>,
+        point: Vec<f32>,
+        distance: f32,
+    ) -> Result<SearchResults, ClientError> {
+        self.search_nearest(name, point, distance, 0).await
+    }
+
+    /// Finds the `limit` nearest points within `distance` of `point`, nearest first. A limit of 0
+    /// finds them all, in no particular order.
+    pub async fn search_nearest(
+        &self,
+        name: impl Into<String>,
+        point: Vec<f32>,
+        distance: f32,
+        limit: u32,
+    ) -> Result<SearchResults, ClientError> {
+        let request = SearchRequest {
+            name: name.into(),
+            point: Some(GrpcPoint { coords: point }),
+            distance,
+            limit,
+            ..Default::default()
+        };
+        let response = retry(self.backoff(), || {
+            let mut client = self.inner.clone();
+            let request = request.clone();
+            async move { client.search(request).await }
+        })
+        .await?;
+        Ok(SearchResults {
+            inner: response.into_inner(),
+        })
+    }
+
+    /// Lists the constellations whose names start with `prefix`.
+    pub async fn list(&self, prefix: impl Into<String>) -> Result<Vec<Constellation>, ClientError> {
+        let request = ListRequest {
+            prefix: prefix.into(),
+            ..Default::default()
+        };
+        retry(self.backoff(), || {
+            let mut client = self.inner.clone();
+            let request = request.clone();
+            async move {
+                let mut stream = client.list(request).await?.into_inner();
+                let mut constellations = vec![];
+                while let Some(response) = stream.message().await? {
+                    constellations.push(response.into());
+                }
+                Ok(constellations)
+            }
+        })
+        .await
+        .map_err(Into::into)
+    }
+
+    pub async fn describe(&self, name: impl Into<String>) -> Result<Constellation, ClientError> {
+        let request = DescribeRequest {
+            name: name.into(),
+            ..Default::default()
+        };
+        let response = retry(self.backoff(), || {
+            let mut client = self.inner.clone();
+            let request = request.clone();
+            async move { client.describe(request).await }
+        })
+        .await?;
+        Ok(response.into_inner().into())
+    }
+
+    /// Deletes points, returning how many were deleted. This isn't retried, so the count stays
+    /// accurate.
+    pub async fn delete(
+        &self,
+        name: impl Into<String>,
+        points: Vec<Vec<f32>>,
+    ) -> Result<u64, ClientError> {
+        let request = DeleteRequest {
+            name: name.into(),
+            points: points
+                .into_iter()
+                .map(|coords| GrpcPoint { coords })
+                .collect(),
+        };
+        let mut client = self.inner.clone();
+        let response = client.delete(request).await?;
+        Ok(response.into_inner().deleted_count as u64)
+    }
+
+    pub async fn stats(&self) -> Result<Stats, ClientError> {
+        let response = retry(self.backoff(), || {
+            let mut client = self.inner.clone();
+            async move { client.stats(StatsRequest {}).await }
+        })
+        .await?
+        .into_inner();
+        Ok(Stats {
+            constellations: response.constellations,
+            count: response.count,
+            memory_size: response.memory_size,
+            memory_limit: match response.memory_limit {
+                0 => None,
+                limit => Some(limit),
+            },
+        })
+    }
+}
+
+fn batches(points: Vec<Vec<f32>>, size: usize) -> Vec<Vec<GrpcPoint>> {
+    let mut points = points.into_iter().peekable();
+    let mut batches = vec![];
+    while points.peek().is_some() {
+        batches.push(
+            points
+                .by_ref()
+                .take(size)
+                .map(|coords| GrpcPoint { coords })
+                .collect(),
+        );
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batches() {
+        let points: Vec<Vec<f32>> = (0..5).map(|i| vec![i as f32]).collect();
+        let sizes: Vec<usize> = batches(points, 2).iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert!(batches(vec![], 2).is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::client::{Client, ClientError};
+use crate::retry::Backoff;
+use std::time::Duration;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig};
+use tonic::{Interceptor, Request};
+
+/// How to connect to a server, and how to send requests to it.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    address: String,
+    token: Option<String>,
+    tls: Option<ClientTlsConfig>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    pub(crate) backoff: Backoff,
+    pub(crate) batch_size: usize,
+    pub(crate) parallelism: usize,
+}
+
+impl ClientConfig {
+    /// Connects to the server at `address`, e.g. `http://[::1]:4321`.
+    pub fn new(address: impl Into<String>) -> Self {
+        ClientConfig {
+            address: address.into(),
+            token: None,
+            tls: None,
+            connect_timeout: None,
+            timeout: None,
+            backoff: Backoff::default(),
+            batch_size: 1000,
+            parallelism: 8,
+        }
+    }
+
+    /// Authenticates with a bearer token, for servers that require one.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Connects with TLS. This is needed for `https://` addresses.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Gives up connecting after `timeout`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Gives up on each request after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retries requests that fail with a transient error with `backoff`, which by default retries
+    /// 3 times. Only requests that are safe to repeat are retried: adding and deleting points
+    /// isn't, since a request that failed may still have been applied.
+    pub fn with_retries(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Adds points in batches of up to this many, 1000 by default.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sends up to this many batches of points at once, 8 by default.
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    pub async fn connect(self) -> Result<Client, ClientError> {
+        let interceptor = match &self.token {
+            Some(token) => {
+                let authorization = MetadataValue::from_str(&format!("Bearer {}", token))
+                    .map_err(|_| ClientError::InvalidToken)?;
+                Interceptor::new(move |mut request: Request<()>| {
+                    request
+                        .metadata_mut()
+                        .insert("authorization", authorization.clone());
+                    Ok(request)
+                })
+            }
+            None => Interceptor::new(Ok),
+        };
+        let mut endpoint = Channel::from_shared(self.address.clone())
+            .map_err(|_| ClientError::InvalidAddress(self.address.clone()))?;
+        if let Some(tls) = self.tls.clone() {
+            endpoint = endpoint.tls_config(tls);
+        }
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        let channel = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, endpoint.connect())
+                .await
+                .map_err(|_| ClientError::ConnectTimeout(timeout))??,
+            None => endpoint.connect().await?,
+        };
+        Ok(Client::new(channel, interceptor, self))
+    }
+}
@@ -0,0 +1,26 @@
+//! A client for Proximity DB servers.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), proximity_client::ClientError> {
+//! use futures::StreamExt;
+//! use proximity_client::ClientConfig;
+//!
+//! let client = ClientConfig::new("http://[::1]:4321")
+//!     .with_token("secret")
+//!     .connect()
+//!     .await?;
+//! client.add("hello", vec![vec![0.5; 8], vec![1.; 8]]).await?;
+//! let mut results = client.search("hello", vec![0.; 8], 2.).await?;
+//! while let Some(neighbour) = results.next().await {
+//!     println!("{:?}", neighbour?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+mod client;
+mod config;
+pub mod retry;
+
+pub use client::{Client, ClientError, Constellation, Neighbour, SearchResults, Stats};
+pub use config::ClientConfig;
+pub use retry::Backoff;
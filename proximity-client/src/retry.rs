@@ -0,0 +1,127 @@
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// How often, and how long to wait between, retrying requests that fail with a transient error.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// How many times to retry a request before giving up.
+    pub retries: u32,
+    /// How long to wait before the first retry. Each retry waits twice as long as the last.
+    pub initial: Duration,
+    /// The longest to wait between retries.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            retries: 3,
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Backoff {
+    /// Never retries.
+    pub fn none() -> Self {
+        Backoff {
+            retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before retrying for the `attempt`th time, counting from 0. This is
+    /// randomized, so clients that failed together don't all retry at the same moment.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial
+            .checked_mul(1 << attempt.min(16))
+            .map_or(self.max, |delay| delay.min(self.max));
+        delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.))
+    }
+}
+
+/// Whether a request that failed with `status` might succeed if it's sent again.
+///
+/// Only the server being unavailable counts, as other errors, like a quota being exceeded, would
+/// happen again.
+pub fn is_transient(status: &Status) -> bool {
+    status.code() == Code::Unavailable
+}
+
+/// Calls `request` until it succeeds, fails with an error that isn't transient, or runs out of
+/// retries.
+pub async fn retry<T, F, Fut>(backoff: &Backoff, mut request: F) -> Result<T, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(status) if is_transient(&status) && attempt < backoff.retries => {
+                tokio::time::delay_for(backoff.delay(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_delay() {
+        let backoff = Backoff::default();
+        let first = backoff.delay(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let third = backoff.delay(2);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+        assert!(backoff.delay(100) <= backoff.max);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let calls = Cell::new(0);
+        let result = retry(&backoff, || {
+            calls.set(calls.get() + 1);
+            async {
+                match calls.get() {
+                    1 | 2 => Err(Status::new(Code::Unavailable, "Try again")),
+                    _ => Ok(calls.get()),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Errors that would happen again aren't retried, and retries run out.
+        calls.set(0);
+        let result: Result<(), _> = retry(&backoff, || {
+            calls.set(calls.get() + 1);
+            async { Err(Status::new(Code::NotFound, "Missing")) }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().code(), Code::NotFound);
+        assert_eq!(calls.get(), 1);
+
+        calls.set(0);
+        let result: Result<(), _> = retry(&Backoff::none(), || {
+            calls.set(calls.get() + 1);
+            async { Err(Status::new(Code::Unavailable, "Try again")) }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().code(), Code::Unavailable);
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+#[path = "../../proximity-db/tests/common/mod.rs"]
+mod common;
+
+use futures::TryStreamExt;
+use proximity_client::{Client, ClientConfig, ClientError, Neighbour};
+use proximity_db::handler::ProximityDBHandler;
+use proximity_db::sky::Sky;
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use std::net::TcpListener;
+use std::time::Duration;
+use tonic::transport::Server;
+use tonic::Code;
+
+async fn start_server() -> String {
+    let (addr, incoming) = common::listen().await;
+    tokio::spawn(
+        Server::builder()
+            .add_service(ProximityDbServer::new(ProximityDBHandler::new(
+                Sky::default(),
+            )))
+            .serve_with_incoming(incoming),
+    );
+    format!("http://{}", addr)
+}
+
+#[tokio::test]
+async fn test_client() {
+    let client = ClientConfig::new(start_server().await)
+        .with_batch_size(3)
+        .with_parallelism(2)
+        .connect()
+        .await
+        .unwrap();
+
+    let created = client
+        .create("hello", 8, Some(Duration::from_secs(60)))
+        .await
+        .unwrap();
+    assert_eq!(created.ttl, Some(Duration::from_secs(60)));
+    let points: Vec<Vec<f32>> = (0..10).map(|i| vec![i as f32; 8]).collect();
+    assert_eq!(client.add("hello", points).await.unwrap(), 10);
+    assert_eq!(client.add("hello", vec![]).await.unwrap(), 0);
+
+    let mut found: Vec<Neighbour> = client
+        .search("hello", vec![0.; 8], 6.)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    found.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+    let found: Vec<f32> = found.iter().map(|n| n.point[0]).collect();
+    assert_eq!(found, vec![0., 1., 2.]);
+    let nearest: Vec<Neighbour> = client
+        .search_nearest("hello", vec![9.; 8], 100., 1)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(nearest[0].point, vec![9.; 8]);
+
+    assert_eq!(client.delete("hello", vec![vec![0.; 8]]).await.unwrap(), 1);
+    let described = client.describe("hello").await.unwrap();
+    assert_eq!((described.dimensions, described.count), (8, 9));
+    let listed = client.list("hel").await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(client.stats().await.unwrap().count, 9);
+
+    let error = client.describe("missing").await.unwrap_err();
+    assert_eq!(error.code(), Some(Code::NotFound));
+    let error = client.add("hello", vec![vec![1.; 64]]).await.unwrap_err();
+    assert_eq!(error.code(), Some(Code::InvalidArgument));
+}
+
+#[tokio::test]
+async fn test_connect_errors() {
+    let address = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    assert!(matches!(
+        Client::connect(address.clone()).await,
+        Err(ClientError::Connect(_))
+    ));
+    assert!(matches!(
+        Client::connect("not an address").await,
+        Err(ClientError::InvalidAddress(_))
+    ));
+    assert!(matches!(
+        ClientConfig::new(address)
+            .with_token("not\na header")
+            .connect()
+            .await,
+        Err(ClientError::InvalidToken)
+    ));
+}
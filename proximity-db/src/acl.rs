@@ -0,0 +1,234 @@
+//! Controls which constellations each principal can use.
+//!
+//! An ACL file has one `principal role pattern` grant per line, e.g. `analytics write analytics-*`,
+//! which applies to every constellation the pattern matches, in the same syntax as quotas. A
+//! pattern of `*` covers every constellation, and a principal of `*` covers every caller. Each role
+//! includes the ones before it:
+//!
+//! * `read` allows searching, listing and describing constellations.
+//! * `write` allows adding and deleting points.
+//! * `admin` allows creating, dropping and compacting constellations.
+use crate::pattern::{InvalidPattern, Pattern};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+use tonic::{Code, Status};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read,
+    Write,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AclError {
+    #[error("Error reading the ACL file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {0} of the ACL file is not a principal, role and pattern")]
+    InvalidLine(usize),
+    #[error("Line {0} of the ACL file has an invalid pattern: {1}")]
+    InvalidPattern(usize, InvalidPattern),
+}
+
+#[derive(Error, Debug)]
+#[error("{} does not have {role} access to {name:?}", principal.as_deref().unwrap_or("An unauthenticated caller"))]
+pub struct PermissionDenied {
+    principal: Option<String>,
+    name: String,
+    role: Role,
+}
+
+impl From<PermissionDenied> for Status {
+    fn from(e: PermissionDenied) -> Self {
+        Status::new(Code::PermissionDenied, e.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct Grant {
+    /// `None` grants the role to every caller.
+    principal: Option<String>,
+    pattern: Pattern,
+    role: Role,
+}
+
+#[derive(Debug, Default)]
+pub struct Acl {
+    grants: Vec<Grant>,
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> Result<Self, AclError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Whether `principal` has at least `role` on the constellation `name`.
+    pub fn allows(&self, principal: Option<&str>, name: &str, role: Role) -> bool {
+        self.grants.iter().any(|grant| {
+            grant.role >= role
+                && grant.pattern.matches(name)
+                && (grant.principal.is_none() || grant.principal.as_deref() == principal)
+        })
+    }
+
+    pub fn check(
+        &self,
+        principal: Option<&str>,
+        name: &str,
+        role: Role,
+    ) -> Result<(), PermissionDenied> {
+        if self.allows(principal, name, role) {
+            Ok(())
+        } else {
+            Err(PermissionDenied {
+                principal: principal.map(Into::into),
+                name: name.into(),
+                role,
+            })
+        }
+    }
+
+    /// Checks that `principal` has at least `role` on every constellation, i.e. that it was
+    /// granted it with the `*` pattern.
+    pub fn check_all(&self, principal: Option<&str>, role: Role) -> Result<(), PermissionDenied> {
+        self.check(principal, "", role)
+            .map_err(|e| PermissionDenied {
+                name: "*".into(),
+                ..e
+            })
+    }
+}
+
+impl FromStr for Acl {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut grants = vec![];
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let (principal, role, pattern) = match parts.as_slice() {
+                [principal, role, pattern] => (principal, role, pattern),
+                _ => return Err(AclError::InvalidLine(number + 1)),
+            };
+            grants.push(Grant {
+                principal: match *principal {
+                    "*" => None,
+                    principal => Some(principal.into()),
+                },
+                pattern: pattern
+                    .parse()
+                    .map_err(|e| AclError::InvalidPattern(number + 1, e))?,
+                role: role
+                    .parse()
+                    .map_err(|_| AclError::InvalidLine(number + 1))?,
+            });
+        }
+        Ok(Acl { grants })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let acl: Acl = "# Comment\n\
+                        alice write logs-*\n\
+                        alice read metrics-*\n\
+                        ops admin *\n\
+                        * read public-*\n\
+                        bob read exact\n"
+            .parse()
+            .unwrap();
+
+        assert!(acl.allows(Some("alice"), "logs-today", Role::Read));
+        assert!(acl.allows(Some("alice"), "logs-today", Role::Write));
+        assert!(!acl.allows(Some("alice"), "logs-today", Role::Admin));
+        assert!(acl.allows(Some("alice"), "metrics-cpu", Role::Read));
+        assert!(!acl.allows(Some("alice"), "metrics-cpu", Role::Write));
+        assert!(!acl.allows(Some("alice"), "other", Role::Read));
+
+        assert!(acl.allows(Some("ops"), "other", Role::Admin));
+        // Compacting every constellation needs access to all of them.
+        assert!(acl.allows(Some("ops"), "", Role::Admin));
+        assert!(!acl.allows(Some("alice"), "", Role::Admin));
+
+        assert!(acl.allows(None, "public-data", Role::Read));
+        assert!(acl.allows(Some("bob"), "public-data", Role::Read));
+        assert!(!acl.allows(None, "public-data", Role::Write));
+
+        assert!(acl.allows(Some("bob"), "exact", Role::Read));
+        assert!(!acl.allows(Some("bob"), "exactly", Role::Read));
+    }
+
+    #[test]
+    fn test_invalid_lines() {
+        assert!(matches!(
+            "alice write logs-*\nalice logs-*\n".parse::<Acl>(),
+            Err(AclError::InvalidLine(2))
+        ));
+        assert!(matches!(
+            "alice delete logs-*\n".parse::<Acl>(),
+            Err(AclError::InvalidLine(1))
+        ));
+        assert!(matches!(
+            "alice write logs-*\nalice write lo*gs\n".parse::<Acl>(),
+            Err(AclError::InvalidPattern(2, _))
+        ));
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let acl = Acl::default();
+        let status: Status = acl
+            .check(Some("alice"), "logs", Role::Write)
+            .unwrap_err()
+            .into();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(
+            status.message(),
+            "alice does not have write access to \"logs\""
+        );
+
+        let acl: Acl = "alice admin logs-*\nops admin *\n".parse().unwrap();
+        acl.check_all(Some("ops"), Role::Admin).unwrap();
+        let status: Status = acl
+            .check_all(Some("alice"), Role::Admin)
+            .unwrap_err()
+            .into();
+        assert_eq!(
+            status.message(),
+            "alice does not have admin access to \"*\""
+        );
+    }
+}
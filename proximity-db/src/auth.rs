@@ -0,0 +1,256 @@
+//! Authenticates requests with bearer tokens.
+//!
+//! Tokens are read from a key file with one `principal token` pair per line, ignoring blank lines
+//! and lines starting with `#`. The file is checked for changes periodically, so keys can be added
+//! and revoked without restarting the server.
+use crate::acl::{Acl, AclError, Role};
+use crate::shards::LOCAL_ONLY_HEADER;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use structopt::StructOpt;
+use thiserror::Error;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::{Code, Interceptor, Request, Status};
+
+/// Set on every authenticated request to the principal that made it, replacing anything the
+/// client sent.
+pub const PRINCIPAL_HEADER: &str = "x-proximity-principal";
+
+#[derive(Error, Debug)]
+pub enum KeysError {
+    #[error("Error reading the key file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {0} of the key file is not a principal followed by a token")]
+    InvalidLine(usize),
+}
+
+/// Options for requiring clients to authenticate, shared by every binary that runs a server.
+#[derive(Debug, StructOpt)]
+pub struct AuthOptions {
+    #[structopt(long, env = "PROXIMITY_KEY_FILE")]
+    /// Only accept requests with a bearer token listed in this file, which holds one
+    /// "principal token" pair per line. Without one, every request is accepted
+    pub key_file: Option<PathBuf>,
+    #[structopt(long, default_value = "10", env = "PROXIMITY_KEY_RELOAD_INTERVAL")]
+    /// How often, in seconds, to check the key file for changes
+    pub key_reload_interval: u64,
+    #[structopt(long, env = "PROXIMITY_ACL_FILE", requires = "key-file")]
+    /// Only allow principals to use the constellations granted to them in this file, which holds
+    /// one "principal role pattern" grant per line, with patterns written like a quota's. Other
+    /// shards and peers need admin access to every constellation with the principal of their token
+    pub acl_file: Option<PathBuf>,
+}
+
+impl AuthOptions {
+    /// Loads the keys and starts watching them for changes, or returns `None` if authentication
+    /// isn't enabled.
+    pub fn keys(&self) -> Result<Option<Arc<Keys>>, KeysError> {
+        let key_file = match &self.key_file {
+            Some(key_file) => key_file,
+            None => return Ok(None),
+        };
+        let keys = Arc::new(Keys::load(key_file.clone())?);
+        tokio::spawn(
+            keys.clone()
+                .watch(Duration::from_secs(self.key_reload_interval)),
+        );
+        Ok(Some(keys))
+    }
+
+    /// Loads the access control list, or returns `None` if every caller can use everything.
+    pub fn acl(&self) -> Result<Option<Acl>, AclError> {
+        self.acl_file.as_deref().map(Acl::load).transpose()
+    }
+}
+
+pub struct Keys {
+    path: PathBuf,
+    /// The modification time and length of the file when it was last read.
+    version: Mutex<Option<(SystemTime, u64)>>,
+    /// Maps each token to its principal.
+    principals: RwLock<HashMap<String, MetadataValue<Ascii>>>,
+}
+
+impl Keys {
+    pub fn load(path: PathBuf) -> Result<Self, KeysError> {
+        let keys = Keys {
+            path,
+            version: Mutex::new(None),
+            principals: RwLock::new(HashMap::new()),
+        };
+        keys.reload()?;
+        Ok(keys)
+    }
+
+    /// Reads the key file again if it has changed, returning whether it had. If the file can't
+    /// be read the current keys are kept.
+    pub fn reload(&self) -> Result<bool, KeysError> {
+        let metadata = std::fs::metadata(&self.path)?;
+        let version = Some((metadata.modified()?, metadata.len()));
+        let mut current = self.version.lock().unwrap();
+        if *current == version {
+            return Ok(false);
+        }
+        let principals = parse(&std::fs::read_to_string(&self.path)?)?;
+        *self.principals.write().unwrap() = principals;
+        *current = version;
+        Ok(true)
+    }
+
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let keys = self.clone();
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || keys.reload()).await {
+                tracing::warn!("Keeping the previous keys: {}", e);
+            }
+        }
+    }
+
+    /// The principal that a token belongs to.
+    pub fn principal(&self, token: &str) -> Option<MetadataValue<Ascii>> {
+        self.principals.read().unwrap().get(token).cloned()
+    }
+
+    /// The principal that a bearer token belongs to, rejecting a missing or unknown token as
+    /// `Unauthenticated`.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<MetadataValue<Ascii>, Status> {
+        let token = token
+            .ok_or_else(|| Status::new(Code::Unauthenticated, "A bearer token is required"))?;
+        self.principal(token)
+            .ok_or_else(|| Status::new(Code::Unauthenticated, "Invalid token"))
+    }
+
+    /// Rejects requests without a valid bearer token as `Unauthenticated`.
+    ///
+    /// Only other shards can ask for a request to be served by this shard alone, so the
+    /// local-only header is removed unless the caller is a peer under `acl`.
+    pub fn interceptor(self: Arc<Self>, acl: Option<Acl>) -> Interceptor {
+        Interceptor::new(move |mut request: Request<()>| {
+            let principal = self.authenticate(bearer_token(&request))?;
+            if !is_peer(acl.as_ref(), principal.to_str().ok()) {
+                request.metadata_mut().remove(LOCAL_ONLY_HEADER);
+            }
+            request.metadata_mut().insert(PRINCIPAL_HEADER, principal);
+            Ok(request)
+        })
+    }
+}
+
+/// Whether `principal` could be another shard or peer, which need admin access to every
+/// constellation. Without an ACL every principal has that.
+fn is_peer(acl: Option<&Acl>, principal: Option<&str>) -> bool {
+    match acl {
+        Some(acl) => acl.check_all(principal, Role::Admin).is_ok(),
+        None => true,
+    }
+}
+
+/// The principal that made an authenticated request.
+pub fn principal<T>(request: &Request<T>) -> Option<String> {
+    let value = request.metadata().get(PRINCIPAL_HEADER)?;
+    value.to_str().ok().map(Into::into)
+}
+
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    token(request.metadata().get("authorization")?.to_str().ok()?)
+}
+
+/// The token in the value of an `authorization` header, if it's a bearer token.
+pub fn token(authorization: &str) -> Option<&str> {
+    let mut parts = authorization.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+/// The value of the `authorization` header that presents `token`.
+pub fn bearer(token: &str) -> Option<MetadataValue<Ascii>> {
+    MetadataValue::from_str(&format!("Bearer {}", token)).ok()
+}
+
+fn parse(contents: &str) -> Result<HashMap<String, MetadataValue<Ascii>>, KeysError> {
+    let mut principals = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [principal, token] => {
+                let principal = MetadataValue::from_str(principal)
+                    .map_err(|_| KeysError::InvalidLine(number + 1))?;
+                principals.insert(token.to_string(), principal);
+            }
+            _ => return Err(KeysError::InvalidLine(number + 1)),
+        }
+    }
+    Ok(principals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let principals = parse("# Comment\nalice abc\n\n  bob   def  \n").unwrap();
+        assert_eq!(principals.len(), 2);
+        assert_eq!(principals["abc"], "alice");
+        assert_eq!(principals["def"], "bob");
+
+        assert!(matches!(
+            parse("alice abc\nbob\n"),
+            Err(KeysError::InvalidLine(2))
+        ));
+    }
+
+    #[test]
+    fn test_is_peer() {
+        let acl: Acl = "alice admin logs-*\nnode admin *\n".parse().unwrap();
+        assert!(is_peer(Some(&acl), Some("node")));
+        assert!(!is_peer(Some(&acl), Some("alice")));
+        assert!(!is_peer(Some(&acl), None));
+        assert!(is_peer(None, Some("alice")));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let with_header = |value: &'static str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert("authorization", MetadataValue::from_static(value));
+            request
+        };
+        assert_eq!(bearer_token(&with_header("Bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(&with_header("bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(&with_header("Basic abc")), None);
+        assert_eq!(bearer_token(&Request::new(())), None);
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("proximity-keys-{}", std::process::id()));
+        std::fs::write(&path, "alice abc\n").unwrap();
+        let keys = Keys::load(path.clone()).unwrap();
+        assert_eq!(keys.principal("abc").unwrap(), "alice");
+        assert!(!keys.reload().unwrap());
+
+        std::fs::write(&path, "bob def\n").unwrap();
+        assert!(keys.reload().unwrap());
+        assert!(keys.principal("abc").is_none());
+        assert_eq!(keys.principal("def").unwrap(), "bob");
+
+        // A broken file keeps the previous keys.
+        std::fs::write(&path, "bob\n").unwrap();
+        assert!(keys.reload().is_err());
+        assert_eq!(keys.principal("def").unwrap(), "bob");
+        std::fs::remove_file(path).unwrap();
+    }
+}
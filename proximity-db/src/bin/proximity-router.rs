@@ -0,0 +1,100 @@
+use proximity_db::auth::{self, AuthOptions};
+use proximity_db::router::Router;
+use proximity_db::telemetry::{self, LogOptions};
+use proximity_db::tls::TlsOptions;
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use std::str::FromStr;
+use structopt::StructOpt;
+use tonic::transport::Server;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Route requests to a set of proximity database instances.")]
+struct Opt {
+    #[structopt(short, long, default_value = "[::1]:4320", env = "PROXIMITY_ADDRESS")]
+    /// The interface and port that the router will listen on
+    address: String,
+    #[structopt(long = "backend", env = "PROXIMITY_BACKENDS", use_delimiter = true)]
+    /// A server that constellations are sharded across, e.g. http://10.0.0.1:4321
+    backends: Vec<String>,
+    #[structopt(long = "route", env = "PROXIMITY_ROUTES", value_delimiter = ";")]
+    /// Sends constellations with a name prefix to other servers instead, given as
+    /// prefix=address,address. The longest matching prefix wins
+    routes: Vec<Route>,
+    #[structopt(long, env = "PROXIMITY_TOKEN")]
+    /// The bearer token to present to the backends, when they require one
+    token: Option<String>,
+    #[structopt(flatten)]
+    tls: TlsOptions,
+    #[structopt(flatten)]
+    auth: AuthOptions,
+    #[structopt(flatten)]
+    log: LogOptions,
+}
+
+#[derive(Debug)]
+struct Route {
+    prefix: String,
+    backends: Vec<String>,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(prefix), Some(backends)) if !backends.is_empty() => Ok(Route {
+                prefix: prefix.into(),
+                backends: backends.split(',').map(Into::into).collect(),
+            }),
+            _ => anyhow::bail!(
+                "Routes must be given as prefix=address,address, not {:?}",
+                s
+            ),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+    opt.log.init(None)?;
+    let addr = opt.address.parse()?;
+
+    if opt.backends.is_empty() && opt.routes.is_empty() {
+        anyhow::bail!("At least one backend or route must be given");
+    }
+
+    let mut router = opt
+        .routes
+        .into_iter()
+        .fold(Router::new(opt.backends), |router, route| {
+            router.route(route.prefix, route.backends)
+        });
+    if let Some(acl) = opt.auth.acl()? {
+        router = router.with_acl(acl);
+    }
+    if let Some(token) = &opt.token {
+        router =
+            router.with_token(auth::bearer(token).ok_or_else(|| anyhow::anyhow!("Invalid token"))?);
+    }
+    if let Some(tls) = opt.tls.client_config()? {
+        router = router.with_tls(tls);
+    }
+
+    let mut server = Server::builder();
+    if let Some(tls) = opt.tls.server_config()? {
+        server = server.tls_config(tls);
+    }
+
+    let service = match opt.auth.keys()? {
+        Some(keys) => {
+            ProximityDbServer::with_interceptor(router, keys.interceptor(opt.auth.acl()?))
+        }
+        None => ProximityDbServer::new(router),
+    };
+    server.add_service(service).serve(addr).await?;
+
+    telemetry::shutdown();
+    Ok(())
+}
@@ -1,26 +1,293 @@
+use futures::channel::oneshot;
+use futures::{stream, Future, FutureExt, Stream};
 use num_cpus;
+use proximity::QueryPool;
+use proximity_db::auth::{self, AuthOptions, Keys};
+use proximity_db::cluster::{Cluster, GrpcTransport, RaftService};
+use proximity_db::compaction::{Compactor, Reaper};
+use proximity_db::config::{self, ConfigFile, Setting};
+use proximity_db::gateway::Gateway;
 use proximity_db::handler::ProximityDBHandler;
+use proximity_db::health::HealthService;
+use proximity_db::metrics::{self, Instrumented, SkyCollector};
+use proximity_db::quota::Quota;
+use proximity_db::reflection::ReflectionService;
+use proximity_db::shards::Shards;
 use proximity_db::sky::Sky;
+use proximity_db::snapshot::{DataDir, Snapshotter};
+use proximity_db::telemetry::{self, LogHandle, LogOptions, SlowQueryOptions};
+use proximity_db::tls::TlsOptions;
+use proximity_grpc::health::health_server::HealthServer;
 use proximity_grpc::proximity_db_server::ProximityDbServer;
+use proximity_grpc::raft_server::RaftServer;
+use proximity_grpc::reflection::server_reflection_server::ServerReflectionServer;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::clap::{self, ArgMatches};
 use structopt::StructOpt;
-use tonic::transport::Server;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tonic::transport::{NamedService, Server};
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Run a proximity database instance.")]
 struct Opt {
+    #[structopt(long, env = "PROXIMITY_CONFIG")]
+    /// Read options from this TOML file, with one key per option, e.g. max-concurrent-queries = 4.
+    /// Options given on the command line or in the environment take precedence over it. On
+    /// SIGHUP, the quotas, log level and key file are reloaded from it
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    /// Print the options in effect as a config file, then exit
+    print_config: bool,
     #[structopt(short, long, default_value = "[::1]:4321", env = "PROXIMITY_ADDRESS")]
     /// The interface and port that Proximity will listen on
     address: String,
     #[structopt(short, long, env = "PROXIMITY_THREADS")]
-    /// Specifies how many threads Proximity DB will use. Defaults to CPU count - 1
+    /// Specifies how many threads Proximity DB will use. Defaults to CPU count - 1, and at least 1
     threads: Option<usize>,
+    #[structopt(long, env = "PROXIMITY_MAX_CONCURRENT_QUERIES")]
+    /// How many searches can scan at the same time, with the rest waiting for a slot. Defaults to
+    /// the number of threads
+    max_concurrent_queries: Option<usize>,
+    #[structopt(long, default_value = "60", env = "PROXIMITY_COMPACTION_INTERVAL")]
+    /// How often, in seconds, to look for constellations that need compacting
+    compaction_interval: u64,
+    #[structopt(long, default_value = "0.2", env = "PROXIMITY_COMPACTION_RATIO")]
+    /// Constellations are compacted once this fraction of their points have been deleted
+    compaction_ratio: f32,
+    #[structopt(long, default_value = "60", env = "PROXIMITY_REAP_INTERVAL")]
+    /// How often, in seconds, to remove expired points
+    reap_interval: u64,
+    #[structopt(long = "quota", env = "PROXIMITY_QUOTAS", value_delimiter = ";")]
+    /// Limits the points or bytes in constellations, given as pattern=points:N,bytes:N. A pattern
+    /// is a constellation's name, or a prefix followed by * to limit all of them combined
+    quotas: Vec<Quota>,
+    #[structopt(long, env = "PROXIMITY_MEMORY_LIMIT")]
+    /// Rejects adds once the points held would take up more than this many bytes. This doesn't
+    /// count the server's own overhead, so leave some headroom below the memory available
+    memory_limit: Option<usize>,
+    #[structopt(long, env = "PROXIMITY_DATA_DIR")]
+    /// Restore the sky from a snapshot in this directory on startup, and save one there
+    /// periodically and when shutting down. Nodes with peers restore from the other nodes
+    /// instead, so can't use one
+    data_dir: Option<PathBuf>,
+    #[structopt(long, default_value = "300", env = "PROXIMITY_SNAPSHOT_INTERVAL")]
+    /// How often, in seconds, to save a snapshot to the data directory. 0 only saves one when
+    /// shutting down
+    snapshot_interval: u64,
+    #[structopt(long, default_value = "5", env = "PROXIMITY_SHUTDOWN_GRACE")]
+    /// How long, in seconds, to keep accepting requests after reporting NOT_SERVING when shutting
+    /// down, so load balancers can stop sending them first
+    shutdown_grace: u64,
+    #[structopt(long, default_value = "30", env = "PROXIMITY_DRAIN_TIMEOUT")]
+    /// How long, in seconds, to wait for in-flight requests to finish when shutting down
+    drain_timeout: u64,
+    #[structopt(long, env = "PROXIMITY_METRICS_ADDRESS")]
+    /// Serve Prometheus metrics over HTTP at /metrics on this address, e.g. [::1]:9090
+    metrics_address: Option<SocketAddr>,
+    #[structopt(long, env = "PROXIMITY_HTTP_ADDRESS")]
+    /// Serve an HTTP/JSON gateway on this address, e.g. [::1]:8080, with an OpenAPI description at
+    /// /v1/openapi.json. It serves this node's sky alone, so can't be used with peers or shards
+    http_address: Option<SocketAddr>,
+    #[structopt(long, default_value = "1", env = "PROXIMITY_NODE_ID")]
+    /// The raft id of this node, which must be unique within the cluster
+    node_id: u64,
+    #[structopt(long = "peer", env = "PROXIMITY_PEERS", use_delimiter = true)]
+    /// Another node in the cluster, given as id=address, e.g. 2=http://10.0.0.2:4321. Writes are
+    /// applied on every node once a majority of the cluster has accepted them
+    peers: Vec<Peer>,
+    #[structopt(long = "shard", env = "PROXIMITY_SHARDS", use_delimiter = true)]
+    /// The address of every shard, in the same order on every server, e.g.
+    /// http://10.0.0.1:4321. Each constellation is split across all of them
+    shards: Vec<String>,
+    #[structopt(long, default_value = "0", env = "PROXIMITY_SHARD_INDEX")]
+    /// Which of the shards this server holds, counting from 0
+    shard_index: usize,
+    #[structopt(long, env = "PROXIMITY_TOKEN")]
+    /// The bearer token to present to the other shards and peers, when they require one
+    token: Option<String>,
+    #[structopt(flatten)]
+    tls: TlsOptions,
+    #[structopt(flatten)]
+    auth: AuthOptions,
+    #[structopt(flatten)]
+    log: LogOptions,
+    #[structopt(flatten)]
+    slow_queries: SlowQueryOptions,
+}
+
+/// The options that can be set in the config file.
+static SETTINGS: &[Setting] = &[
+    Setting::value("address").with_short('a'),
+    Setting::value("threads").with_short('t'),
+    Setting::value("max-concurrent-queries"),
+    Setting::value("compaction-interval"),
+    Setting::value("compaction-ratio"),
+    Setting::value("reap-interval"),
+    Setting::list("quotas", "quota"),
+    Setting::value("memory-limit"),
+    Setting::value("data-dir"),
+    Setting::value("snapshot-interval"),
+    Setting::value("shutdown-grace"),
+    Setting::value("drain-timeout"),
+    Setting::value("metrics-address"),
+    Setting::value("http-address"),
+    Setting::value("node-id"),
+    Setting::list("peers", "peer"),
+    Setting::list("shards", "shard"),
+    Setting::value("shard-index"),
+    Setting::secret("token"),
+    Setting::value("tls-cert"),
+    Setting::value("tls-key"),
+    Setting::value("tls-client-ca"),
+    Setting::value("tls-ca"),
+    Setting::value("tls-client-cert"),
+    Setting::value("tls-client-key"),
+    Setting::value("key-file"),
+    Setting::value("key-reload-interval"),
+    Setting::value("acl-file"),
+    Setting::value("log-level"),
+    Setting::value("log-format"),
+    Setting::value("jaeger-agent"),
+    Setting::value("service-name"),
+    Setting::value("slow-query-threshold"),
+    Setting::value("slow-query-log"),
+    Setting::value("slow-query-rotation"),
+];
+
+/// Parses the options from `args`, the environment and the config file, if one is given.
+fn parse(args: Vec<OsString>) -> anyhow::Result<(Opt, ArgMatches<'static>)> {
+    let args = match config::find(&args) {
+        Some(path) => ConfigFile::load(&path, SETTINGS)?.merge(args),
+        None => args,
+    };
+    let matches = Opt::clap().get_matches_from_safe(args)?;
+    let opt = Opt::from_clap(&matches);
+    validate(&opt)?;
+    Ok((opt, matches))
+}
+
+fn validate(opt: &Opt) -> anyhow::Result<()> {
+    if opt.threads == Some(0) {
+        anyhow::bail!("The number of threads must be at least 1");
+    }
+    if opt.max_concurrent_queries == Some(0) {
+        anyhow::bail!("The number of concurrent queries must be at least 1");
+    }
+    if !(0.0..=1.0).contains(&opt.compaction_ratio) {
+        anyhow::bail!(
+            "The compaction ratio must be between 0 and 1, not {}",
+            opt.compaction_ratio
+        );
+    }
+    // The raft log would replay every write on top of the snapshot.
+    if opt.data_dir.is_some() && !opt.peers.is_empty() {
+        anyhow::bail!("A data directory can't be used with peers");
+    }
+    if opt.http_address.is_some() && !(opt.peers.is_empty() && opt.shards.is_empty()) {
+        anyhow::bail!("The HTTP gateway can't be used with peers or shards");
+    }
+    if !opt.shards.is_empty() && opt.shard_index >= opt.shards.len() {
+        anyhow::bail!(
+            "The shard index must be less than the number of shards ({})",
+            opt.shards.len()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Peer {
+    id: u64,
+    address: String,
+}
+
+impl FromStr for Peer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(id), Some(address)) => Ok(Peer {
+                id: id.parse()?,
+                address: address.into(),
+            }),
+            _ => anyhow::bail!("Peers must be given as id=address, not {:?}", s),
+        }
+    }
+}
+
+/// Resolves once the server is asked to stop, with SIGINT or SIGTERM.
+/// Accepts connections on `listener` until `until` resolves.
+fn accept_until(
+    listener: TcpListener,
+    until: impl Future<Output = ()> + Unpin,
+) -> impl Stream<Item = io::Result<TcpStream>> {
+    stream::unfold((listener, until), |(mut listener, mut until)| async move {
+        tokio::select! {
+            accepted = listener.accept() => {
+                Some((accepted.map(|(stream, _)| stream), (listener, until)))
+            }
+            _ = &mut until => None,
+        }
+    })
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Reloads the options that can change while the server is running on SIGHUP.
+async fn reload_on_hangup(sky: Arc<Sky>, log: LogHandle, keys: Option<Arc<Keys>>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        match reload(&sky, &log, keys.as_deref()) {
+            Ok(()) => tracing::info!("Reloaded the config"),
+            Err(e) => tracing::warn!("Could not reload the config: {}", e),
+        }
+    }
+}
+
+fn reload(sky: &Sky, log: &LogHandle, keys: Option<&Keys>) -> anyhow::Result<()> {
+    let (opt, _) = parse(std::env::args_os().collect())?;
+    log.set_level(&opt.log.log_level)?;
+    sky.set_quotas(opt.quotas);
+    if let Some(keys) = keys {
+        keys.reload()?;
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let opt = Opt::from_args();
+    let (opt, matches) = match parse(std::env::args_os().collect()) {
+        Ok(parsed) => parsed,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => return Err(e),
+        },
+    };
+    if opt.print_config {
+        print!("{}", config::render(SETTINGS, &matches));
+        return Ok(());
+    }
+    let log = opt.log.init(Some(&opt.slow_queries))?;
+    if let Some(config) = &opt.config {
+        tracing::info!("Read options from {}", config.display());
+    }
     let addr = opt.address.parse()?;
-    let threads = opt.threads.unwrap_or_else(|| num_cpus::get() - 1);
+    let threads = opt.threads.unwrap_or_else(|| (num_cpus::get() - 1).max(1));
 
     rayon::ThreadPoolBuilder::new()
         .num_threads(threads)
@@ -28,13 +295,229 @@ async fn main() -> anyhow::Result<()> {
         .build_global()
         .unwrap();
 
-    let sky = Sky::default();
-    let embedding_handler = ProximityDBHandler::new(sky);
+    if QueryPool::new(opt.max_concurrent_queries.unwrap_or(threads))
+        .build_global()
+        .is_err()
+    {
+        anyhow::bail!("The query pool has already been initialized");
+    }
+
+    let mut sky = Sky::default().with_quotas(opt.quotas);
+    if let Some(memory_limit) = opt.memory_limit {
+        sky = sky.with_memory_limit(memory_limit);
+    }
+    let sky = Arc::new(sky);
+    let data_dir = opt
+        .data_dir
+        .clone()
+        .map(DataDir::open)
+        .transpose()?
+        .map(Arc::new);
+
+    let token = match &opt.token {
+        Some(token) => Some(auth::bearer(token).ok_or_else(|| anyhow::anyhow!("Invalid token"))?),
+        None => None,
+    };
+    let client_tls = opt.tls.client_config()?;
+    let (mut embedding_handler, raft_service) = if opt.peers.is_empty() {
+        (ProximityDBHandler::new(sky.clone()), None)
+    } else {
+        let peer_ids: Vec<u64> = opt.peers.iter().map(|p| p.id).collect();
+        let addresses: HashMap<u64, String> =
+            opt.peers.into_iter().map(|p| (p.id, p.address)).collect();
+        let mut transport = GrpcTransport::new(addresses);
+        if let Some(token) = &token {
+            transport = transport.with_token(token.clone());
+        }
+        if let Some(tls) = &client_tls {
+            transport = transport.with_tls(tls.clone());
+        }
+        let cluster = Cluster::start(
+            opt.node_id,
+            &peer_ids,
+            sky.clone(),
+            Arc::new(Arc::new(transport)),
+        )?;
+        (
+            ProximityDBHandler::replicated(cluster.clone()),
+            Some(RaftService::new(cluster)),
+        )
+    };
+
+    if let Some(acl) = opt.auth.acl()? {
+        embedding_handler = embedding_handler.with_acl(acl);
+    }
+    let raft_service = match (raft_service, opt.auth.acl()?) {
+        (Some(raft_service), Some(acl)) => Some(raft_service.with_acl(acl)),
+        (raft_service, _) => raft_service,
+    };
+    if let Some(threshold) = opt.slow_queries.threshold() {
+        embedding_handler = embedding_handler.with_slow_query_threshold(threshold);
+    }
+    if !opt.shards.is_empty() {
+        let mut shards = Shards::new(opt.shard_index, opt.shards);
+        if let Some(token) = token {
+            shards = shards.with_token(token);
+        }
+        if let Some(tls) = client_tls {
+            shards = shards.with_tls(tls);
+        }
+        embedding_handler = embedding_handler.sharded(shards);
+    }
+
+    if let Some(metrics_address) = opt.metrics_address {
+        prometheus::register(Box::new(SkyCollector::new(sky.clone())))?;
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_address).await {
+                tracing::error!("Stopped serving metrics: {}", e);
+            }
+        });
+    }
+    let health = HealthService::new(sky.clone());
+
+    let mut server = Server::builder();
+    if let Some(tls) = opt.tls.server_config()? {
+        server = server.tls_config(tls);
+    }
+
+    // Once asked to shut down, the server reports NOT_SERVING, waits for load balancers to stop
+    // sending it requests, then stops both the gRPC server and the gateway.
+    let (stop, stopping) = oneshot::channel();
+    let stopping = stopping.map(|_| ()).shared();
+    let shutdown_grace = Duration::from_secs(opt.shutdown_grace);
+    tokio::spawn({
+        let health = health.clone();
+        async move {
+            shutdown_signal().await;
+            tracing::info!("Shutting down");
+            health.stop();
+            tokio::time::delay_for(shutdown_grace).await;
+            stop.send(()).ok();
+        }
+    });
+
+    // Until the snapshot is loaded only health checks are served, and they report NOT_SERVING.
+    let (loaded, loading) = oneshot::channel();
+    let listener = TcpListener::bind(addr).await?;
+    let health_only = tokio::spawn(
+        server
+            .clone()
+            .add_service(health.server())
+            .serve_with_incoming(accept_until(listener, loading.map(|_| ()))),
+    );
+    tracing::info!("Listening on {}", addr);
+    if let Some(data_dir) = &data_dir {
+        let restored = tokio::task::spawn_blocking({
+            let (sky, data_dir) = (sky.clone(), data_dir.clone());
+            move || data_dir.load(&sky)
+        })
+        .await??;
+        tracing::info!(
+            "Restored {} points from {}",
+            restored,
+            data_dir.path().display()
+        );
+        if opt.snapshot_interval > 0 {
+            let interval = Duration::from_secs(opt.snapshot_interval);
+            tokio::spawn(Snapshotter::new(sky.clone(), data_dir.clone(), interval).run());
+        }
+    }
+    loaded.send(()).ok();
+    health_only.await??;
+
+    tokio::spawn(Reaper::new(sky.clone(), Duration::from_secs(opt.reap_interval)).run());
+    tokio::spawn(
+        Compactor::new(
+            sky.clone(),
+            Duration::from_secs(opt.compaction_interval),
+            opt.compaction_ratio,
+        )
+        .run(),
+    );
+
+    let embedding_handler = Instrumented::new(embedding_handler);
+    let keys = opt.auth.keys()?;
+    tokio::spawn(reload_on_hangup(sky.clone(), log, keys.clone()));
+    let mut gateway_task = None;
+    if let Some(http_address) = opt.http_address {
+        let mut gateway = Gateway::new(sky.clone());
+        if let Some(keys) = &keys {
+            gateway = gateway.with_keys(keys.clone());
+        }
+        if let Some(acl) = opt.auth.acl()? {
+            gateway = gateway.with_acl(acl);
+        }
+        let serving = gateway.serve_with_shutdown(http_address, stopping.clone());
+        gateway_task = Some(tokio::spawn(async move {
+            if let Err(e) = serving.await {
+                tracing::error!("Stopped serving the HTTP gateway: {}", e);
+            }
+        }));
+    }
+    // The other nodes authenticate with the same keys as clients.
+    let raft_service = raft_service.map(|raft_service| match &keys {
+        Some(keys) => RaftServer::with_interceptor(raft_service, keys.clone().interceptor(None)),
+        None => RaftServer::new(raft_service),
+    });
+    let service = match keys {
+        Some(keys) => ProximityDbServer::with_interceptor(
+            embedding_handler,
+            keys.interceptor(opt.auth.acl()?),
+        ),
+        None => ProximityDbServer::new(embedding_handler),
+    };
+    // Reflection only lists the services that are actually served.
+    let mut served = vec![
+        <ProximityDbServer<ProximityDBHandler> as NamedService>::NAME,
+        <HealthServer<HealthService> as NamedService>::NAME,
+        <ServerReflectionServer<ReflectionService> as NamedService>::NAME,
+    ];
+    if raft_service.is_some() {
+        served.push(<RaftServer<RaftService> as NamedService>::NAME);
+    }
+    let reflection = ReflectionService::new(proximity_grpc::FILE_DESCRIPTOR_SET, &served)?;
+    let router = server
+        .add_service(service)
+        .add_service(health.server())
+        .add_service(reflection.server());
+
+    let serve = tokio::spawn({
+        let stopping = stopping.clone();
+        async move {
+            let served = match raft_service {
+                Some(raft_service) => {
+                    router
+                        .add_service(raft_service)
+                        .serve_with_shutdown(addr, stopping)
+                        .await
+                }
+                None => router.serve_with_shutdown(addr, stopping).await,
+            };
+            if let Some(gateway_task) = gateway_task {
+                gateway_task.await.ok();
+            }
+            served
+        }
+    });
+    // Does nothing if the server has already started shutting down.
+    health.set_serving(true);
 
-    Server::builder()
-        .add_service(ProximityDbServer::new(embedding_handler))
-        .serve(addr)
-        .await?;
+    let drain_timeout = Duration::from_secs(opt.drain_timeout);
+    let give_up = async move {
+        stopping.await;
+        tokio::time::delay_for(drain_timeout).await
+    };
+    tokio::select! {
+        result = serve => result??,
+        _ = give_up => {
+            tracing::warn!("Stopped waiting for in-flight requests after {:?}", drain_timeout);
+        }
+    }
 
+    if let Some(data_dir) = data_dir {
+        let saved = tokio::task::spawn_blocking(move || data_dir.save(&sky)).await??;
+        tracing::info!("Saved {} points", saved);
+    }
+    telemetry::shutdown();
     Ok(())
 }
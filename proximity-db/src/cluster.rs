@@ -0,0 +1,847 @@
+//! Replicates writes to the `Sky` across a cluster of nodes with raft.
+//!
+//! Every write is proposed as a `Command` to the raft leader, and once it's committed it is
+//! applied to the sky on every node. Followers forward writes they receive to the leader, so
+//! clients can send them to any node.
+//!
+//! The raft log is kept in memory and compacted as it grows. A node that falls too far behind is
+//! sent a snapshot of the whole sky instead of the entries it missed.
+//!
+//! Reads are always served from the local sky. Depending on the consistency the client asked
+//! for, a node may first have to catch up with the leader using raft's ReadIndex protocol.
+use crate::acl::{Acl, PermissionDenied, Role};
+use crate::auth;
+use crate::sky::{Sky, SkyError};
+use crate::snapshot;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use prost::Message as ProstMessage;
+use proximity_grpc::raft_client::RaftClient;
+use proximity_grpc::raft_server::Raft;
+use proximity_grpc::{
+    Command, Consistency, ProposeRequest, ProposeResponse, RaftMessage, RaftResponse,
+};
+use raft::eraftpb::{ConfState, Entry, EntryType, Message, Snapshot};
+use raft::storage::MemStorage;
+use raft::{Config, RaftState, RawNode, StateRole, Storage};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, ClientTlsConfig};
+use tonic::{Code, Interceptor, Request, Response, Status};
+
+/// How often the raft state machine ticks. Elections time out after 10 ticks.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long a linearizable read waits to catch up with the leader before giving up.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a write waits to be committed and applied before giving up.
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many entries are applied between compactions of the raft log.
+const COMPACT_ENTRIES: u64 = 10_000;
+
+/// How far behind the leader a bounded staleness read may be, if the client doesn't say.
+pub const DEFAULT_MAX_STALENESS: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum ClusterError {
+    #[error(transparent)]
+    Sky(#[from] SkyError),
+    #[error(transparent)]
+    Raft(#[from] raft::Error),
+    #[error("There is no leader at the moment, try again once one has been elected")]
+    NoLeader,
+    #[error("This node is shutting down")]
+    Stopped,
+    #[error("Timed out waiting to catch up with the leader")]
+    ReadTimeout,
+    #[error("Timed out waiting for the write to be committed, it may still be applied")]
+    ProposeTimeout,
+    #[error("The leader rejected the write: {0}")]
+    Forwarded(Status),
+}
+
+impl From<ClusterError> for Status {
+    fn from(other: ClusterError) -> Self {
+        let msg = format!("{}", other);
+        match other {
+            ClusterError::Sky(e) => e.into(),
+            ClusterError::Forwarded(status) => status,
+            ClusterError::NoLeader
+            | ClusterError::Stopped
+            | ClusterError::ReadTimeout
+            | ClusterError::ProposeTimeout => Status::new(Code::Unavailable, msg),
+            ClusterError::Raft(..) => Status::new(Code::Internal, msg),
+        }
+    }
+}
+
+/// How nodes talk to each other.
+#[tonic::async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Sends a raft message to another node. Raft copes with messages being lost, so this
+    /// doesn't need to report failures.
+    fn send(&self, message: Message);
+
+    /// Asks the leader to propose a command, returning once it has been applied.
+    async fn forward(&self, leader: u64, command: Command) -> Result<usize, ClusterError>;
+}
+
+type ProposalResult = Result<usize, SkyError>;
+
+enum Event {
+    Propose {
+        command: Command,
+        done: oneshot::Sender<Result<usize, ClusterError>>,
+    },
+    Step(Message),
+    ReadIndex {
+        done: oneshot::Sender<Result<(), ClusterError>>,
+    },
+}
+
+type ReadCallback = oneshot::Sender<Result<(), ClusterError>>;
+
+/// A handle to this node's raft state machine, which runs on its own thread.
+pub struct Cluster {
+    id: u64,
+    sky: Arc<Sky>,
+    mailbox: Sender<Event>,
+    leader: Arc<AtomicU64>,
+    last_contact: Arc<AtomicU64>,
+    started: Instant,
+    transport: Arc<dyn Transport>,
+}
+
+impl Cluster {
+    /// Starts a node with the given id. `peers` are the ids of every other voting node.
+    pub fn start(
+        id: u64,
+        peers: &[u64],
+        sky: Arc<Sky>,
+        transport: Arc<dyn Transport>,
+    ) -> raft::Result<Arc<Self>> {
+        let config = Config {
+            id,
+            election_tick: 10,
+            heartbeat_tick: 3,
+            ..Default::default()
+        };
+        config.validate()?;
+
+        let mut voters = peers.to_vec();
+        voters.push(id);
+        let storage = MemStorage::new_with_conf_state(ConfState::from((voters, vec![])));
+        let node = RawNode::new(
+            &config,
+            SkyStorage {
+                log: storage.clone(),
+                sky: sky.clone(),
+            },
+            &raft::default_logger(),
+        )?;
+
+        let (mailbox, events) = unbounded();
+        let leader = Arc::new(AtomicU64::new(raft::INVALID_ID));
+        let last_contact = Arc::new(AtomicU64::new(0));
+        let started = Instant::now();
+        let state_machine = StateMachine {
+            id,
+            node,
+            storage,
+            sky: sky.clone(),
+            transport: transport.clone(),
+            leader: leader.clone(),
+            last_contact: last_contact.clone(),
+            started,
+            role: (StateRole::Follower, 0),
+            proposals: HashMap::new(),
+            next_proposal: 0,
+            reads: HashMap::new(),
+            next_read: 0,
+            pending_reads: vec![],
+            applied: 0,
+            compacted: 0,
+        };
+        std::thread::Builder::new()
+            .name(format!("raft-{}", id))
+            .spawn(move || state_machine.run(events))
+            .expect("Error spawning raft thread");
+
+        Ok(Arc::new(Cluster {
+            id,
+            sky,
+            mailbox,
+            leader,
+            last_contact,
+            started,
+            transport,
+        }))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn sky(&self) -> Arc<Sky> {
+        self.sky.clone()
+    }
+
+    pub fn leader(&self) -> Option<u64> {
+        match self.leader.load(Ordering::Acquire) {
+            raft::INVALID_ID => None,
+            leader => Some(leader),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader() == Some(self.id)
+    }
+
+    /// Hands a message from another node to the state machine.
+    pub fn step(&self, message: Message) {
+        self.mailbox.send(Event::Step(message)).ok();
+    }
+
+    /// Replicates a write through the leader, returning once it has been applied there.
+    pub async fn propose(&self, command: Command) -> Result<usize, ClusterError> {
+        match self.leader() {
+            None => Err(ClusterError::NoLeader),
+            Some(leader) if leader == self.id => self.propose_local(command).await,
+            Some(leader) => self.transport.forward(leader, command).await,
+        }
+    }
+
+    /// Waits until this node's sky is up to date enough to serve a read with the given
+    /// consistency.
+    ///
+    /// Bounded staleness reads are served straight away if this node has heard from the leader
+    /// recently, and otherwise fall back to a linearizable read.
+    pub async fn read(
+        &self,
+        consistency: Consistency,
+        max_staleness: Duration,
+    ) -> Result<(), ClusterError> {
+        match consistency {
+            Consistency::AnyReplica => Ok(()),
+            Consistency::BoundedStaleness if self.staleness() <= max_staleness => Ok(()),
+            Consistency::BoundedStaleness | Consistency::Linearizable => {
+                if self.leader().is_none() {
+                    return Err(ClusterError::NoLeader);
+                }
+                let (done, result) = oneshot::channel();
+                self.mailbox
+                    .send(Event::ReadIndex { done })
+                    .map_err(|_| ClusterError::Stopped)?;
+                match tokio::time::timeout(READ_TIMEOUT, result).await {
+                    Ok(result) => result.map_err(|_| ClusterError::Stopped)?,
+                    Err(_) => Err(ClusterError::ReadTimeout),
+                }
+            }
+        }
+    }
+
+    /// How long it has been since this node last heard from the leader. The leader itself is
+    /// never stale.
+    fn staleness(&self) -> Duration {
+        if self.is_leader() {
+            return Duration::from_secs(0);
+        }
+        let last_contact = Duration::from_millis(self.last_contact.load(Ordering::Acquire));
+        self.started.elapsed() - last_contact.min(self.started.elapsed())
+    }
+
+    async fn propose_local(&self, command: Command) -> Result<usize, ClusterError> {
+        // Only the leader prepares writes, so every node applies them the same way.
+        let command = Command {
+            command: command
+                .command
+                .map(|command| self.sky.prepare(command))
+                .transpose()?,
+        };
+        let (done, result) = oneshot::channel();
+        self.mailbox
+            .send(Event::Propose { command, done })
+            .map_err(|_| ClusterError::Stopped)?;
+        match tokio::time::timeout(PROPOSE_TIMEOUT, result).await {
+            Ok(result) => result.map_err(|_| ClusterError::Stopped)?,
+            Err(_) => Err(ClusterError::ProposeTimeout),
+        }
+    }
+}
+
+/// The raft log, along with the sky it's applied to. Snapshots are taken from the sky, so entries
+/// that have been applied can be compacted away.
+#[derive(Clone)]
+struct SkyStorage {
+    log: MemStorage,
+    sky: Arc<Sky>,
+}
+
+impl Storage for SkyStorage {
+    fn initial_state(&self) -> raft::Result<RaftState> {
+        self.log.initial_state()
+    }
+
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+    ) -> raft::Result<Vec<Entry>> {
+        self.log.entries(low, high, max_size)
+    }
+
+    fn term(&self, index: u64) -> raft::Result<u64> {
+        self.log.term(index)
+    }
+
+    fn first_index(&self) -> raft::Result<u64> {
+        self.log.first_index()
+    }
+
+    fn last_index(&self) -> raft::Result<u64> {
+        self.log.last_index()
+    }
+
+    fn snapshot(&self, request_index: u64) -> raft::Result<Snapshot> {
+        // Raft only asks for snapshots on the state machine's thread, between readies, when the
+        // sky holds every committed entry and nothing more.
+        let mut snapshot = self.log.snapshot(request_index)?;
+        let mut data = vec![];
+        snapshot::write_sky(&self.sky, &mut data).expect("Error writing a snapshot to memory");
+        snapshot.set_data(data);
+        Ok(snapshot)
+    }
+}
+
+struct StateMachine {
+    id: u64,
+    node: RawNode<SkyStorage>,
+    storage: MemStorage,
+    sky: Arc<Sky>,
+    transport: Arc<dyn Transport>,
+    leader: Arc<AtomicU64>,
+    last_contact: Arc<AtomicU64>,
+    started: Instant,
+    /// This node's role and term, as of the last time round the loop.
+    role: (StateRole, u64),
+    proposals: HashMap<u64, oneshot::Sender<Result<usize, ClusterError>>>,
+    next_proposal: u64,
+    /// Reads waiting for the leader to tell us its commit index.
+    reads: HashMap<u64, ReadCallback>,
+    next_read: u64,
+    /// Reads waiting for this node to apply everything up to the leader's commit index.
+    pending_reads: Vec<(u64, ReadCallback)>,
+    applied: u64,
+    /// The index the raft log was last compacted up to.
+    compacted: u64,
+}
+
+impl StateMachine {
+    fn run(mut self, events: Receiver<Event>) {
+        let mut last_tick = Instant::now();
+        loop {
+            match events.recv_timeout(TICK) {
+                Ok(Event::Propose { command, done }) => self.propose(command, done),
+                Ok(Event::Step(message)) => {
+                    if message.get_from() == self.node.raft.leader_id {
+                        let now = self.started.elapsed().as_millis() as u64;
+                        self.last_contact.store(now, Ordering::Release);
+                    }
+                    // Messages from stale terms or unknown peers are rejected, which is harmless.
+                    self.node.step(message).ok();
+                }
+                Ok(Event::ReadIndex { done }) => {
+                    // Raft drops reads while there's no leader, and their callers give up.
+                    self.reads.retain(|_, done| !done.is_closed());
+                    let read = self.next_read;
+                    self.next_read += 1;
+                    self.reads.insert(read, done);
+                    self.node.read_index(read.to_be_bytes().to_vec());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // Every `Cluster` handle has gone, so the node is shutting down.
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            if last_tick.elapsed() >= TICK {
+                self.node.tick();
+                last_tick = Instant::now();
+            }
+            self.leader
+                .store(self.node.raft.leader_id, Ordering::Release);
+            self.on_ready();
+
+            let role = (self.node.raft.state, self.node.raft.term);
+            if role != self.role {
+                self.role = role;
+                // Proposals from an earlier term may never be committed, so stop waiting on them.
+                for (_, done) in self.proposals.drain() {
+                    done.send(Err(ClusterError::NoLeader)).ok();
+                }
+            }
+        }
+    }
+
+    fn propose(&mut self, command: Command, done: oneshot::Sender<Result<usize, ClusterError>>) {
+        if self.node.raft.state != StateRole::Leader {
+            done.send(Err(ClusterError::NoLeader)).ok();
+            return;
+        }
+        let mut data = Vec::with_capacity(command.encoded_len());
+        command.encode(&mut data).expect("Error encoding command");
+
+        // The context identifies the proposal, so whoever proposed it can be told once it has
+        // been applied. It includes the node id as a new leader may reuse proposal numbers.
+        let proposal = self.next_proposal;
+        self.next_proposal += 1;
+        let mut context = self.id.to_be_bytes().to_vec();
+        context.extend_from_slice(&proposal.to_be_bytes());
+
+        match self.node.propose(context, data) {
+            Ok(()) => {
+                // Forget proposals whose callers have timed out.
+                self.proposals.retain(|_, done| !done.is_closed());
+                self.proposals.insert(proposal, done);
+            }
+            Err(e) => {
+                done.send(Err(e.into())).ok();
+            }
+        }
+    }
+
+    fn on_ready(&mut self) {
+        if !self.node.has_ready() {
+            return;
+        }
+        let mut ready = self.node.ready();
+
+        // Entries and snapshots must be stored before `advance` is called, the snapshot first
+        // since the entries follow on from it.
+        if *ready.snapshot() != Snapshot::default() {
+            // The leader no longer has the entries this node is missing, so it sent its sky.
+            let snapshot = ready.snapshot().clone();
+            self.sky.clear();
+            snapshot::read_sky(&self.sky, &mut snapshot.get_data())
+                .expect("Error restoring raft snapshot");
+            self.applied = snapshot.get_metadata().index;
+            self.storage
+                .wl()
+                .apply_snapshot(snapshot)
+                .expect("Error applying raft snapshot");
+        }
+        self.storage
+            .wl()
+            .append(ready.entries())
+            .expect("Error appending raft entries");
+
+        for message in ready.messages.drain(..) {
+            self.transport.send(message);
+        }
+
+        for read_state in ready.read_states() {
+            let read = match read_state.request_ctx[..].try_into() {
+                Ok(read) => u64::from_be_bytes(read),
+                Err(_) => continue,
+            };
+            if let Some(done) = self.reads.remove(&read) {
+                self.pending_reads.push((read_state.index, done));
+            }
+        }
+
+        if let Some(committed_entries) = ready.committed_entries.take() {
+            for entry in &committed_entries {
+                self.applied = entry.index;
+                // New leaders commit an empty entry, and membership is fixed at startup.
+                if entry.get_data().is_empty() || entry.get_entry_type() != EntryType::EntryNormal {
+                    continue;
+                }
+                let result = match Command::decode(entry.get_data()) {
+                    Ok(command) => self.apply(command),
+                    Err(e) => panic!("Error decoding committed command: {}", e),
+                };
+                if let Some(done) = self.take_proposal(entry.get_context()) {
+                    done.send(result.map_err(ClusterError::from)).ok();
+                }
+            }
+            // The term and vote only change with the hard state below.
+            if let Some(last_committed) = committed_entries.last() {
+                self.storage.wl().mut_hard_state().commit = last_committed.index;
+            }
+        }
+        if let Some(hard_state) = ready.hs() {
+            self.storage.wl().set_hardstate(hard_state.clone());
+        }
+        if self.applied >= self.compacted + COMPACT_ENTRIES {
+            self.storage
+                .wl()
+                .compact(self.applied)
+                .expect("Error compacting the raft log");
+            self.compacted = self.applied;
+        }
+
+        let applied = self.applied;
+        let (caught_up, waiting): (Vec<_>, Vec<_>) = self
+            .pending_reads
+            .drain(..)
+            .partition(|(index, _)| *index <= applied);
+        self.pending_reads = waiting;
+        for (_, done) in caught_up {
+            done.send(Ok(())).ok();
+        }
+
+        self.node.advance(ready);
+    }
+
+    fn apply(&self, command: Command) -> ProposalResult {
+        match command.command {
+            Some(command) => self.sky.apply(command),
+            None => Ok(0),
+        }
+    }
+
+    fn take_proposal(
+        &mut self,
+        context: &[u8],
+    ) -> Option<oneshot::Sender<Result<usize, ClusterError>>> {
+        if context.len() != 16 {
+            return None;
+        }
+        let node = u64::from_be_bytes(context[..8].try_into().unwrap());
+        let proposal = u64::from_be_bytes(context[8..].try_into().unwrap());
+        if node != self.id {
+            return None;
+        }
+        self.proposals.remove(&proposal)
+    }
+}
+
+/// Sends raft messages and forwarded writes to other nodes over gRPC.
+pub struct GrpcTransport {
+    addresses: HashMap<u64, String>,
+    /// The `authorization` header sent to the other nodes.
+    token: Option<MetadataValue<Ascii>>,
+    tls: Option<ClientTlsConfig>,
+    clients: Mutex<HashMap<u64, RaftClient<Channel>>>,
+    runtime: Handle,
+}
+
+impl GrpcTransport {
+    /// Must be called from within the tokio runtime, which messages are sent from.
+    pub fn new(addresses: HashMap<u64, String>) -> Self {
+        GrpcTransport {
+            addresses,
+            token: None,
+            tls: None,
+            clients: Mutex::new(HashMap::new()),
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Authenticates with the other nodes, for servers that require a bearer token.
+    pub fn with_token(mut self, token: MetadataValue<Ascii>) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Connects to the other nodes over TLS.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    async fn client(&self, to: u64) -> Result<RaftClient<Channel>, Status> {
+        if let Some(client) = self.clients.lock().unwrap().get(&to) {
+            // This is super cheap, see https://github.com/hyperium/tonic/issues/285
+            return Ok(client.clone());
+        }
+        let address = self
+            .addresses
+            .get(&to)
+            .ok_or_else(|| Status::new(Code::Internal, format!("Unknown node {}", to)))?;
+        let mut endpoint = Channel::from_shared(address.clone())
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))?;
+        if let Some(tls) = self.tls.clone() {
+            endpoint = endpoint.tls_config(tls);
+        }
+        let channel = endpoint
+            .connect()
+            .await
+            .map_err(|e| Status::new(Code::Unavailable, e.to_string()))?;
+        let client = match self.token.clone() {
+            Some(token) => RaftClient::with_interceptor(
+                channel,
+                Interceptor::new(move |mut request: Request<()>| {
+                    request
+                        .metadata_mut()
+                        .insert("authorization", token.clone());
+                    Ok(request)
+                }),
+            ),
+            None => RaftClient::new(channel),
+        };
+        self.clients.lock().unwrap().insert(to, client.clone());
+        Ok(client)
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for Arc<GrpcTransport> {
+    fn send(&self, message: Message) {
+        let transport = self.clone();
+        self.runtime.spawn(async move {
+            let to = message.get_to();
+            let mut buffer = Vec::with_capacity(message.encoded_len());
+            message
+                .encode(&mut buffer)
+                .expect("Error encoding raft message");
+            let sent = match transport.client(to).await {
+                Ok(mut client) => client.step(RaftMessage { message: buffer }).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                // Reconnect next time, raft will resend whatever was lost.
+                transport.clients.lock().unwrap().remove(&to);
+            }
+        });
+    }
+
+    async fn forward(&self, leader: u64, command: Command) -> Result<usize, ClusterError> {
+        let mut client = self.client(leader).await.map_err(ClusterError::Forwarded)?;
+        let response = client
+            .propose(ProposeRequest {
+                command: Some(command),
+            })
+            .await
+            .map_err(ClusterError::Forwarded)?;
+        Ok(response.into_inner().affected as usize)
+    }
+}
+
+/// The gRPC service nodes use to talk to each other.
+pub struct RaftService {
+    cluster: Arc<Cluster>,
+    acl: Option<Acl>,
+}
+
+impl RaftService {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        RaftService { cluster, acl: None }
+    }
+
+    /// Only accepts messages from principals that `acl` grants admin access to every
+    /// constellation, since they can change any of them.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), PermissionDenied> {
+        match &self.acl {
+            Some(acl) => acl.check_all(auth::principal(request).as_deref(), Role::Admin),
+            None => Ok(()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Raft for RaftService {
+    async fn step(&self, request: Request<RaftMessage>) -> Result<Response<RaftResponse>, Status> {
+        self.authorize(&request)?;
+        let message = Message::decode(&request.into_inner().message[..])
+            .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
+        self.cluster.step(message);
+        Ok(Response::new(RaftResponse {}))
+    }
+
+    async fn propose(
+        &self,
+        request: Request<ProposeRequest>,
+    ) -> Result<Response<ProposeResponse>, Status> {
+        self.authorize(&request)?;
+        let command = request
+            .into_inner()
+            .command
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "No command given"))?;
+        // Only the leader accepts forwarded writes, so they can't bounce between nodes.
+        if !self.cluster.is_leader() {
+            return Err(Status::new(
+                Code::Unavailable,
+                "This node is not the leader",
+            ));
+        }
+        let affected = self.cluster.propose_local(command).await?;
+        Ok(Response::new(ProposeResponse {
+            affected: affected as u64,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proximity_grpc::command::Command as SkyCommand;
+    use proximity_grpc::{AddRequest, CreateRequest, Point as GrpcPoint};
+    use std::sync::RwLock;
+
+    /// Delivers messages between nodes in the same process.
+    #[derive(Default)]
+    struct LocalTransport {
+        nodes: RwLock<HashMap<u64, Arc<Cluster>>>,
+    }
+
+    impl LocalTransport {
+        fn node(&self, id: u64) -> Arc<Cluster> {
+            self.nodes.read().unwrap()[&id].clone()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Transport for Arc<LocalTransport> {
+        fn send(&self, message: Message) {
+            self.node(message.get_to()).step(message);
+        }
+
+        async fn forward(&self, leader: u64, command: Command) -> Result<usize, ClusterError> {
+            self.node(leader).propose_local(command).await
+        }
+    }
+
+    fn start_cluster(size: u64) -> Vec<Arc<Cluster>> {
+        let transport = Arc::new(LocalTransport::default());
+        let ids: Vec<u64> = (1..=size).collect();
+        let nodes: Vec<Arc<Cluster>> = ids
+            .iter()
+            .map(|id| {
+                let peers: Vec<u64> = ids.iter().copied().filter(|p| p != id).collect();
+                Cluster::start(
+                    *id,
+                    &peers,
+                    Arc::new(Sky::default()),
+                    Arc::new(transport.clone()),
+                )
+                .unwrap()
+            })
+            .collect();
+        for node in &nodes {
+            transport
+                .nodes
+                .write()
+                .unwrap()
+                .insert(node.id(), node.clone());
+        }
+        nodes
+    }
+
+    async fn wait_for<F: Fn() -> bool>(condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        panic!("Timed out waiting for the cluster");
+    }
+
+    #[tokio::test]
+    async fn test_replicates_writes() {
+        let nodes = start_cluster(3);
+        wait_for(|| nodes.iter().all(|n| n.leader().is_some())).await;
+
+        // Send the writes to a follower, which forwards them to the leader.
+        let follower = nodes.iter().find(|n| !n.is_leader()).unwrap();
+        follower
+            .propose(Command {
+                command: Some(SkyCommand::Create(CreateRequest {
+                    name: "hello".into(),
+                    dimensions: 8,
+                    ..Default::default()
+                })),
+            })
+            .await
+            .unwrap();
+        let added = follower
+            .propose(Command {
+                command: Some(SkyCommand::Add(AddRequest {
+                    name: "hello".into(),
+                    points: vec![GrpcPoint {
+                        coords: vec![1.; 8],
+                    }],
+                    ..Default::default()
+                })),
+            })
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+
+        wait_for(|| {
+            nodes.iter().all(|n| {
+                n.sky()
+                    .describe(&"hello".into())
+                    .map(|m| m.count == 1)
+                    .unwrap_or(false)
+            })
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_linearizable_follower_reads() {
+        let nodes = start_cluster(3);
+        wait_for(|| nodes.iter().all(|n| n.leader().is_some())).await;
+        let leader = nodes.iter().find(|n| n.is_leader()).unwrap();
+        leader
+            .propose(Command {
+                command: Some(SkyCommand::Create(CreateRequest {
+                    name: "hello".into(),
+                    dimensions: 8,
+                    ..Default::default()
+                })),
+            })
+            .await
+            .unwrap();
+
+        // Once a linearizable read returns, every follower has applied the write.
+        for node in &nodes {
+            node.read(Consistency::Linearizable, DEFAULT_MAX_STALENESS)
+                .await
+                .unwrap();
+            assert!(node.sky().describe(&"hello".into()).is_ok());
+        }
+
+        // Reads from any replica never wait.
+        nodes[0]
+            .read(Consistency::AnyReplica, Duration::from_secs(0))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_returned_to_the_proposer() {
+        let nodes = start_cluster(3);
+        wait_for(|| nodes.iter().all(|n| n.leader().is_some())).await;
+        let leader = nodes.iter().find(|n| n.is_leader()).unwrap();
+
+        let result = leader
+            .propose(Command {
+                command: Some(SkyCommand::Create(CreateRequest {
+                    name: "hello".into(),
+                    dimensions: 7,
+                    ..Default::default()
+                })),
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(ClusterError::Sky(SkyError::InvalidSize(_)))
+        ));
+    }
+}
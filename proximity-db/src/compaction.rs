@@ -0,0 +1,61 @@
+use crate::sky::Sky;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Periodically compacts every constellation where enough points have been deleted.
+///
+/// Compaction copies the live points of a constellation into new segments and swaps them in, so
+/// searches carry on against the old segments while it runs.
+pub struct Compactor {
+    sky: Arc<Sky>,
+    interval: Duration,
+    min_dead_ratio: f32,
+}
+
+impl Compactor {
+    pub fn new(sky: Arc<Sky>, interval: Duration, min_dead_ratio: f32) -> Self {
+        Compactor {
+            sky,
+            interval,
+            min_dead_ratio,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let sky = self.sky.clone();
+            let min_dead_ratio = self.min_dead_ratio;
+            tokio::task::spawn_blocking(move || sky.compact_all(min_dead_ratio))
+                .await
+                .ok();
+        }
+    }
+}
+
+/// Periodically removes expired points from every constellation.
+///
+/// Searches already skip expired points, so this only frees the memory they take up. Each node
+/// in a cluster reaps its own sky, since every node works out the same expiry times.
+pub struct Reaper {
+    sky: Arc<Sky>,
+    interval: Duration,
+}
+
+impl Reaper {
+    pub fn new(sky: Arc<Sky>, interval: Duration) -> Self {
+        Reaper { sky, interval }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let sky = self.sky.clone();
+            tokio::task::spawn_blocking(move || sky.expire_all())
+                .await
+                .ok();
+        }
+    }
+}
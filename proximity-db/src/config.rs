@@ -0,0 +1,350 @@
+//! Reads options from a TOML config file.
+//!
+//! Each key in the file is the name of a command line option, e.g. `max-concurrent-queries = 4`,
+//! and options that can be given several times take a list, e.g. `quotas = ["logs-*=points:1000"]`.
+//! Options given on the command line or in the environment take precedence over the file, which
+//! takes precedence over the defaults.
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use structopt::clap::ArgMatches;
+use thiserror::Error;
+use toml::Value;
+
+/// An option that can be set in a config file.
+pub struct Setting {
+    /// The name of the option, which is also its key in the file.
+    name: &'static str,
+    /// The flag that sets it on the command line, without the leading dashes.
+    flag: &'static str,
+    short: Option<char>,
+    list: bool,
+    secret: bool,
+}
+
+impl Setting {
+    pub const fn value(name: &'static str) -> Self {
+        Setting {
+            name,
+            flag: name,
+            short: None,
+            list: false,
+            secret: false,
+        }
+    }
+
+    /// An option given once per value, with `--flag`.
+    pub const fn list(name: &'static str, flag: &'static str) -> Self {
+        Setting {
+            name,
+            flag,
+            short: None,
+            list: true,
+            secret: false,
+        }
+    }
+
+    /// An option that's left out when printing the config.
+    pub const fn secret(name: &'static str) -> Self {
+        Setting {
+            name,
+            flag: name,
+            short: None,
+            list: false,
+            secret: true,
+        }
+    }
+
+    /// An option that can also be given with `-short`.
+    pub const fn with_short(self, short: char) -> Self {
+        Setting {
+            short: Some(short),
+            ..self
+        }
+    }
+
+    /// The environment variable that sets this option.
+    fn env(&self) -> String {
+        format!("PROXIMITY_{}", self.name.replace('-', "_").to_uppercase())
+    }
+
+    /// Whether this option is given in command line `args`.
+    fn given_in(&self, args: &[OsString]) -> bool {
+        let long = format!("--{}", self.flag);
+        flags(args).any(|arg| {
+            let short = match (self.short, arg.strip_prefix('-')) {
+                (Some(short), Some(rest)) => !rest.starts_with('-') && rest.starts_with(short),
+                _ => false,
+            };
+            short || arg == long || arg.starts_with(&format!("{}=", long))
+        })
+    }
+}
+
+/// The arguments in `args` that look like options, before any `--`.
+fn flags(args: &[OsString]) -> impl Iterator<Item = &str> {
+    args.iter()
+        .skip(1)
+        .filter_map(|arg| arg.to_str())
+        .take_while(|arg| *arg != "--")
+        .filter(|arg| arg.starts_with('-'))
+}
+
+/// Finds the config file given in `args` with `--config`, or in the environment, without parsing
+/// the other options. They're only checked once the file has been merged in, so that options
+/// which require each other can be split between the file and the command line.
+pub fn find(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os("PROXIMITY_CONFIG").map(PathBuf::from)
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read the config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file: {0}")]
+    Invalid(#[from] toml::de::Error),
+    #[error("Unknown option {name:?} in the config file{}", did_you_mean(.suggestion))]
+    UnknownOption {
+        name: String,
+        suggestion: Option<&'static str>,
+    },
+    #[error("The option {name:?} in the config file {reason}")]
+    InvalidValue { name: String, reason: &'static str },
+}
+
+fn did_you_mean(suggestion: &Option<&str>) -> String {
+    match suggestion {
+        Some(suggestion) => format!(", did you mean {:?}?", suggestion),
+        None => String::new(),
+    }
+}
+
+pub struct ConfigFile {
+    values: Vec<(&'static Setting, Vec<String>)>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path, settings: &'static [Setting]) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.into(),
+            source,
+        })?;
+        ConfigFile::parse(&text, settings)
+    }
+
+    pub fn parse(text: &str, settings: &'static [Setting]) -> Result<Self, ConfigError> {
+        let table: toml::value::Table = toml::from_str(text)?;
+        let mut values = vec![];
+        for (name, value) in table {
+            let setting = match settings.iter().find(|s| s.name == name) {
+                Some(setting) => setting,
+                None => {
+                    let suggestion = settings
+                        .iter()
+                        .map(|s| s.name)
+                        .find(|s| strsim::jaro_winkler(s, &name) > 0.8);
+                    return Err(ConfigError::UnknownOption { name, suggestion });
+                }
+            };
+            let invalid = |reason| ConfigError::InvalidValue {
+                name: name.clone(),
+                reason,
+            };
+            let value = match value {
+                Value::Array(_) if !setting.list => {
+                    return Err(invalid("takes a single value, not a list"))
+                }
+                Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| scalar(item).ok_or_else(|| invalid("must be a list of values")))
+                    .collect::<Result<_, _>>()?,
+                value => {
+                    vec![scalar(value).ok_or_else(|| invalid("must be a string or a number"))?]
+                }
+            };
+            values.push((setting, value));
+        }
+        Ok(ConfigFile { values })
+    }
+
+    /// Adds the options from this file to command line `args`, unless they're already given
+    /// there or in the environment.
+    pub fn merge(&self, args: Vec<OsString>) -> Vec<OsString> {
+        let from_file = self.values.iter().filter(|(setting, _)| {
+            !setting.given_in(&args) && std::env::var_os(setting.env()).is_none()
+        });
+        let options: Vec<OsString> = from_file
+            .flat_map(|(setting, values)| {
+                values
+                    .iter()
+                    .map(move |value| format!("--{}={}", setting.flag, value).into())
+            })
+            .collect();
+        let mut merged = args;
+        let program = if merged.is_empty() { 0 } else { 1 };
+        merged.splice(program..program, options);
+        merged
+    }
+}
+
+fn scalar(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// The options that are in effect, as a config file.
+pub fn render(settings: &[Setting], matches: &ArgMatches) -> String {
+    let mut table = toml::value::Table::new();
+    for setting in settings.iter().filter(|s| !s.secret) {
+        let values: Vec<Value> = match matches.values_of(setting.name) {
+            Some(values) => values.map(typed).collect(),
+            None => continue,
+        };
+        let value = match setting.list {
+            true => Value::Array(values),
+            false => values.into_iter().next().unwrap(),
+        };
+        table.insert(setting.name.into(), value);
+    }
+    toml::to_string(&table).expect("Error rendering the config")
+}
+
+/// A value as a number if it looks like one, so it's rendered without quotes.
+fn typed(value: &str) -> Value {
+    let numeric = value
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == '.' || c == '-');
+    match (value.parse(), value.parse()) {
+        (Ok(i), _) if numeric => Value::Integer(i),
+        (_, Ok(f)) if numeric => Value::Float(f),
+        _ => Value::String(value.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::clap::{App, Arg};
+
+    static SETTINGS: &[Setting] = &[
+        Setting::value("address").with_short('a'),
+        Setting::value("compaction-ratio"),
+        Setting::list("quotas", "quota"),
+        Setting::secret("token"),
+    ];
+
+    fn app() -> App<'static, 'static> {
+        App::new("test")
+            .arg(
+                Arg::with_name("address")
+                    .short("a")
+                    .long("address")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("compaction-ratio")
+                    .long("compaction-ratio")
+                    .default_value("0.2"),
+            )
+            .arg(
+                Arg::with_name("quotas")
+                    .long("quota")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(Arg::with_name("token").long("token").takes_value(true))
+    }
+
+    fn parse_error(text: &str) -> String {
+        match ConfigFile::parse(text, SETTINGS) {
+            Ok(_) => panic!("{:?} should be invalid", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse_error("adress = \"[::1]:4321\""),
+            "Unknown option \"adress\" in the config file, did you mean \"address\"?"
+        );
+        assert_eq!(
+            parse_error("address = [\"a\", \"b\"]"),
+            "The option \"address\" in the config file takes a single value, not a list"
+        );
+        assert_eq!(
+            parse_error("[address]\nport = 1"),
+            "The option \"address\" in the config file must be a string or a number"
+        );
+        assert!(parse_error("address = ").starts_with("Invalid config file"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let file = ConfigFile::parse(
+            "address = \"[::1]:1234\"\ncompaction-ratio = 0.5\nquotas = [\"a=points:1\", \"b=points:2\"]",
+            SETTINGS,
+        )
+        .unwrap();
+        for address in &[&["--address=[::1]:5678"][..], &["-a", "[::1]:5678"]] {
+            let mut args: Vec<OsString> = vec!["test".into()];
+            args.extend(address.iter().map(Into::into));
+            let matches = app().get_matches_from(file.merge(args));
+
+            // The command line wins over the file.
+            assert_eq!(matches.value_of("address"), Some("[::1]:5678"));
+            assert_eq!(matches.value_of("compaction-ratio"), Some("0.5"));
+            let quotas: Vec<&str> = matches.values_of("quotas").unwrap().collect();
+            assert_eq!(quotas, vec!["a=points:1", "b=points:2"]);
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let find_in = |args: &[&str]| {
+            let args: Vec<OsString> = args.iter().map(Into::into).collect();
+            find(&args)
+        };
+        assert_eq!(
+            find_in(&["test", "--tls-cert", "a.pem", "--config", "a.toml"]),
+            Some("a.toml".into())
+        );
+        assert_eq!(find_in(&["test", "--config=b.toml"]), Some("b.toml".into()));
+        assert_eq!(find_in(&["test", "--", "--config=c.toml"]), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let matches = app().get_matches_from(vec![
+            "test",
+            "--address=[::1]:4321",
+            "--quota=a=points:1",
+            "--token=secret",
+        ]);
+        let rendered = render(SETTINGS, &matches);
+        assert_eq!(
+            rendered,
+            "address = \"[::1]:4321\"\ncompaction-ratio = 0.2\nquotas = [\"a=points:1\"]\n"
+        );
+        // What's printed can be read back in.
+        ConfigFile::parse(&rendered, SETTINGS).unwrap();
+    }
+}
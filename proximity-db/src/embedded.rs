@@ -0,0 +1,293 @@
+//! Runs a sky in-process, without a server.
+//!
+//! A `Database` keeps its sky in a data directory, saved in the same format as the server's
+//! `--data-dir`, so a directory can be opened by either. It's loaded when opened and saved when
+//! closed or dropped, and periodically as well with `with_snapshot_interval`. Only one database
+//! or server can use a directory at a time.
+//!
+//! ```no_run
+//! use proximity_db::embedded::Database;
+//! use std::num::NonZeroUsize;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = Database::open("/var/lib/proximity")?;
+//! db.add("hello", vec![vec![0.5; 8], vec![1.; 8]])?;
+//! let limit = NonZeroUsize::new(10).unwrap();
+//! for neighbour in db.search_nearest("hello", vec![0.; 8], 2., limit)? {
+//!     println!("{} {:?}", neighbour.distance, neighbour.coords());
+//! }
+//! db.close()?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::sky::{Metrics, Sky, SkyError};
+use crate::snapshot::{DataDir, SnapshotError};
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use proximity::QueryIterator;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub use proximity::Neighbour;
+
+pub struct Database {
+    sky: Arc<Sky>,
+    /// Where the sky is saved, until the database is closed.
+    data_dir: Option<Arc<DataDir>>,
+    /// The thread saving the database periodically, which stops once the sender is dropped.
+    saver: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Database {
+    /// Opens the database in the directory at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, SnapshotError> {
+        Database::open_with(path, Sky::default())
+    }
+
+    /// Opens the database into `sky`, which can have quotas or a memory limit.
+    pub fn open_with(path: impl Into<PathBuf>, sky: Sky) -> Result<Self, SnapshotError> {
+        let data_dir = DataDir::open(path)?;
+        data_dir.load(&sky)?;
+        Ok(Database {
+            sky: Arc::new(sky),
+            data_dir: Some(Arc::new(data_dir)),
+            saver: None,
+        })
+    }
+
+    /// Also saves the database every `interval`, from a background thread, so a crash only loses
+    /// the writes made since the last save.
+    pub fn with_snapshot_interval(mut self, interval: Duration) -> Self {
+        self.stop_saving();
+        let data_dir = match &self.data_dir {
+            Some(data_dir) => data_dir.clone(),
+            None => return self,
+        };
+        let sky = self.sky.clone();
+        let (stop, stopping) = bounded(0);
+        let saver = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopping.recv_timeout(interval) {
+                if let Err(e) = data_dir.save(&sky) {
+                    tracing::error!("Could not save the database: {}", e);
+                }
+            }
+        });
+        self.saver = Some((stop, saver));
+        self
+    }
+
+    /// A database that isn't saved anywhere.
+    pub fn in_memory() -> Self {
+        Database {
+            sky: Arc::new(Sky::default()),
+            data_dir: None,
+            saver: None,
+        }
+    }
+
+    /// The sky the database holds, e.g. to serve it with a `ProximityDBHandler`.
+    pub fn sky(&self) -> &Arc<Sky> {
+        &self.sky
+    }
+
+    /// Creates an empty constellation, whose points expire after `ttl` by default. Adding points
+    /// creates constellations too, so this is only needed to set a TTL or fix the dimensions.
+    pub fn create(
+        &self,
+        name: &str,
+        dimensions: usize,
+        ttl: Option<Duration>,
+    ) -> Result<(), SkyError> {
+        self.sky.create(name.into(), dimensions, ttl)
+    }
+
+    /// Removes a constellation and all of its points.
+    pub fn remove(&self, name: &str) -> Result<(), SkyError> {
+        self.sky.remove(&name.into())
+    }
+
+    /// Adds points, returning how many were added.
+    pub fn add(&self, name: &str, points: Vec<Vec<f32>>) -> Result<usize, SkyError> {
+        self.sky.add(name.into(), points)
+    }
+
+    /// Adds points that expire after `ttl`, or the constellation's default TTL if that's `None`.
+    pub fn add_with_ttl(
+        &self,
+        name: &str,
+        points: Vec<Vec<f32>>,
+        ttl: Option<Duration>,
+    ) -> Result<usize, SkyError> {
+        self.sky.add_with_ttl(name.into(), points, ttl)
+    }
+
+    /// Finds every point within `distance` of `point`, in no particular order.
+    pub fn search(
+        &self,
+        name: &str,
+        point: Vec<f32>,
+        distance: f32,
+    ) -> Result<QueryIterator, SkyError> {
+        self.sky.search(name.into(), distance, point, 0)
+    }
+
+    /// Finds the `limit` nearest points within `distance` of `point`, nearest first. Use `search`
+    /// for every point, which is what a limit of 0 means over gRPC.
+    pub fn search_nearest(
+        &self,
+        name: &str,
+        point: Vec<f32>,
+        distance: f32,
+        limit: NonZeroUsize,
+    ) -> Result<QueryIterator, SkyError> {
+        self.sky.search(name.into(), distance, point, limit.get())
+    }
+
+    /// Deletes points, returning how many were deleted.
+    pub fn delete(&self, name: &str, points: Vec<Vec<f32>>) -> Result<usize, SkyError> {
+        self.sky.delete(name.into(), points)
+    }
+
+    /// Describes the constellations whose names start with `prefix`.
+    pub fn list(&self, prefix: &str) -> Vec<Metrics> {
+        self.sky.list(&prefix.into())
+    }
+
+    pub fn describe(&self, name: &str) -> Result<Metrics, SkyError> {
+        self.sky.describe(&name.into())
+    }
+
+    /// Removes expired points and frees the space used by deleted ones, returning how many points
+    /// were removed. The server does this periodically.
+    pub fn compact(&self) -> usize {
+        self.sky.expire_all() + self.sky.compact_all(0.)
+    }
+
+    /// Saves the database, returning how many points were saved.
+    pub fn save(&self) -> Result<usize, SnapshotError> {
+        match &self.data_dir {
+            Some(data_dir) => data_dir.save(&self.sky),
+            None => Ok(0),
+        }
+    }
+
+    /// Saves and closes the database. Dropping it saves it too, but ignores any errors.
+    pub fn close(mut self) -> Result<usize, SnapshotError> {
+        self.stop_saving();
+        match self.data_dir.take() {
+            Some(data_dir) => data_dir.save(&self.sky),
+            None => Ok(0),
+        }
+    }
+
+    /// Waits for the thread saving the database periodically to finish, if there is one.
+    fn stop_saving(&mut self) {
+        if let Some((stop, saver)) = self.saver.take() {
+            drop(stop);
+            saver.join().ok();
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.stop_saving();
+        if let Err(e) = self.save() {
+            tracing::error!("Could not save the database: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("proximity-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_persists() {
+        let path = temp_dir("embedded");
+        let db = Database::open(&path).unwrap();
+        db.create("expiring", 8, Some(Duration::from_secs(60)))
+            .unwrap();
+        db.add("hello", (0..5).map(|i| vec![i as f32; 8]).collect())
+            .unwrap();
+        assert_eq!(db.delete("hello", vec![vec![0.; 8]]).unwrap(), 1);
+        assert_eq!(db.close().unwrap(), 4);
+
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.describe("hello").unwrap().count, 4);
+        assert_eq!(
+            db.describe("expiring").unwrap().ttl,
+            Some(Duration::from_secs(60))
+        );
+        let nearest: Vec<Vec<f32>> = db
+            .search_nearest("hello", vec![0.; 8], 100., NonZeroUsize::new(2).unwrap())
+            .unwrap()
+            .map(|n| n.coords().to_vec())
+            .collect();
+        assert_eq!(nearest, vec![vec![1.; 8], vec![2.; 8]]);
+        assert_eq!(db.search("hello", vec![0.; 8], 100.).unwrap().count(), 4);
+
+        // Only one database can use the directory at a time.
+        assert!(matches!(
+            Database::open(&path),
+            Err(SnapshotError::Locked(_))
+        ));
+
+        // Dropping saves too.
+        db.remove("expiring").unwrap();
+        drop(db);
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.list("").len(), 1);
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_saves_periodically() {
+        let path = temp_dir("embedded-periodic");
+        let db = Database::open(&path)
+            .unwrap()
+            .with_snapshot_interval(Duration::from_millis(10));
+        db.add("hello", vec![vec![1.; 8]]).unwrap();
+        let saved = || {
+            let mut file = std::fs::File::open(path.join("sky.snapshot")).ok()?;
+            crate::snapshot::read_sky(&Sky::default(), &mut file).ok()
+        };
+        for _ in 0..100 {
+            if saved() == Some(1) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(saved(), Some(1));
+        assert_eq!(db.close().unwrap(), 1);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let db = Database::in_memory();
+        assert!(matches!(db.describe("missing"), Err(SkyError::NotFound(_))));
+        db.add("hello", vec![vec![1.; 8]]).unwrap();
+        assert!(matches!(
+            db.search("hello", vec![1.; 64], 1.),
+            Err(SkyError::IncorrectSize { .. })
+        ));
+        assert_eq!(db.close().unwrap(), 0);
+
+        let path = temp_dir("embedded-invalid");
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("sky.snapshot"), "not a snapshot").unwrap();
+        assert!(matches!(
+            Database::open(&path),
+            Err(SnapshotError::Invalid(_))
+        ));
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
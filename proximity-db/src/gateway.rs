@@ -0,0 +1,577 @@
+//! An HTTP/JSON gateway, for clients that can't speak gRPC.
+//!
+//! It adds, searches, deletes, lists and describes constellations on this node's sky, as
+//! described by the OpenAPI document served at `/v1/openapi.json`. Errors get the same codes as
+//! over gRPC, with the matching HTTP status and a body like `{"code": "NotFound", "message": ...}`.
+//! Search results are streamed as one JSON object per line. Request bodies are limited to 4 MiB.
+use crate::acl::{Acl, PermissionDenied, Role};
+use crate::auth::{self, Keys};
+use crate::handler::SEARCH_BUFFER;
+use crate::sky::{self, Sky, SkyError};
+use futures::SinkExt;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tonic::{Code, Status};
+
+/// The OpenAPI description of the endpoints.
+pub const OPENAPI: &str = include_str!("openapi.json");
+
+/// The largest request body that's read, in bytes.
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct AddBody {
+    points: Vec<Vec<f32>>,
+    #[serde(default)]
+    ttl_seconds: u64,
+}
+
+#[derive(Deserialize)]
+struct DeleteBody {
+    points: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct SearchBody {
+    point: Vec<f32>,
+    distance: f32,
+    #[serde(default)]
+    limit: u32,
+}
+
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    distance: f32,
+    point: &'a [f32],
+}
+
+pub struct Gateway {
+    sky: Arc<Sky>,
+    keys: Option<Arc<Keys>>,
+    acl: Option<Acl>,
+}
+
+impl Gateway {
+    pub fn new(sky: Arc<Sky>) -> Self {
+        Gateway {
+            sky,
+            keys: None,
+            acl: None,
+        }
+    }
+
+    /// Only accepts requests with a bearer token from `keys`, like the gRPC service.
+    pub fn with_keys(mut self, keys: Arc<Keys>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Only lets callers use the constellations that `acl` grants them.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> hyper::Result<()> {
+        self.serve_with_shutdown(addr, futures::future::pending())
+            .await
+    }
+
+    /// Serves until `signal` resolves, then waits for the requests in flight to finish.
+    pub async fn serve_with_shutdown(
+        self,
+        addr: SocketAddr,
+        signal: impl Future<Output = ()>,
+    ) -> hyper::Result<()> {
+        let gateway = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let gateway = gateway.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let gateway = gateway.clone();
+                    async move { Ok::<_, Infallible>(gateway.handle(request).await) }
+                }))
+            }
+        });
+        hyper::Server::bind(&addr)
+            .serve(make_service)
+            .with_graceful_shutdown(signal)
+            .await
+    }
+
+    pub async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        match self.route(request).await {
+            Ok(response) => response,
+            Err(status) => error(status),
+        }
+    }
+
+    async fn route(&self, request: Request<Body>) -> Result<Response<Body>, Status> {
+        let path = request
+            .uri()
+            .path()
+            .trim_start_matches('/')
+            .split('/')
+            .map(decode)
+            .collect::<Result<Vec<_>, _>>()?;
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        if request.method() == Method::GET && path == ["v1", "openapi.json"] {
+            return Ok(json_response(OPENAPI));
+        }
+
+        let principal = self.authenticate(&request)?;
+        let principal = principal.as_deref();
+        match (request.method().clone(), path.as_slice()) {
+            (Method::GET, ["v1", "constellations"]) => {
+                let prefix = query_param(request.uri().query(), "prefix")?;
+                Ok(self.list(principal, &prefix.unwrap_or_default()))
+            }
+            (Method::GET, ["v1", "constellations", name]) => self.describe(principal, name),
+            (Method::POST, ["v1", "constellations", name, "points"]) => {
+                let name = name.to_string();
+                self.add(principal, name, parse(request).await?).await
+            }
+            (Method::POST, ["v1", "constellations", name, "points", "delete"]) => {
+                let name = name.to_string();
+                self.delete(principal, name, parse(request).await?).await
+            }
+            (Method::POST, ["v1", "constellations", name, "search"]) => {
+                let name = name.to_string();
+                self.search(principal, name, parse(request).await?).await
+            }
+            _ => Err(Status::new(Code::NotFound, "Unknown endpoint")),
+        }
+    }
+
+    fn authenticate(&self, request: &Request<Body>) -> Result<Option<String>, Status> {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(auth::token);
+        let principal = keys.authenticate(token)?;
+        Ok(principal.to_str().ok().map(Into::into))
+    }
+
+    fn authorize(
+        &self,
+        principal: Option<&str>,
+        name: &str,
+        role: Role,
+    ) -> Result<(), PermissionDenied> {
+        match &self.acl {
+            Some(acl) => acl.check(principal, name, role),
+            None => Ok(()),
+        }
+    }
+
+    fn list(&self, principal: Option<&str>, prefix: &String) -> Response<Body> {
+        let constellations: Vec<_> = self
+            .sky
+            .list(prefix)
+            .into_iter()
+            .filter(|metrics| self.authorize(principal, &metrics.name, Role::Read).is_ok())
+            .map(|metrics| describe_json(metrics.into()))
+            .collect();
+        json_response(json!({ "constellations": constellations }).to_string())
+    }
+
+    fn describe(&self, principal: Option<&str>, name: &str) -> Result<Response<Body>, Status> {
+        self.authorize(principal, name, Role::Read)?;
+        let metrics = self.sky.describe(&name.into())?;
+        Ok(json_response(describe_json(metrics.into()).to_string()))
+    }
+
+    async fn add(
+        &self,
+        principal: Option<&str>,
+        name: String,
+        body: AddBody,
+    ) -> Result<Response<Body>, Status> {
+        self.authorize(principal, &name, Role::Write)?;
+        let sky = self.sky.clone();
+        let total_added =
+            blocking(move || sky.add_with_ttl(name, body.points, sky::ttl(body.ttl_seconds)))
+                .await?;
+        Ok(json_response(
+            json!({ "total_added": total_added }).to_string(),
+        ))
+    }
+
+    async fn delete(
+        &self,
+        principal: Option<&str>,
+        name: String,
+        body: DeleteBody,
+    ) -> Result<Response<Body>, Status> {
+        self.authorize(principal, &name, Role::Write)?;
+        let sky = self.sky.clone();
+        let deleted_count = blocking(move || sky.delete(name, body.points)).await?;
+        Ok(json_response(
+            json!({ "deleted_count": deleted_count }).to_string(),
+        ))
+    }
+
+    async fn search(
+        &self,
+        principal: Option<&str>,
+        name: String,
+        body: SearchBody,
+    ) -> Result<Response<Body>, Status> {
+        self.authorize(principal, &name, Role::Read)?;
+        let sky = self.sky.clone();
+        // Errors are only known once the search starts, but must be sent before any results.
+        let (started, starting) = oneshot::channel();
+        let (mut tx, rx) = futures::channel::mpsc::channel(SEARCH_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let neighbours = match sky.search(name, body.distance, body.point, body.limit as usize)
+            {
+                Ok(neighbours) => neighbours,
+                Err(e) => {
+                    started.send(Err(e)).ok();
+                    return;
+                }
+            };
+            started.send(Ok(())).ok();
+            for neighbour in neighbours {
+                let result = SearchResult {
+                    distance: neighbour.distance,
+                    point: neighbour.coords(),
+                };
+                let mut line = serde_json::to_vec(&result).expect("Error serializing a result");
+                line.push(b'\n');
+                let sent = futures::executor::block_on(tx.send(Ok::<_, Infallible>(line)));
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+        starting
+            .await
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))??;
+
+        let mut response = Response::new(Body::wrap_stream(rx));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        Ok(response)
+    }
+}
+
+/// Runs a write off the async executor, as adding and deleting can take a while.
+async fn blocking<T: Send + 'static>(
+    write: impl FnOnce() -> Result<T, SkyError> + Send + 'static,
+) -> Result<T, Status> {
+    Ok(tokio::task::spawn_blocking(write)
+        .await
+        .map_err(|e| Status::new(Code::Internal, e.to_string()))??)
+}
+
+async fn parse<T: DeserializeOwned>(request: Request<Body>) -> Result<T, Status> {
+    let mut chunks = request.into_body();
+    let mut body = Vec::new();
+    while let Some(chunk) = chunks.data().await {
+        let chunk = chunk.map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
+        if body.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("The request body is larger than {} bytes", MAX_BODY_SIZE),
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&body).map_err(|e| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Invalid request body: {}", e),
+        )
+    })
+}
+
+fn decode(segment: &str) -> Result<String, Status> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .map(Into::into)
+        .map_err(|_| Status::new(Code::InvalidArgument, "The URL isn't valid UTF-8"))
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Result<Option<String>, Status> {
+    for pair in query.unwrap_or_default().split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            return decode(parts.next().unwrap_or_default()).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn describe_json(response: proximity_grpc::DescribeResponse) -> serde_json::Value {
+    let quotas: Vec<_> = response
+        .quotas
+        .into_iter()
+        .map(|usage| {
+            json!({
+                "pattern": usage.pattern,
+                "max_points": usage.max_points,
+                "max_bytes": usage.max_bytes,
+                "points": usage.points,
+                "bytes": usage.bytes,
+            })
+        })
+        .collect();
+    json!({
+        "name": response.name,
+        "dimensions": response.dimensions,
+        "count": response.count,
+        "dead_count": response.dead_count,
+        "memory_size": response.memory_size,
+        "ttl_seconds": response.ttl_seconds,
+        "pending_expiry": response.pending_expiry,
+        "quotas": quotas,
+    })
+}
+
+fn json_response(body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error(status: Status) -> Response<Body> {
+    let body = json!({
+        "code": format!("{:?}", status.code()),
+        "message": status.message(),
+    });
+    let mut response = json_response(body.to_string());
+    *response.status_mut() = http_status(status.code());
+    response
+}
+
+/// The HTTP status that best matches a gRPC code.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn call(
+        gateway: &Gateway,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = gateway.handle(request).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn points(values: &[f32]) -> String {
+        let points: Vec<Vec<f32>> = values.iter().map(|&v| vec![v; 8]).collect();
+        serde_json::to_string(&points).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let sky = Arc::new(Sky::default());
+        let gateway = Gateway::new(sky.clone());
+        let (status, body) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello%20world/points",
+            &format!(r#"{{"points": {}}}"#, points(&[1., 2., 3.])),
+        )
+        .await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"{"total_added":3}"#)
+        );
+
+        let (status, body) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello%20world/points/delete",
+            &format!(r#"{{"points": {}}}"#, points(&[3.])),
+        )
+        .await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"{"deleted_count":1}"#)
+        );
+
+        let (status, body) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello%20world/search",
+            &format!(
+                r#"{{"point": {:?}, "distance": 100, "limit": 1}}"#,
+                vec![2.; 8]
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let results: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(results, vec![json!({"distance": 0., "point": vec![2.; 8]})]);
+
+        let (status, body) = call(
+            &gateway,
+            Method::GET,
+            "/v1/constellations/hello%20world",
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let described: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(described["count"], 2);
+        assert_eq!(described["dimensions"], 8);
+
+        sky.create("other".into(), 8, Some(Duration::from_secs(60)))
+            .unwrap();
+        let (_, body) = call(&gateway, Method::GET, "/v1/constellations?prefix=hello", "").await;
+        let listed: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(listed["constellations"][0]["name"], "hello world");
+        assert_eq!(listed["constellations"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_search_streams_every_result() {
+        let gateway = Gateway::new(Arc::new(Sky::default()));
+        let count = SEARCH_BUFFER * 4;
+        let values: Vec<f32> = (0..count).map(|i| i as f32).collect();
+        let (status, _) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello/points",
+            &format!(r#"{{"points": {}}}"#, points(&values)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello/search",
+            &format!(r#"{{"point": {:?}, "distance": 1e9}}"#, vec![0.; 8]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.lines().count(), count);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let gateway = Gateway::new(Arc::new(Sky::default()));
+        let (status, body) = call(&gateway, Method::GET, "/v1/constellations/missing", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(error["code"], "NotFound");
+
+        let (status, _) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello/points",
+            r#"{"points": [[1, 2, 3]]}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/missing/search",
+            r#"{"point": [1, 2, 3, 4, 5, 6, 7, 8], "distance": 1}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello/points",
+            "{",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("Invalid request body"));
+        let (status, body) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/hello/points",
+            &" ".repeat(MAX_BODY_SIZE + 1),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("larger than"));
+        let (status, _) = call(&gateway, Method::DELETE, "/v1/constellations", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_acl() {
+        let acl = "* read public-".parse().unwrap();
+        let gateway = Gateway::new(Arc::new(Sky::default())).with_acl(acl);
+        let (status, _) = call(
+            &gateway,
+            Method::POST,
+            "/v1/constellations/public-hello/points",
+            &format!(r#"{{"points": {}}}"#, points(&[1.])),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_openapi() {
+        let openapi: serde_json::Value = serde_json::from_str(OPENAPI).unwrap();
+        for path in &[
+            "/v1/constellations",
+            "/v1/constellations/{name}",
+            "/v1/constellations/{name}/points",
+            "/v1/constellations/{name}/points/delete",
+            "/v1/constellations/{name}/search",
+        ] {
+            assert!(openapi["paths"][path].is_object(), "{} is missing", path);
+        }
+    }
+}
@@ -1,106 +1,349 @@
+use futures::executor::block_on;
+use futures::future::join_all;
+use proximity_grpc::command::Command as SkyCommand;
+use proximity_grpc::Consistency;
 use proximity_grpc::{
-    AddRequest, AddResponse, DeleteRequest, DeleteResponse, DescribeRequest, DescribeResponse,
-    ListRequest, Point as GrpcPoint, SearchRequest, SearchResponse,
+    AddRequest, AddResponse, Command, CompactRequest, CompactResponse, CreateRequest,
+    DeleteRequest, DeleteResponse, DescribeRequest, DescribeResponse, DropRequest, DropResponse,
+    ListRequest, Point as GrpcPoint, QuotaUsage as GrpcQuotaUsage, SearchRequest, SearchResponse,
+    StatsRequest, StatsResponse,
 };
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tonic::{Code, Request, Response, Status};
+use tracing::field::Empty;
+use tracing::Span;
 
+use crate::acl::{Acl, PermissionDenied, Role};
+use crate::auth;
+use crate::cluster::{Cluster, DEFAULT_MAX_STALENESS};
+use crate::metrics;
+use crate::shards::{self, Shards};
 use crate::sky::{Metrics, Sky};
+use crate::telemetry::SLOW_QUERY_TARGET;
 use proximity_grpc::proximity_db_server::ProximityDb;
 use std::sync::Arc;
 
+/// The number of search results buffered before the search waits for the client to read them.
+pub(crate) const SEARCH_BUFFER: usize = 256;
+
 #[derive(Default)]
 pub struct ProximityDBHandler {
     sky: Arc<Sky>,
+    cluster: Option<Arc<Cluster>>,
+    shards: Option<Arc<Shards>>,
+    acl: Option<Acl>,
+    slow_query_threshold: Option<Duration>,
 }
 
 impl ProximityDBHandler {
-    pub fn new(sky: Sky) -> Self {
-        ProximityDBHandler { sky: sky.into() }
+    pub fn new(sky: impl Into<Arc<Sky>>) -> Self {
+        ProximityDBHandler {
+            sky: sky.into(),
+            cluster: None,
+            shards: None,
+            acl: None,
+            slow_query_threshold: None,
+        }
+    }
+
+    /// A handler that replicates writes through the cluster, and reads from this node's sky.
+    pub fn replicated(cluster: Arc<Cluster>) -> Self {
+        ProximityDBHandler {
+            sky: cluster.sky(),
+            cluster: Some(cluster),
+            shards: None,
+            acl: None,
+            slow_query_threshold: None,
+        }
+    }
+
+    /// Makes this server one shard of every constellation, coordinating requests with the rest.
+    pub fn sharded(mut self, shards: Shards) -> Self {
+        self.shards = Some(Arc::new(shards));
+        self
+    }
+
+    /// Only lets callers use the constellations that `acl` grants them.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Logs searches of this node that take at least `threshold`, to the slow query log.
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    fn authorize(
+        &self,
+        principal: Option<&str>,
+        name: &str,
+        role: Role,
+    ) -> Result<(), PermissionDenied> {
+        match &self.acl {
+            Some(acl) => acl.check(principal, name, role),
+            None => Ok(()),
+        }
+    }
+
+    /// The shards a request should be fanned out to, unless it's for this shard alone.
+    fn shards_for<T>(&self, request: &Request<T>) -> Option<Arc<Shards>> {
+        match &self.shards {
+            Some(shards) if !shards::is_local(request) => Some(shards.clone()),
+            _ => None,
+        }
+    }
+
+    /// Waits until this node can serve a read with the consistency the client asked for.
+    async fn catch_up(&self, consistency: i32, max_staleness_ms: u64) -> Result<(), Status> {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+        let consistency = Consistency::from_i32(consistency)
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "Unknown consistency level"))?;
+        let max_staleness = match max_staleness_ms {
+            0 => DEFAULT_MAX_STALENESS,
+            ms => Duration::from_millis(ms),
+        };
+        Ok(cluster.read(consistency, max_staleness).await?)
+    }
+
+    /// Sends each part of a write to the shard it belongs to.
+    ///
+    /// This isn't atomic, so if one shard fails the others may still have applied their part.
+    async fn route(
+        &self,
+        shards: Option<Arc<Shards>>,
+        command: SkyCommand,
+    ) -> Result<usize, Status> {
+        let shards = match shards {
+            Some(shards) => shards,
+            None => return self.write(command).await,
+        };
+        let writes = shards.route(command).into_iter().map(|(shard, command)| {
+            let shards = shards.clone();
+            async move {
+                if Some(shard) == shards.index() {
+                    self.write(command).await
+                } else {
+                    shards.forward(shard, command).await
+                }
+            }
+        });
+        shards::skip_missing(join_all(writes).await)
+            .into_iter()
+            .sum()
+    }
+
+    /// Creates a constellation on one shard, returning its dimensions there.
+    async fn create_on(
+        &self,
+        shards: &Shards,
+        shard: usize,
+        request: CreateRequest,
+    ) -> Result<usize, Status> {
+        if Some(shard) != shards.index() {
+            return shards.create_on(shard, request).await;
+        }
+        let name = request.name.clone();
+        let dimensions = request.dimensions as usize;
+        match self.write(SkyCommand::Create(request)).await {
+            Ok(_) => Ok(dimensions),
+            Err(status) if status.code() == Code::AlreadyExists => {
+                Ok(self.sky.describe(&name)?.dimensions)
+            }
+            Err(status) => Err(status),
+        }
+    }
+
+    async fn write(&self, command: SkyCommand) -> Result<usize, Status> {
+        match &self.cluster {
+            Some(cluster) => Ok(cluster
+                .propose(Command {
+                    command: Some(command),
+                })
+                .await?),
+            None => {
+                let sky = self.sky.clone();
+                // Adding and deleting can take a while, so keep them off the async executor.
+                Ok(
+                    tokio::task::spawn_blocking(move || sky.apply(sky.prepare(command)?))
+                        .await
+                        .map_err(|e| Status::new(Code::Internal, e.to_string()))??,
+                )
+            }
+        }
     }
 }
 
 #[tonic::async_trait]
 impl ProximityDb for ProximityDBHandler {
-    type SearchStream = mpsc::UnboundedReceiver<Result<SearchResponse, Status>>;
+    type SearchStream = mpsc::Receiver<Result<SearchResponse, Status>>;
 
+    #[tracing::instrument(skip_all, fields(constellation = %request.get_ref().name))]
     async fn search(
         &self,
         request: Request<SearchRequest>,
     ) -> Result<Response<Self::SearchStream>, Status> {
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let slow_query = self.slow_query_threshold.map(|threshold| SlowQuery {
+            threshold,
+            client: request.remote_addr(),
+            principal: principal.clone(),
+        });
         let search_request = request.into_inner();
+        self.authorize(principal.as_deref(), &search_request.name, Role::Read)?;
 
         if search_request.point.is_none() {
             return Err(Status::new(Code::InvalidArgument, "No point given"));
         }
+        self.catch_up(search_request.consistency, search_request.max_staleness_ms)
+            .await?;
 
-        let sky_reference = self.sky.clone();
-
-        let (tx, rx) = mpsc::unbounded_channel();
-
-        tokio::task::spawn_blocking(move || {
-            match sky_reference.query(
-                search_request.name,
-                search_request.distance,
-                search_request.point.unwrap().coords,
-            ) {
-                Err(e) => {
-                    tx.send(Err(e.into())).unwrap();
-                }
-                Ok(query_iterator) => {
-                    for (distance, coords) in query_iterator {
-                        if tx
-                            .send(Ok(SearchResponse {
-                                distance,
-                                point: Some(GrpcPoint { coords }),
-                            }))
-                            .is_err()
-                        {
-                            break;
-                        }
-                    }
-                }
-            };
-        });
+        let (tx, rx) = mpsc::channel(SEARCH_BUFFER);
+        match shards {
+            Some(shards) => {
+                let (local_tx, local_rx) = mpsc::channel(SEARCH_BUFFER);
+                search_local(
+                    self.sky.clone(),
+                    search_request.clone(),
+                    local_tx,
+                    slow_query,
+                );
+                tokio::spawn(shards.search(search_request, Some(local_rx), tx));
+            }
+            None => search_local(self.sky.clone(), search_request, tx, slow_query),
+        }
         Ok(Response::new(rx))
     }
 
+    #[tracing::instrument(skip_all, fields(points = Empty))]
     async fn add(
         &self,
         request: Request<tonic::Streaming<AddRequest>>,
     ) -> Result<Response<AddResponse>, Status> {
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
         let mut stream = request.into_inner();
-        let sky = self.sky.clone();
         let mut total_added = 0;
         while let Some(add_request) = stream.message().await? {
-            total_added += sky.add(
-                add_request.name,
-                add_request.points.into_iter().map(|p| p.coords).collect(),
-            )?;
+            self.authorize(principal.as_deref(), &add_request.name, Role::Write)?;
+            if let Some(shards) = &shards {
+                shards
+                    .create_for(&add_request, |shard, request| {
+                        self.create_on(shards, shard, request)
+                    })
+                    .await?;
+            }
+            total_added += self
+                .route(shards.clone(), SkyCommand::Add(add_request))
+                .await?;
         }
+        Span::current().record("points", total_added);
         Ok(Response::new(AddResponse {
             total_added: total_added as u64,
         }))
     }
 
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            constellation = %request.get_ref().name,
+            points = request.get_ref().points.len(),
+        )
+    )]
     async fn delete(
         &self,
-        _request: Request<DeleteRequest>,
+        request: Request<DeleteRequest>,
     ) -> Result<Response<DeleteResponse>, Status> {
-        unimplemented!()
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let delete_request = request.into_inner();
+        self.authorize(principal.as_deref(), &delete_request.name, Role::Write)?;
+        let deleted_count = self
+            .route(shards, SkyCommand::Delete(delete_request))
+            .await?;
+
+        Ok(Response::new(DeleteResponse {
+            deleted_count: deleted_count as i32,
+        }))
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            constellation = %request.get_ref().name,
+            dimensions = request.get_ref().dimensions,
+        )
+    )]
+    async fn create(
+        &self,
+        request: Request<CreateRequest>,
+    ) -> Result<Response<DescribeResponse>, Status> {
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let create_request = request.into_inner();
+        self.authorize(principal.as_deref(), &create_request.name, Role::Admin)?;
+        let name = create_request.name.clone();
+        let dimensions = create_request.dimensions;
+        let ttl_seconds = create_request.ttl_seconds;
+        self.route(shards, SkyCommand::Create(create_request))
+            .await?;
+
+        Ok(Response::new(DescribeResponse {
+            name,
+            dimensions,
+            ttl_seconds,
+            ..Default::default()
+        }))
+    }
+
+    #[tracing::instrument(skip_all, fields(constellation = %request.get_ref().name))]
+    async fn drop(&self, request: Request<DropRequest>) -> Result<Response<DropResponse>, Status> {
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let drop_request = request.into_inner();
+        self.authorize(principal.as_deref(), &drop_request.name, Role::Admin)?;
+        self.route(shards, SkyCommand::Drop(drop_request)).await?;
+        Ok(Response::new(DropResponse {}))
     }
 
     type ListStream = mpsc::UnboundedReceiver<Result<DescribeResponse, Status>>;
 
+    #[tracing::instrument(skip_all, fields(prefix = %request.get_ref().prefix))]
     async fn list(
         &self,
         request: Request<ListRequest>,
     ) -> Result<Response<Self::ListStream>, Status> {
-        let prefix = request.into_inner().prefix;
-        let (tx, rx) = mpsc::unbounded_channel();
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let list_request = request.into_inner();
+        self.catch_up(list_request.consistency, list_request.max_staleness_ms)
+            .await?;
+        let local: Vec<DescribeResponse> = self
+            .sky
+            .list(&list_request.prefix)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        let responses = match shards {
+            Some(shards) => shards.list(list_request, local).await?,
+            None => local,
+        };
 
-        for metric in self.sky.list(&prefix) {
-            if tx.send(Ok(metric.into())).is_err() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let readable = responses.into_iter().filter(|response| {
+            self.authorize(principal.as_deref(), &response.name, Role::Read)
+                .is_ok()
+        });
+        for response in readable {
+            if tx.send(Ok(response)).is_err() {
                 break;
             }
         }
@@ -108,15 +351,162 @@ impl ProximityDb for ProximityDBHandler {
         Ok(Response::new(rx))
     }
 
+    #[tracing::instrument(skip_all, fields(constellation = %request.get_ref().name))]
     async fn describe(
         &self,
         request: Request<DescribeRequest>,
     ) -> Result<Response<DescribeResponse>, Status> {
-        let name = request.into_inner().name;
-        let metrics = self.sky.describe(&name)?;
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let describe_request = request.into_inner();
+        self.authorize(principal.as_deref(), &describe_request.name, Role::Read)?;
+        self.catch_up(
+            describe_request.consistency,
+            describe_request.max_staleness_ms,
+        )
+        .await?;
+        let local = self
+            .sky
+            .describe(&describe_request.name)
+            .map(Into::into)
+            .map_err(Into::into);
+
+        let response = match shards {
+            Some(shards) => shards.describe(describe_request, Some(local)).await?,
+            None => local?,
+        };
 
-        Ok(Response::new(metrics.into()))
+        Ok(Response::new(response))
     }
+
+    #[tracing::instrument(skip_all, fields(constellation = %request.get_ref().name))]
+    async fn compact(
+        &self,
+        request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let shards = self.shards_for(&request);
+        let principal = auth::principal(&request);
+        let compact_request = request.into_inner();
+        self.authorize(principal.as_deref(), &compact_request.name, Role::Admin)?;
+        let sky = self.sky.clone();
+        let name = compact_request.name.clone();
+        let local = tokio::task::spawn_blocking(move || {
+            if name.is_empty() {
+                Ok(sky.compact_all(0.))
+            } else {
+                sky.compact(&name)
+            }
+        })
+        .await
+        .map_err(|e| Status::new(Code::Internal, e.to_string()))?
+        .map_err(Into::into);
+
+        let purged_count = match shards {
+            Some(shards) => shards.compact(compact_request, Some(local)).await?,
+            None => local?,
+        };
+
+        Ok(Response::new(CompactResponse {
+            purged_count: purged_count as u64,
+        }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        // The totals cover every constellation, so callers need to be able to read them all.
+        self.authorize(auth::principal(&request).as_deref(), "", Role::Read)?;
+        let stats = self.sky.stats();
+        Ok(Response::new(StatsResponse {
+            constellations: stats.constellations as u64,
+            count: stats.count as u64,
+            memory_size: stats.memory_size as u64,
+            memory_limit: stats.memory_limit.unwrap_or(0) as u64,
+        }))
+    }
+}
+
+/// Who made a search, to log if it turns out to be slow.
+struct SlowQuery {
+    threshold: Duration,
+    client: Option<SocketAddr>,
+    principal: Option<String>,
+}
+
+/// Searches this node's sky, sending the results to `tx`.
+fn search_local(
+    sky: Arc<Sky>,
+    search_request: SearchRequest,
+    mut tx: mpsc::Sender<Result<SearchResponse, Status>>,
+    slow_query: Option<SlowQuery>,
+) {
+    let started = Instant::now();
+    // This covers the whole scan, which carries on after the request has returned the stream.
+    let span = tracing::info_span!(
+        "search_local",
+        constellation = %search_request.name,
+        dimensions = search_request.point.as_ref().map_or(0, |p| p.coords.len()),
+        distance = search_request.distance,
+        limit = search_request.limit,
+        scanned = Empty,
+        results = Empty,
+    );
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let scanned = sky.stored(&search_request.name);
+        if let Some(scanned) = scanned {
+            metrics::POINTS_SCANNED.observe(scanned as f64);
+            span.record("scanned", scanned);
+        }
+        let point = search_request.point.unwrap().coords;
+        let dimensions = point.len();
+        let neighbours = sky.search(
+            search_request.name.clone(),
+            search_request.distance,
+            point,
+            search_request.limit as usize,
+        );
+        match neighbours {
+            Err(e) => {
+                block_on(tx.send(Err(e.into()))).ok();
+            }
+            Ok(neighbours) => {
+                let mut results = 0;
+                for neighbour in neighbours {
+                    let response = SearchResponse {
+                        distance: neighbour.distance,
+                        point: Some(GrpcPoint {
+                            coords: neighbour.coords().to_vec(),
+                        }),
+                    };
+                    if block_on(tx.send(Ok(response))).is_err() {
+                        break;
+                    }
+                    results += 1;
+                }
+                span.record("results", results);
+
+                let elapsed = started.elapsed();
+                if let Some(slow_query) = slow_query.filter(|slow| elapsed >= slow.threshold) {
+                    tracing::warn!(
+                        target: SLOW_QUERY_TARGET,
+                        constellation = %search_request.name,
+                        radius = search_request.distance,
+                        dimensions,
+                        limit = search_request.limit,
+                        scanned = scanned.unwrap_or(0),
+                        results,
+                        wall_time_ms = elapsed.as_secs_f64() * 1000.,
+                        client = slow_query.client.map(display),
+                        principal = slow_query.principal.as_deref(),
+                        "Slow search"
+                    );
+                }
+            }
+        };
+    });
 }
 
 impl Into<DescribeResponse> for Metrics {
@@ -124,8 +514,22 @@ impl Into<DescribeResponse> for Metrics {
         DescribeResponse {
             name: self.name,
             count: self.count as u64,
+            dead_count: self.dead_count as u64,
             dimensions: self.dimensions as u64,
             memory_size: self.memory_size as u64,
+            quotas: self
+                .quotas
+                .into_iter()
+                .map(|usage| GrpcQuotaUsage {
+                    pattern: usage.quota.pattern.to_string(),
+                    max_points: usage.quota.max_points.unwrap_or(0) as u64,
+                    max_bytes: usage.quota.max_bytes.unwrap_or(0) as u64,
+                    points: usage.points as u64,
+                    bytes: usage.bytes as u64,
+                })
+                .collect(),
+            ttl_seconds: self.ttl.map_or(0, |ttl| ttl.as_secs()),
+            pending_expiry: self.pending_expiry as u64,
         }
     }
 }
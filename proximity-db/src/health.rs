@@ -0,0 +1,191 @@
+//! The standard gRPC health checking service, `grpc.health.v1.Health`.
+//!
+//! The empty service name and `grpc.ProximityDB` report on the server as a whole. Any other name
+//! is treated as a constellation, which is serving if the server is and this node holds it, so
+//! load balancers can wait for specific data to be ready.
+//!
+//! Health checks don't require authentication, so anyone who can reach the server can find out
+//! which constellations exist.
+use crate::handler::ProximityDBHandler;
+use crate::sky::Sky;
+use proximity_grpc::health::health_check_response::ServingStatus;
+use proximity_grpc::health::health_server::{Health, HealthServer};
+use proximity_grpc::health::{HealthCheckRequest, HealthCheckResponse};
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tonic::transport::NamedService;
+use tonic::{Code, Request, Response, Status};
+
+/// How often a watched constellation is checked for being created or dropped.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct HealthService {
+    sky: Arc<Sky>,
+    serving: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    stopped: Arc<Mutex<bool>>,
+}
+
+impl HealthService {
+    /// Starts out not serving, until `set_serving` is called once the server is ready.
+    pub fn new(sky: Arc<Sky>) -> Self {
+        let (serving, receiver) = watch::channel(false);
+        HealthService {
+            sky,
+            serving: Arc::new(serving),
+            receiver,
+            stopped: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    pub fn server(&self) -> HealthServer<HealthService> {
+        HealthServer::new(self.clone())
+    }
+
+    /// Marks the whole server as serving or not, e.g. while it's loading data. Does nothing once
+    /// `stop` has been called.
+    pub fn set_serving(&self, serving: bool) {
+        let stopped = self.stopped.lock().unwrap();
+        self.serving.broadcast(serving && !*stopped).ok();
+    }
+
+    /// Reports not serving from now on, once the server starts shutting down.
+    pub fn stop(&self) {
+        let mut stopped = self.stopped.lock().unwrap();
+        *stopped = true;
+        self.serving.broadcast(false).ok();
+    }
+
+    /// The status of a service, or `None` if there's no such service.
+    fn status(&self, service: &str) -> Option<ServingStatus> {
+        let server_name = <ProximityDbServer<ProximityDBHandler> as NamedService>::NAME;
+        if !service.is_empty() && service != server_name && !self.sky.contains(service) {
+            return None;
+        }
+        Some(match *self.receiver.borrow() {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        })
+    }
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse {
+        status: status as i32,
+    }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match self.status(&service) {
+            Some(status) => Ok(Response::new(response(status))),
+            None => Err(Status::new(
+                Code::NotFound,
+                format!("Unknown service {:?}", service),
+            )),
+        }
+    }
+
+    type WatchStream = mpsc::UnboundedReceiver<Result<HealthCheckResponse, Status>>;
+
+    /// Sends the current status, then again each time it changes until the client goes away.
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let health = self.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut changes = health.receiver.clone();
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            let mut last = None;
+            loop {
+                let status = health
+                    .status(&service)
+                    .unwrap_or(ServingStatus::ServiceUnknown);
+                if last != Some(status) {
+                    if tx.send(Ok(response(status))).is_err() {
+                        return;
+                    }
+                    last = Some(status);
+                }
+                tokio::select! {
+                    _ = changes.recv() => {}
+                    _ = interval.tick() => {}
+                }
+            }
+        });
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check(health: &HealthService, service: &str) -> Result<i32, Code> {
+        health
+            .check(Request::new(HealthCheckRequest {
+                service: service.into(),
+            }))
+            .await
+            .map(|response| response.into_inner().status)
+            .map_err(|status| status.code())
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let sky = Arc::new(Sky::default());
+        let health = HealthService::new(sky.clone());
+        let not_serving = Ok(ServingStatus::NotServing as i32);
+        assert_eq!(check(&health, "").await, not_serving);
+        assert_eq!(check(&health, "grpc.ProximityDB").await, not_serving);
+
+        health.set_serving(true);
+        assert_eq!(check(&health, "").await, Ok(ServingStatus::Serving as i32));
+        assert_eq!(check(&health, "hello").await, Err(Code::NotFound));
+        sky.create("hello".into(), 8, None).unwrap();
+        assert_eq!(
+            check(&health, "hello").await,
+            Ok(ServingStatus::Serving as i32)
+        );
+
+        health.set_serving(false);
+        assert_eq!(check(&health, "hello").await, not_serving);
+    }
+
+    #[tokio::test]
+    async fn test_stop() {
+        let health = HealthService::new(Arc::new(Sky::default()));
+        let not_serving = Ok(ServingStatus::NotServing as i32);
+        health.set_serving(true);
+        health.stop();
+        assert_eq!(check(&health, "").await, not_serving);
+        health.set_serving(true);
+        assert_eq!(check(&health, "").await, not_serving);
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let health = HealthService::new(Arc::new(Sky::default()));
+        let mut stream = health
+            .watch(Request::new(HealthCheckRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let status = stream.recv().await.unwrap().unwrap().status;
+        assert_eq!(status, ServingStatus::NotServing as i32);
+        health.set_serving(true);
+        let status = stream.recv().await.unwrap().unwrap().status;
+        assert_eq!(status, ServingStatus::Serving as i32);
+    }
+}
@@ -1,6 +1,23 @@
+pub mod acl;
+pub mod auth;
+pub mod cluster;
+pub mod compaction;
+pub mod config;
 pub mod constellation_builder;
+pub mod embedded;
+pub mod gateway;
 pub mod handler;
+pub mod health;
+pub mod metrics;
+pub mod pattern;
+pub mod quota;
+pub mod reflection;
+pub mod router;
+pub mod shards;
 pub mod sky;
+pub mod snapshot;
 pub mod supported_sizes;
+pub mod telemetry;
+pub mod tls;
 
 pub use supported_sizes::SupportedSize;
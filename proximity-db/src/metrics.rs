@@ -0,0 +1,465 @@
+//! Prometheus metrics, served over HTTP for scraping.
+//!
+//! Requests are counted and timed by wrapping the service in `Instrumented`, while `SkyCollector`
+//! reads the size of each constellation, and how busy the query pool and rayon threads are, when
+//! scraped.
+use crate::sky::Sky;
+use futures::Stream;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, Counter, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, TextEncoder,
+};
+use proximity::QueryPool;
+use proximity_grpc::proximity_db_server::ProximityDb;
+use proximity_grpc::{
+    AddRequest, AddResponse, CompactRequest, CompactResponse, CreateRequest, DeleteRequest,
+    DeleteResponse, DescribeRequest, DescribeResponse, DropRequest, DropResponse, ListRequest,
+    SearchRequest, StatsRequest, StatsResponse,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proximity_requests_total",
+        "Requests handled, by method and status code",
+        &["method", "code"]
+    )
+    .unwrap()
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proximity_request_duration_seconds",
+        "How long requests took, up to the end of the stream for streaming responses",
+        &["method"]
+    )
+    .unwrap()
+});
+
+/// Searches scan every stored point in the constellation, including deleted ones.
+pub static POINTS_SCANNED: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "proximity_search_points_scanned",
+        "Points compared against the query by each search on this server",
+        exponential_buckets(1000., 4., 10).unwrap()
+    )
+    .unwrap()
+});
+
+static SEARCH_RESULTS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "proximity_search_results",
+        "Points returned by each search",
+        exponential_buckets(1., 4., 10).unwrap()
+    )
+    .unwrap()
+});
+
+static POINTS_ADDED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("proximity_points_added_total", "Points added by requests").unwrap()
+});
+
+fn record(method: &str, code: Code, started: Instant) {
+    REQUESTS
+        .with_label_values(&[method, &format!("{:?}", code)])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[method])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+fn code<T>(result: &Result<T, Status>) -> Code {
+    match result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    }
+}
+
+/// A response stream that records its request once it ends or the client goes away.
+pub struct Metered<S> {
+    inner: S,
+    method: &'static str,
+    started: Instant,
+    /// The first error sent, which becomes the request's status code.
+    code: Code,
+    sent: usize,
+    sizes: Option<&'static Histogram>,
+}
+
+impl<S> Metered<S> {
+    fn new(inner: S, method: &'static str, started: Instant) -> Self {
+        Metered {
+            inner,
+            method,
+            started,
+            code: Code::Ok,
+            sent: 0,
+            sizes: None,
+        }
+    }
+
+    /// Also records how many messages were sent in `sizes`.
+    fn with_sizes(mut self, sizes: &'static Histogram) -> Self {
+        self.sizes = Some(sizes);
+        self
+    }
+}
+
+impl<S, T> Stream for Metered<S>
+where
+    S: Stream<Item = Result<T, Status>> + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = Pin::new(&mut self.inner).poll_next(cx);
+        match &item {
+            Poll::Ready(Some(Ok(_))) => self.sent += 1,
+            Poll::Ready(Some(Err(status))) if self.code == Code::Ok => self.code = status.code(),
+            _ => {}
+        }
+        item
+    }
+}
+
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        record(self.method, self.code, self.started);
+        if let Some(sizes) = self.sizes {
+            sizes.observe(self.sent as f64);
+        }
+    }
+}
+
+/// Wraps a service to count and time every request it handles.
+pub struct Instrumented<S> {
+    inner: S,
+}
+
+impl<S> Instrumented<S> {
+    pub fn new(inner: S) -> Self {
+        Instrumented { inner }
+    }
+}
+
+#[tonic::async_trait]
+impl<S> ProximityDb for Instrumented<S>
+where
+    S: ProximityDb,
+    S::SearchStream: Unpin,
+    S::ListStream: Unpin,
+{
+    type SearchStream = Metered<S::SearchStream>;
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        let started = Instant::now();
+        match self.inner.search(request).await {
+            Ok(response) => Ok(Response::new(
+                Metered::new(response.into_inner(), "search", started).with_sizes(&SEARCH_RESULTS),
+            )),
+            Err(status) => {
+                record("search", status.code(), started);
+                Err(status)
+            }
+        }
+    }
+
+    async fn add(
+        &self,
+        request: Request<Streaming<AddRequest>>,
+    ) -> Result<Response<AddResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.add(request).await;
+        if let Ok(response) = &result {
+            POINTS_ADDED.inc_by(response.get_ref().total_added as i64);
+        }
+        record("add", code(&result), started);
+        result
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.delete(request).await;
+        record("delete", code(&result), started);
+        result
+    }
+
+    async fn create(
+        &self,
+        request: Request<CreateRequest>,
+    ) -> Result<Response<DescribeResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.create(request).await;
+        record("create", code(&result), started);
+        result
+    }
+
+    async fn drop(&self, request: Request<DropRequest>) -> Result<Response<DropResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.drop(request).await;
+        record("drop", code(&result), started);
+        result
+    }
+
+    type ListStream = Metered<S::ListStream>;
+
+    async fn list(
+        &self,
+        request: Request<ListRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let started = Instant::now();
+        match self.inner.list(request).await {
+            Ok(response) => Ok(Response::new(Metered::new(
+                response.into_inner(),
+                "list",
+                started,
+            ))),
+            Err(status) => {
+                record("list", status.code(), started);
+                Err(status)
+            }
+        }
+    }
+
+    async fn describe(
+        &self,
+        request: Request<DescribeRequest>,
+    ) -> Result<Response<DescribeResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.describe(request).await;
+        record("describe", code(&result), started);
+        result
+    }
+
+    async fn compact(
+        &self,
+        request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.compact(request).await;
+        record("compact", code(&result), started);
+        result
+    }
+
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let started = Instant::now();
+        let result = self.inner.stats(request).await;
+        record("stats", code(&result), started);
+        result
+    }
+}
+
+/// Reports the size of every constellation in a sky, how many query pool slots are in use and
+/// how long rayon threads have spent scanning, each time the metrics are scraped.
+pub struct SkyCollector {
+    sky: Arc<Sky>,
+    points: IntGaugeVec,
+    memory_size: IntGaugeVec,
+    pending_expiry: IntGaugeVec,
+    queries_running: IntGauge,
+    max_concurrent_queries: IntGauge,
+    rayon_busy: Counter,
+    rayon_threads: IntGauge,
+}
+
+impl SkyCollector {
+    pub fn new(sky: Arc<Sky>) -> Self {
+        let gauges = |name: &str, help: &str| {
+            IntGaugeVec::new(Opts::new(name, help), &["constellation"]).unwrap()
+        };
+        SkyCollector {
+            sky,
+            points: gauges("proximity_constellation_points", "Live points"),
+            memory_size: gauges(
+                "proximity_constellation_memory_bytes",
+                "Memory used by stored points",
+            ),
+            pending_expiry: gauges(
+                "proximity_constellation_pending_expiry",
+                "Live points that will be removed once they expire",
+            ),
+            queries_running: IntGauge::new(
+                "proximity_query_pool_running",
+                "Searches that are scanning right now",
+            )
+            .unwrap(),
+            max_concurrent_queries: IntGauge::new(
+                "proximity_query_pool_max_concurrent",
+                "How many searches can scan at the same time",
+            )
+            .unwrap(),
+            rayon_busy: Counter::new(
+                "proximity_rayon_busy_seconds_total",
+                "Time rayon threads have spent scanning for searches",
+            )
+            .unwrap(),
+            rayon_threads: IntGauge::new("proximity_rayon_threads", "Threads in the rayon pool")
+                .unwrap(),
+        }
+    }
+}
+
+impl Collector for SkyCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![
+            self.points.desc(),
+            self.memory_size.desc(),
+            self.pending_expiry.desc(),
+            self.queries_running.desc(),
+            self.max_concurrent_queries.desc(),
+            self.rayon_busy.desc(),
+            self.rayon_threads.desc(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Dropped constellations shouldn't linger from earlier scrapes.
+        self.points.reset();
+        self.memory_size.reset();
+        self.pending_expiry.reset();
+        for metrics in self.sky.list(&String::new()) {
+            let labels = [metrics.name.as_str()];
+            self.points
+                .with_label_values(&labels)
+                .set(metrics.count as i64);
+            self.memory_size
+                .with_label_values(&labels)
+                .set(metrics.memory_size as i64);
+            self.pending_expiry
+                .with_label_values(&labels)
+                .set(metrics.pending_expiry as i64);
+        }
+        let pool = QueryPool::global();
+        self.queries_running.set(pool.running() as i64);
+        self.max_concurrent_queries
+            .set(pool.max_concurrent() as i64);
+        // The counter only goes up, so it's brought level with the pool's running total.
+        let busy = pool.busy_time().as_secs_f64();
+        self.rayon_busy
+            .inc_by((busy - self.rayon_busy.get()).max(0.));
+        self.rayon_threads.set(rayon::current_num_threads() as i64);
+
+        vec![
+            self.points.collect(),
+            self.memory_size.collect(),
+            self.pending_expiry.collect(),
+            self.queries_running.collect(),
+            self.max_concurrent_queries.collect(),
+            self.rayon_busy.collect(),
+            self.rayon_threads.collect(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Serves every registered metric at `/metrics`.
+pub async fn serve(addr: SocketAddr) -> hyper::Result<()> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(respond)) });
+    hyper::Server::bind(&addr).serve(make_service).await
+}
+
+async fn respond(request: hyper::Request<Body>) -> Result<hyper::Response<Body>, Infallible> {
+    let mut response = hyper::Response::new(Body::empty());
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => {
+            *response.body_mut() = Body::from(buffer);
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(prometheus::TEXT_FORMAT),
+            );
+        }
+        Err(e) => {
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            *response.body_mut() = Body::from(e.to_string());
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Registry;
+
+    #[test]
+    fn test_sky_collector() {
+        let sky = Arc::new(Sky::default());
+        sky.add("hello".into(), vec![vec![0.; 8]; 3]).unwrap();
+        let registry = Registry::new();
+        registry
+            .register(Box::new(SkyCollector::new(sky.clone())))
+            .unwrap();
+
+        let families = registry.gather();
+        let points = families
+            .iter()
+            .find(|f| f.get_name() == "proximity_constellation_points")
+            .unwrap();
+        let metric = &points.get_metric()[0];
+        assert_eq!(metric.get_label()[0].get_value(), "hello");
+        assert_eq!(metric.get_gauge().get_value(), 3.);
+        assert!(families
+            .iter()
+            .any(|f| f.get_name() == "proximity_rayon_busy_seconds_total"));
+
+        // Dropped constellations are no longer reported.
+        sky.remove(&"hello".into()).unwrap();
+        let families = registry.gather();
+        assert!(families
+            .iter()
+            .all(|f| f.get_name() != "proximity_constellation_points"));
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let stream = Metered::new(
+            futures::stream::empty::<Result<(), Status>>(),
+            "test",
+            Instant::now(),
+        );
+        drop(stream);
+
+        let request = |path| hyper::Request::get(path).body(Body::empty()).unwrap();
+        let response = respond(request("/metrics")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"proximity_requests_total{code="Ok",method="test"} 1"#));
+
+        let response = respond(request("/other")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,74 @@
+//! Patterns that pick out constellations by name, as used by quotas and ACL grants.
+//!
+//! A pattern is either the name of one constellation, or a prefix followed by `*`, which matches
+//! every constellation whose name starts with it. On its own, `*` matches every constellation.
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(String);
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid pattern {0:?}, expected a constellation name or a prefix followed by *")]
+pub struct InvalidPattern(String);
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self.0.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == self.0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = InvalidPattern;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.strip_suffix('*').unwrap_or(s);
+        if s.is_empty() || name.contains('*') || s.contains(char::is_whitespace) {
+            return Err(InvalidPattern(s.into()));
+        }
+        Ok(Pattern(s.into()))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let pattern: Pattern = "logs-*".parse().unwrap();
+        assert!(pattern.matches("logs-today"));
+        assert!(pattern.matches("logs-"));
+        assert!(!pattern.matches("metrics"));
+        let pattern: Pattern = "logs".parse().unwrap();
+        assert!(pattern.matches("logs"));
+        assert!(!pattern.matches("logs-today"));
+        let pattern: Pattern = "*".parse().unwrap();
+        assert!(pattern.matches("logs"));
+        assert!(pattern.matches(""));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for invalid in &["", "lo*gs", "*logs", "logs**", "logs today"] {
+            assert_eq!(
+                invalid.parse::<Pattern>(),
+                Err(InvalidPattern(invalid.to_string()))
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Limits on how many points and bytes constellations can hold.
+//!
+//! A quota applies to every constellation its pattern matches, with the limits covering all of
+//! them combined. A constellation can fall under several quotas, and adding points fails if it
+//! would exceed any of them.
+use crate::pattern::Pattern;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+    pub pattern: Pattern,
+    pub max_points: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl Quota {
+    pub fn applies_to(&self, name: &str) -> bool {
+        self.pattern.matches(name)
+    }
+}
+
+/// Parses quotas given as `pattern=points:N,bytes:N`, where either limit can be left out.
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let (pattern, limits) = match (parts.next(), parts.next()) {
+            (Some(pattern), Some(limits)) => (pattern, limits),
+            _ => anyhow::bail!(
+                "Quotas must be given as pattern=points:N,bytes:N, not {:?}",
+                s
+            ),
+        };
+        let mut quota = Quota {
+            pattern: pattern.parse()?,
+            max_points: None,
+            max_bytes: None,
+        };
+        for limit in limits.split(',') {
+            let mut parts = limit.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some("points"), Some(max)) => quota.max_points = Some(max.parse()?),
+                (Some("bytes"), Some(max)) => quota.max_bytes = Some(max.parse()?),
+                _ => anyhow::bail!(
+                    "Unknown quota limit {:?}, expected points:N or bytes:N",
+                    limit
+                ),
+            }
+        }
+        Ok(quota)
+    }
+}
+
+/// A quota, along with how much of it is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaUsage {
+    pub quota: Quota,
+    pub points: usize,
+    pub bytes: usize,
+}
+
+impl QuotaUsage {
+    /// The limit that adding `points` and `bytes` would go over, if any.
+    pub fn exceeded_by(&self, points: usize, bytes: usize) -> Option<String> {
+        match (self.quota.max_points, self.quota.max_bytes) {
+            (Some(max), _) if self.points + points > max => Some(format!("{} points", max)),
+            (_, Some(max)) if self.bytes + bytes > max => Some(format!("{} bytes", max)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "logs-*=points:100,bytes:2048".parse::<Quota>().unwrap(),
+            Quota {
+                pattern: "logs-*".parse().unwrap(),
+                max_points: Some(100),
+                max_bytes: Some(2048),
+            }
+        );
+        assert_eq!("hello=bytes:10".parse::<Quota>().unwrap().max_points, None);
+        assert!("hello".parse::<Quota>().is_err());
+        assert!("=points:10".parse::<Quota>().is_err());
+        assert!("lo*gs=points:10".parse::<Quota>().is_err());
+        assert!("hello=rows:10".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let quota: Quota = "logs-*=points:1".parse().unwrap();
+        assert!(quota.applies_to("logs-today"));
+        assert!(!quota.applies_to("metrics"));
+        let quota: Quota = "logs=points:1".parse().unwrap();
+        assert!(quota.applies_to("logs"));
+        assert!(!quota.applies_to("logs-today"));
+    }
+
+    #[test]
+    fn test_exceeded_by() {
+        let usage = QuotaUsage {
+            quota: "logs=points:10,bytes:100".parse().unwrap(),
+            points: 8,
+            bytes: 64,
+        };
+        assert_eq!(usage.exceeded_by(2, 32), None);
+        assert_eq!(usage.exceeded_by(3, 0), Some("10 points".into()));
+        assert_eq!(usage.exceeded_by(1, 64), Some("100 bytes".into()));
+    }
+}
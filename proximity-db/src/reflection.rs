@@ -0,0 +1,264 @@
+//! The gRPC server reflection service, `grpc.reflection.v1alpha.ServerReflection`.
+//!
+//! This lets tools like grpcurl list the services and describe their messages without a copy of
+//! the proto files, using the descriptor set that `proximity-grpc` builds from them.
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use proximity_grpc::reflection::server_reflection_request::MessageRequest;
+use proximity_grpc::reflection::server_reflection_response::MessageResponse;
+use proximity_grpc::reflection::server_reflection_server::{
+    ServerReflection, ServerReflectionServer,
+};
+use proximity_grpc::reflection::{
+    ErrorResponse, ExtensionNumberResponse, FileDescriptorResponse, ListServiceResponse,
+    ServerReflectionRequest, ServerReflectionResponse, ServiceResponse,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+struct Descriptors {
+    files: HashMap<String, FileDescriptorProto>,
+    /// Maps every fully qualified service, method, message and enum to the file defining it.
+    symbols: HashMap<String, String>,
+    /// The services the server runs, which are the only ones listed.
+    services: Vec<String>,
+}
+
+impl Descriptors {
+    fn add_messages(&mut self, file: &str, prefix: &str, messages: &[DescriptorProto]) {
+        for message in messages {
+            let name = format!("{}{}", prefix, message.name());
+            for nested in &message.enum_type {
+                self.symbols
+                    .insert(format!("{}.{}", name, nested.name()), file.into());
+            }
+            self.add_messages(file, &format!("{}.", name), &message.nested_type);
+            self.symbols.insert(name, file.into());
+        }
+    }
+
+    /// A file along with everything it imports, encoded.
+    fn file_with_imports(&self, name: &str) -> Option<Vec<Vec<u8>>> {
+        let mut pending = vec![name];
+        let mut seen = vec![];
+        let mut encoded = vec![];
+        while let Some(name) = pending.pop() {
+            if seen.contains(&name) {
+                continue;
+            }
+            let file = self.files.get(name)?;
+            let mut buffer = Vec::with_capacity(file.encoded_len());
+            file.encode(&mut buffer)
+                .expect("Error encoding file descriptor");
+            encoded.push(buffer);
+            pending.extend(file.dependency.iter().map(String::as_str));
+            seen.push(name);
+        }
+        Some(encoded)
+    }
+
+    fn respond(&self, request: MessageRequest) -> MessageResponse {
+        let not_found = |message: String| {
+            MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: Code::NotFound as i32,
+                error_message: message,
+            })
+        };
+        let file = |name: &str| {
+            self.file_with_imports(name).map(|file_descriptor_proto| {
+                MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+                    file_descriptor_proto,
+                })
+            })
+        };
+        match request {
+            MessageRequest::ListServices(_) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                })
+            }
+            MessageRequest::FileByFilename(name) => {
+                file(&name).unwrap_or_else(|| not_found(format!("Unknown file {:?}", name)))
+            }
+            MessageRequest::FileContainingSymbol(symbol) => self
+                .symbols
+                .get(&symbol)
+                .and_then(|name| file(name))
+                .unwrap_or_else(|| not_found(format!("Unknown symbol {:?}", symbol))),
+            // Proto3 doesn't have extensions, so no types have any.
+            MessageRequest::AllExtensionNumbersOfType(name) if self.symbols.contains_key(&name) => {
+                MessageResponse::AllExtensionNumbersResponse(ExtensionNumberResponse {
+                    base_type_name: name,
+                    extension_number: vec![],
+                })
+            }
+            MessageRequest::AllExtensionNumbersOfType(name) => {
+                not_found(format!("Unknown type {:?}", name))
+            }
+            MessageRequest::FileContainingExtension(extension) => not_found(format!(
+                "Unknown extension {} of {:?}",
+                extension.extension_number, extension.containing_type
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ReflectionService {
+    descriptors: Arc<Descriptors>,
+}
+
+impl ReflectionService {
+    /// Describes the files in an encoded `FileDescriptorSet`, listing only `services`, the fully
+    /// qualified names of the services the server runs.
+    pub fn new(descriptor_set: &[u8], services: &[&str]) -> Result<Self, prost::DecodeError> {
+        let mut descriptors = Descriptors {
+            files: HashMap::new(),
+            symbols: HashMap::new(),
+            services: services.iter().map(|name| name.to_string()).collect(),
+        };
+        for file in FileDescriptorSet::decode(descriptor_set)?.file {
+            let name = file.name().to_string();
+            let prefix = match file.package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+            for service in &file.service {
+                let service_name = format!("{}{}", prefix, service.name());
+                for method in &service.method {
+                    descriptors
+                        .symbols
+                        .insert(format!("{}.{}", service_name, method.name()), name.clone());
+                }
+                descriptors.symbols.insert(service_name, name.clone());
+            }
+            for enum_type in &file.enum_type {
+                descriptors
+                    .symbols
+                    .insert(format!("{}{}", prefix, enum_type.name()), name.clone());
+            }
+            descriptors.add_messages(&name, &prefix, &file.message_type);
+            descriptors.files.insert(name, file);
+        }
+        Ok(ReflectionService {
+            descriptors: Arc::new(descriptors),
+        })
+    }
+
+    pub fn server(&self) -> ServerReflectionServer<ReflectionService> {
+        ServerReflectionServer::new(self.clone())
+    }
+}
+
+#[tonic::async_trait]
+impl ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream =
+        mpsc::UnboundedReceiver<Result<ServerReflectionResponse, Status>>;
+
+    async fn server_reflection_info(
+        &self,
+        request: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let mut requests = request.into_inner();
+        let descriptors = self.descriptors.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let response = match requests.message().await {
+                    Ok(Some(request)) => match request.message_request.clone() {
+                        Some(message_request) => Ok(ServerReflectionResponse {
+                            valid_host: request.host.clone(),
+                            message_response: Some(descriptors.respond(message_request)),
+                            original_request: Some(request),
+                        }),
+                        None => Err(Status::new(Code::InvalidArgument, "Empty request")),
+                    },
+                    Ok(None) => return,
+                    Err(status) => Err(status),
+                };
+                if tx.send(response).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proximity_grpc::FILE_DESCRIPTOR_SET;
+
+    fn respond(request: MessageRequest) -> MessageResponse {
+        let services = ["grpc.ProximityDB", "grpc.health.v1.Health"];
+        let service = ReflectionService::new(FILE_DESCRIPTOR_SET, &services).unwrap();
+        service.descriptors.respond(request)
+    }
+
+    fn file_names(response: MessageResponse) -> Vec<String> {
+        match response {
+            MessageResponse::FileDescriptorResponse(response) => response
+                .file_descriptor_proto
+                .iter()
+                .map(|encoded| {
+                    FileDescriptorProto::decode(&encoded[..])
+                        .unwrap()
+                        .name()
+                        .to_string()
+                })
+                .collect(),
+            other => panic!("Expected files, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_list_services() {
+        match respond(MessageRequest::ListServices(String::new())) {
+            MessageResponse::ListServicesResponse(response) => {
+                let names: Vec<String> = response.service.into_iter().map(|s| s.name).collect();
+                // Services in the descriptor set that aren't served, like grpc.Raft, are left out.
+                assert_eq!(names, vec!["grpc.ProximityDB", "grpc.health.v1.Health"]);
+            }
+            other => panic!("Expected services, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_containing_symbol() {
+        for symbol in &[
+            "grpc.ProximityDB",
+            "grpc.ProximityDB.Search",
+            "grpc.Raft",
+            "grpc.SearchRequest",
+            "grpc.Consistency",
+        ] {
+            let response = respond(MessageRequest::FileContainingSymbol(symbol.to_string()));
+            assert_eq!(file_names(response), vec!["grpc.proto"]);
+        }
+        let nested = "grpc.health.v1.HealthCheckResponse.ServingStatus";
+        let response = respond(MessageRequest::FileContainingSymbol(nested.into()));
+        assert_eq!(file_names(response), vec!["health.proto"]);
+
+        assert!(matches!(
+            respond(MessageRequest::FileContainingSymbol("grpc.Nope".into())),
+            MessageResponse::ErrorResponse(ErrorResponse { error_code: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn test_file_by_filename() {
+        let response = respond(MessageRequest::FileByFilename("health.proto".into()));
+        assert_eq!(file_names(response), vec!["health.proto"]);
+        assert!(matches!(
+            respond(MessageRequest::FileByFilename("nope.proto".into())),
+            MessageResponse::ErrorResponse(_)
+        ));
+    }
+}
@@ -0,0 +1,355 @@
+//! Serves the `ProximityDb` service in front of a set of backend servers, without holding any
+//! data itself.
+//!
+//! Each constellation is mapped to a group of backends by the longest matching name prefix, and
+//! is sharded across every backend in that group. Writes are forwarded to the backend that owns
+//! each point, and searches are fanned out to the whole group with the results merged.
+use crate::acl::{Acl, PermissionDenied, Role};
+use crate::auth;
+use crate::handler::SEARCH_BUFFER;
+use crate::shards::Shards;
+use proximity_grpc::command::Command as SkyCommand;
+use proximity_grpc::proximity_db_server::ProximityDb;
+use proximity_grpc::{
+    AddRequest, AddResponse, CompactRequest, CompactResponse, CreateRequest, DeleteRequest,
+    DeleteResponse, DescribeRequest, DescribeResponse, DropRequest, DropResponse, ListRequest,
+    SearchRequest, SearchResponse, StatsRequest, StatsResponse,
+};
+use std::cmp::Reverse;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::ClientTlsConfig;
+use tonic::{Code, Request, Response, Status};
+
+pub struct Router {
+    /// Ordered by prefix length, longest first.
+    routes: Vec<(String, Arc<Shards>)>,
+    default: Option<Arc<Shards>>,
+    acl: Option<Acl>,
+    /// The `authorization` header sent to the backends.
+    token: Option<MetadataValue<Ascii>>,
+    tls: Option<ClientTlsConfig>,
+}
+
+impl Router {
+    /// A router that sends every constellation to `backends`, unless it matches a route.
+    pub fn new(backends: Vec<String>) -> Self {
+        let default = if backends.is_empty() {
+            None
+        } else {
+            Some(Arc::new(Shards::remote(backends)))
+        };
+        Router {
+            routes: vec![],
+            default,
+            acl: None,
+            token: None,
+            tls: None,
+        }
+    }
+
+    /// Sends constellations whose names start with `prefix` to `backends` instead.
+    pub fn route(mut self, prefix: impl Into<String>, backends: Vec<String>) -> Self {
+        let shards = connect(backends, &self.token, &self.tls);
+        self.routes.push((prefix.into(), shards));
+        self.routes.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        self
+    }
+
+    /// Authenticates with every backend, for backends that require a bearer token.
+    pub fn with_token(mut self, token: MetadataValue<Ascii>) -> Self {
+        self.token = Some(token);
+        self.reconnect()
+    }
+
+    /// Connects to every backend over TLS.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self.reconnect()
+    }
+
+    /// Connects to the backends of the routes added so far with the current token and TLS config.
+    fn reconnect(mut self) -> Self {
+        let (token, tls) = (&self.token, &self.tls);
+        let groups = self
+            .routes
+            .iter_mut()
+            .map(|(_, shards)| shards)
+            .chain(self.default.as_mut());
+        for shards in groups {
+            *shards = connect(shards.addresses().to_vec(), token, tls);
+        }
+        self
+    }
+
+    /// Only lets callers use the constellations that `acl` grants them.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    fn authorize(
+        &self,
+        principal: Option<&str>,
+        name: &str,
+        role: Role,
+    ) -> Result<(), PermissionDenied> {
+        match &self.acl {
+            Some(acl) => acl.check(principal, name, role),
+            None => Ok(()),
+        }
+    }
+
+    fn shards_for(&self, name: &str) -> Option<Arc<Shards>> {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .map(|(_, shards)| shards)
+            .or(self.default.as_ref())
+            .cloned()
+    }
+
+    fn groups(&self) -> impl Iterator<Item = &Arc<Shards>> {
+        self.routes
+            .iter()
+            .map(|(_, shards)| shards)
+            .chain(self.default.iter())
+    }
+}
+
+fn no_backends(name: &str) -> Status {
+    Status::new(
+        Code::Unavailable,
+        format!("No backends are configured for the constellation {}", name),
+    )
+}
+
+fn connect(
+    backends: Vec<String>,
+    token: &Option<MetadataValue<Ascii>>,
+    tls: &Option<ClientTlsConfig>,
+) -> Arc<Shards> {
+    let mut shards = Shards::remote(backends);
+    if let Some(token) = token {
+        shards = shards.with_token(token.clone());
+    }
+    if let Some(tls) = tls {
+        shards = shards.with_tls(tls.clone());
+    }
+    Arc::new(shards)
+}
+
+#[tonic::async_trait]
+impl ProximityDb for Router {
+    type SearchStream = mpsc::Receiver<Result<SearchResponse, Status>>;
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        let principal = auth::principal(&request);
+        let search_request = request.into_inner();
+        self.authorize(principal.as_deref(), &search_request.name, Role::Read)?;
+        if search_request.point.is_none() {
+            return Err(Status::new(Code::InvalidArgument, "No point given"));
+        }
+        let shards = self
+            .shards_for(&search_request.name)
+            .ok_or_else(|| no_backends(&search_request.name))?;
+
+        let (tx, rx) = mpsc::channel(SEARCH_BUFFER);
+        tokio::spawn(shards.search(search_request, None, tx));
+        Ok(Response::new(rx))
+    }
+
+    async fn add(
+        &self,
+        request: Request<tonic::Streaming<AddRequest>>,
+    ) -> Result<Response<AddResponse>, Status> {
+        let principal = auth::principal(&request);
+        let mut stream = request.into_inner();
+        let mut total_added = 0;
+        while let Some(add_request) = stream.message().await? {
+            self.authorize(principal.as_deref(), &add_request.name, Role::Write)?;
+            let shards = self
+                .shards_for(&add_request.name)
+                .ok_or_else(|| no_backends(&add_request.name))?;
+            shards
+                .create_for(&add_request, |shard, request| {
+                    shards.create_on(shard, request)
+                })
+                .await?;
+            total_added += shards.forward_all(SkyCommand::Add(add_request)).await?;
+        }
+        Ok(Response::new(AddResponse {
+            total_added: total_added as u64,
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let principal = auth::principal(&request);
+        let delete_request = request.into_inner();
+        self.authorize(principal.as_deref(), &delete_request.name, Role::Write)?;
+        let shards = self
+            .shards_for(&delete_request.name)
+            .ok_or_else(|| no_backends(&delete_request.name))?;
+        let deleted_count = shards
+            .forward_all(SkyCommand::Delete(delete_request))
+            .await?;
+
+        Ok(Response::new(DeleteResponse {
+            deleted_count: deleted_count as i32,
+        }))
+    }
+
+    async fn create(
+        &self,
+        request: Request<CreateRequest>,
+    ) -> Result<Response<DescribeResponse>, Status> {
+        let principal = auth::principal(&request);
+        let create_request = request.into_inner();
+        self.authorize(principal.as_deref(), &create_request.name, Role::Admin)?;
+        let shards = self
+            .shards_for(&create_request.name)
+            .ok_or_else(|| no_backends(&create_request.name))?;
+        let name = create_request.name.clone();
+        let dimensions = create_request.dimensions;
+        shards
+            .forward_all(SkyCommand::Create(create_request))
+            .await?;
+
+        Ok(Response::new(DescribeResponse {
+            name,
+            dimensions,
+            ..Default::default()
+        }))
+    }
+
+    async fn drop(&self, request: Request<DropRequest>) -> Result<Response<DropResponse>, Status> {
+        let principal = auth::principal(&request);
+        let drop_request = request.into_inner();
+        self.authorize(principal.as_deref(), &drop_request.name, Role::Admin)?;
+        let shards = self
+            .shards_for(&drop_request.name)
+            .ok_or_else(|| no_backends(&drop_request.name))?;
+        shards.forward_all(SkyCommand::Drop(drop_request)).await?;
+        Ok(Response::new(DropResponse {}))
+    }
+
+    type ListStream = mpsc::UnboundedReceiver<Result<DescribeResponse, Status>>;
+
+    async fn list(
+        &self,
+        request: Request<ListRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let principal = auth::principal(&request);
+        let list_request = request.into_inner();
+        let mut responses = vec![];
+        for shards in self.groups() {
+            let listed = shards.list(list_request.clone(), vec![]).await?;
+            // Backends can be in more than one group, so only keep the constellations that are
+            // routed to this one.
+            responses.extend(listed.into_iter().filter(|response| {
+                self.shards_for(&response.name)
+                    .map(|owner| Arc::ptr_eq(&owner, shards))
+                    .unwrap_or(false)
+                    && self
+                        .authorize(principal.as_deref(), &response.name, Role::Read)
+                        .is_ok()
+            }));
+        }
+        responses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        for response in responses {
+            if tx.send(Ok(response)).is_err() {
+                break;
+            }
+        }
+        Ok(Response::new(rx))
+    }
+
+    async fn describe(
+        &self,
+        request: Request<DescribeRequest>,
+    ) -> Result<Response<DescribeResponse>, Status> {
+        let principal = auth::principal(&request);
+        let describe_request = request.into_inner();
+        self.authorize(principal.as_deref(), &describe_request.name, Role::Read)?;
+        let shards = self
+            .shards_for(&describe_request.name)
+            .ok_or_else(|| no_backends(&describe_request.name))?;
+        Ok(Response::new(
+            shards.describe(describe_request, None).await?,
+        ))
+    }
+
+    async fn compact(
+        &self,
+        request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let principal = auth::principal(&request);
+        let compact_request = request.into_inner();
+        self.authorize(principal.as_deref(), &compact_request.name, Role::Admin)?;
+        let purged_count = if compact_request.name.is_empty() {
+            let mut purged_count = 0;
+            for shards in self.groups() {
+                purged_count += shards.compact(compact_request.clone(), None).await?;
+            }
+            purged_count
+        } else {
+            let shards = self
+                .shards_for(&compact_request.name)
+                .ok_or_else(|| no_backends(&compact_request.name))?;
+            shards.compact(compact_request, None).await?
+        };
+
+        Ok(Response::new(CompactResponse {
+            purged_count: purged_count as u64,
+        }))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        Err(Status::new(
+            Code::Unimplemented,
+            "The router doesn't hold any data, ask a backend for its stats instead",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let router = Router::new(vec!["http://default".into()])
+            .route("logs", vec!["http://logs".into()])
+            .route("logs-archive", vec!["http://archive".into()]);
+
+        let target = |name: &str| router.shards_for(name).unwrap();
+        assert!(Arc::ptr_eq(
+            &target("logs-archive-2020"),
+            &router.routes[0].1
+        ));
+        assert!(Arc::ptr_eq(&target("logs-today"), &router.routes[1].1));
+        assert!(Arc::ptr_eq(
+            &target("other"),
+            router.default.as_ref().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_no_backends() {
+        let router = Router::new(vec![]).route("logs", vec!["http://logs".into()]);
+        assert!(router.shards_for("logs").is_some());
+        assert!(router.shards_for("other").is_none());
+    }
+}
@@ -0,0 +1,662 @@
+//! Splits constellations across several servers.
+//!
+//! Every server holds one shard of each constellation. Points are assigned to a shard by hashing
+//! their coordinates, which are the only identity a point has, so a point is always added to and
+//! deleted from the same shard. Searches go to every shard and their results are merged.
+//!
+//! Any server can coordinate a request, as can a `Router` that holds no shards itself. The
+//! requests sent on to other shards are marked as local, so that they are served by that shard
+//! alone rather than being fanned out again.
+use crate::handler::SEARCH_BUFFER;
+use futures::future::join_all;
+use proximity_grpc::command::Command as SkyCommand;
+use proximity_grpc::proximity_db_client::ProximityDbClient;
+use proximity_grpc::{
+    AddRequest, CompactRequest, CreateRequest, DeleteRequest, DescribeRequest, DescribeResponse,
+    ListRequest, Point as GrpcPoint, SearchRequest, SearchResponse,
+};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, ClientTlsConfig};
+use tonic::{Code, Interceptor, Request, Status};
+
+/// Marks a request as being for the shard that receives it.
+pub const LOCAL_ONLY_HEADER: &str = "x-proximity-local";
+
+pub type SearchResults = mpsc::Receiver<Result<SearchResponse, Status>>;
+type SearchSender = mpsc::Sender<Result<SearchResponse, Status>>;
+
+pub fn is_local<T>(request: &Request<T>) -> bool {
+    request.metadata().contains_key(LOCAL_ONLY_HEADER)
+}
+
+fn local<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert(LOCAL_ONLY_HEADER, MetadataValue::from_static("1"));
+    request
+}
+
+pub struct Shards {
+    index: Option<usize>,
+    addresses: Vec<String>,
+    /// The `authorization` header sent to the other shards.
+    token: Option<MetadataValue<Ascii>>,
+    tls: Option<ClientTlsConfig>,
+    clients: Mutex<HashMap<usize, ProximityDbClient<Channel>>>,
+    /// The constellations this server has created on every shard.
+    created: Mutex<HashSet<String>>,
+}
+
+impl Shards {
+    /// `addresses` lists every shard in order, including this one at `index`. Every server must
+    /// be given the same list, as it decides which shard each point belongs to.
+    pub fn new(index: usize, addresses: Vec<String>) -> Self {
+        assert!(
+            index < addresses.len(),
+            "Shard {} is not one of the {} shards",
+            index,
+            addresses.len()
+        );
+        Shards {
+            index: Some(index),
+            addresses,
+            token: None,
+            tls: None,
+            clients: Mutex::new(HashMap::new()),
+            created: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Shards that are all held by other servers.
+    pub fn remote(addresses: Vec<String>) -> Self {
+        assert!(!addresses.is_empty(), "At least one shard is needed");
+        Shards {
+            index: None,
+            addresses,
+            token: None,
+            tls: None,
+            clients: Mutex::new(HashMap::new()),
+            created: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Authenticates with the other shards, for servers that require a bearer token.
+    pub fn with_token(mut self, token: MetadataValue<Ascii>) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Connects to the other shards over TLS.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    /// The shard this server holds, if it holds one.
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// The number of shards each constellation is split into.
+    pub fn count(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// Every shard that another server holds.
+    pub fn others(&self) -> impl Iterator<Item = usize> {
+        let index = self.index;
+        (0..self.count()).filter(move |shard| Some(*shard) != index)
+    }
+
+    pub fn shard_for(&self, coords: &[f32]) -> usize {
+        // FNV's low bits are poorly mixed, so this scales the hash into range rather than taking
+        // the remainder, which uses the high bits instead.
+        ((u128::from(hash(coords)) * self.count() as u128) >> 64) as usize
+    }
+
+    /// Splits a write into the writes each shard needs to apply. Points go to the shard that
+    /// owns them, while creating or dropping a constellation happens on every shard.
+    pub fn route(&self, command: SkyCommand) -> Vec<(usize, SkyCommand)> {
+        if let SkyCommand::Drop(request) = &command {
+            self.created.lock().unwrap().remove(&request.name);
+        }
+        match command {
+            SkyCommand::Add(AddRequest {
+                name,
+                points,
+                ttl_seconds,
+                expires_at,
+            }) => self
+                .split(points)
+                .into_iter()
+                .map(|(shard, points)| {
+                    let name = name.clone();
+                    let request = AddRequest {
+                        name,
+                        points,
+                        ttl_seconds,
+                        expires_at,
+                    };
+                    (shard, SkyCommand::Add(request))
+                })
+                .collect(),
+            SkyCommand::Delete(DeleteRequest { name, points }) => self
+                .split(points)
+                .into_iter()
+                .map(|(shard, points)| {
+                    let name = name.clone();
+                    (shard, SkyCommand::Delete(DeleteRequest { name, points }))
+                })
+                .collect(),
+            command => (0..self.count())
+                .map(|shard| (shard, command.clone()))
+                .collect(),
+        }
+    }
+
+    fn split(&self, points: Vec<GrpcPoint>) -> BTreeMap<usize, Vec<GrpcPoint>> {
+        let mut shards: BTreeMap<usize, Vec<GrpcPoint>> = BTreeMap::new();
+        for point in points {
+            shards
+                .entry(self.shard_for(&point.coords))
+                .or_default()
+                .push(point);
+        }
+        shards
+    }
+
+    async fn client(&self, shard: usize) -> Result<ProximityDbClient<Channel>, Status> {
+        if let Some(client) = self.clients.lock().unwrap().get(&shard) {
+            // This is super cheap, see https://github.com/hyperium/tonic/issues/285
+            return Ok(client.clone());
+        }
+        let mut endpoint = Channel::from_shared(self.addresses[shard].clone()).map_err(|e| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("Invalid address for shard {}: {}", shard, e),
+            )
+        })?;
+        if let Some(tls) = self.tls.clone() {
+            endpoint = endpoint.tls_config(tls);
+        }
+        let channel = endpoint.connect().await.map_err(|e| {
+            Status::new(
+                Code::Unavailable,
+                format!("Error connecting to shard {}: {}", shard, e),
+            )
+        })?;
+        let client = match self.token.clone() {
+            Some(token) => ProximityDbClient::with_interceptor(
+                channel,
+                Interceptor::new(move |mut request: Request<()>| {
+                    request
+                        .metadata_mut()
+                        .insert("authorization", token.clone());
+                    Ok(request)
+                }),
+            ),
+            None => ProximityDbClient::new(channel),
+        };
+        self.clients.lock().unwrap().insert(shard, client.clone());
+        Ok(client)
+    }
+
+    /// Creates the constellation `add` is for on every shard before its points are added, unless
+    /// this server already has. `create_on` creates it on one shard, returning the dimensions it
+    /// has there.
+    ///
+    /// The shards are created one at a time, starting with the first. Once the first shard has
+    /// the constellation, the rest are given its dimensions, so concurrent adds of points with
+    /// different dimensions can't leave the shards disagreeing.
+    pub async fn create_for<C, F>(&self, add: &AddRequest, create_on: C) -> Result<(), Status>
+    where
+        C: Fn(usize, CreateRequest) -> F,
+        F: Future<Output = Result<usize, Status>>,
+    {
+        let point = match add.points.first() {
+            Some(point) => point,
+            None => return Ok(()),
+        };
+        if self.created.lock().unwrap().contains(&add.name) {
+            return Ok(());
+        }
+        let mut dimensions = point.coords.len();
+        for shard in 0..self.count() {
+            let request = CreateRequest {
+                name: add.name.clone(),
+                dimensions: dimensions as u64,
+                ttl_seconds: 0,
+            };
+            dimensions = create_on(shard, request).await?;
+        }
+        self.created.lock().unwrap().insert(add.name.clone());
+        Ok(())
+    }
+
+    /// Creates a constellation on another shard, returning its dimensions there, which are the
+    /// ones it already had if it existed.
+    pub async fn create_on(&self, shard: usize, request: CreateRequest) -> Result<usize, Status> {
+        let name = request.name.clone();
+        let dimensions = request.dimensions as usize;
+        match self.forward(shard, SkyCommand::Create(request)).await {
+            Ok(_) => Ok(dimensions),
+            Err(status) if status.code() == Code::AlreadyExists => {
+                let mut client = self.client(shard).await?;
+                let request = DescribeRequest {
+                    name,
+                    ..Default::default()
+                };
+                let response = client.describe(local(request)).await?.into_inner();
+                Ok(response.dimensions as usize)
+            }
+            Err(status) => Err(status),
+        }
+    }
+
+    /// Sends a write to another shard, returning the number of points it added or deleted.
+    pub async fn forward(&self, shard: usize, command: SkyCommand) -> Result<usize, Status> {
+        let mut client = self.client(shard).await?;
+        let affected = match command {
+            SkyCommand::Add(request) => {
+                let stream = futures::stream::iter(vec![request]);
+                client.add(local(stream)).await?.into_inner().total_added as usize
+            }
+            SkyCommand::Delete(request) => {
+                client
+                    .delete(local(request))
+                    .await?
+                    .into_inner()
+                    .deleted_count as usize
+            }
+            SkyCommand::Create(request) => {
+                client.create(local(request)).await?;
+                0
+            }
+            SkyCommand::Drop(request) => {
+                client.drop(local(request)).await?;
+                0
+            }
+        };
+        Ok(affected)
+    }
+
+    /// Sends every part of a write to the other servers, when none of the shards are held here.
+    pub async fn forward_all(&self, command: SkyCommand) -> Result<usize, Status> {
+        let writes = self
+            .route(command)
+            .into_iter()
+            .map(|(shard, command)| self.forward(shard, command));
+        skip_missing(join_all(writes).await).into_iter().sum()
+    }
+
+    /// Describes a constellation across every shard, combined with `local` from this shard.
+    pub async fn describe(
+        &self,
+        request: DescribeRequest,
+        local_response: Option<Result<DescribeResponse, Status>>,
+    ) -> Result<DescribeResponse, Status> {
+        let remote = self.others().map(|shard| {
+            let request = request.clone();
+            async move {
+                let mut client = self.client(shard).await?;
+                Ok(client.describe(local(request)).await?.into_inner())
+            }
+        });
+        let mut responses = join_all(remote).await;
+        responses.extend(local_response);
+        let found = skip_missing(responses)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(merge(found).into_iter().next().unwrap())
+    }
+
+    /// Lists the constellations on every shard, combined with `local` from this shard.
+    pub async fn list(
+        &self,
+        request: ListRequest,
+        local_responses: Vec<DescribeResponse>,
+    ) -> Result<Vec<DescribeResponse>, Status> {
+        let remote = self.others().map(|shard| {
+            let request = request.clone();
+            async move {
+                let mut client = self.client(shard).await?;
+                let mut stream = client.list(local(request)).await?.into_inner();
+                let mut responses = vec![];
+                while let Some(response) = stream.message().await? {
+                    responses.push(response);
+                }
+                Ok::<_, Status>(responses)
+            }
+        });
+        let mut responses = local_responses;
+        for remote in join_all(remote).await {
+            responses.extend(remote?);
+        }
+        Ok(merge(responses))
+    }
+
+    /// Compacts a constellation on every other shard, or every constellation if no name is given,
+    /// returning how many points were purged along with `local_purged` from this shard.
+    pub async fn compact(
+        &self,
+        request: CompactRequest,
+        local_purged: Option<Result<usize, Status>>,
+    ) -> Result<usize, Status> {
+        let compactions = self.others().map(|shard| {
+            let request = request.clone();
+            async move {
+                let mut client = self.client(shard).await?;
+                let response = client.compact(local(request)).await?.into_inner();
+                Ok(response.purged_count as usize)
+            }
+        });
+        let mut purged = join_all(compactions).await;
+        purged.extend(local_purged);
+        skip_missing(purged).into_iter().sum()
+    }
+
+    /// Searches every other shard, merging their results with `local_results` from this shard.
+    ///
+    /// With a limit, each shard returns its nearest points and only the nearest of those are
+    /// sent on. Shards where the constellation doesn't exist are skipped, unless it doesn't exist
+    /// on any of them.
+    pub async fn search(
+        self: Arc<Self>,
+        request: SearchRequest,
+        local_results: Option<SearchResults>,
+        mut tx: SearchSender,
+    ) {
+        let (merged_tx, mut merged) = mpsc::channel(SEARCH_BUFFER);
+        if let Some(local_results) = local_results {
+            tokio::spawn(pipe(local_results, merged_tx.clone()));
+        }
+        for shard in self.others() {
+            let shards = self.clone();
+            let request = request.clone();
+            let mut merged_tx = merged_tx.clone();
+            tokio::spawn(async move {
+                let mut client = match shards.client(shard).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        merged_tx.send(Err(e)).await.ok();
+                        return;
+                    }
+                };
+                match client.search(local(request)).await {
+                    Ok(response) => stream_into(response.into_inner(), merged_tx).await,
+                    Err(e) => {
+                        merged_tx.send(Err(e)).await.ok();
+                    }
+                }
+            });
+        }
+        drop(merged_tx);
+
+        let limit = request.limit as usize;
+        let mut missing = vec![];
+        let mut results = vec![];
+        while let Some(result) = merged.recv().await {
+            let response = match result {
+                Ok(response) => response,
+                Err(status) if status.code() == Code::NotFound => {
+                    missing.push(status);
+                    continue;
+                }
+                Err(status) => {
+                    tx.send(Err(status)).await.ok();
+                    return;
+                }
+            };
+            if limit > 0 {
+                results.push(response);
+            } else if tx.send(Ok(response)).await.is_err() {
+                return;
+            }
+        }
+
+        if missing.len() == self.count() {
+            tx.send(Err(missing.remove(0))).await.ok();
+            return;
+        }
+        for response in nearest(results, limit, |r| r.distance) {
+            if tx.send(Ok(response)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn pipe(mut from: SearchResults, mut to: SearchSender) {
+    while let Some(result) = from.recv().await {
+        if to.send(result).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn stream_into(mut stream: tonic::Streaming<SearchResponse>, mut to: SearchSender) {
+    loop {
+        let result = match stream.message().await {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => return,
+            Err(status) => Err(status),
+        };
+        let failed = result.is_err();
+        if to.send(result).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Drops the errors from shards where the constellation doesn't exist, unless it doesn't exist
+/// on any of them. Adds create a constellation on every shard first, but a shard can still be
+/// missing one, e.g. if it was restarted without a data directory.
+pub fn skip_missing<T>(results: Vec<Result<T, Status>>) -> Vec<Result<T, Status>> {
+    let is_missing = |result: &Result<T, Status>| match result {
+        Err(status) => status.code() == Code::NotFound,
+        Ok(_) => false,
+    };
+    if results.iter().all(is_missing) {
+        results.into_iter().take(1).collect()
+    } else {
+        results.into_iter().filter(|r| !is_missing(r)).collect()
+    }
+}
+
+/// Combines each shard's description of a constellation.
+fn merge(responses: Vec<DescribeResponse>) -> Vec<DescribeResponse> {
+    let mut merged: BTreeMap<String, DescribeResponse> = BTreeMap::new();
+    for response in responses {
+        let total = merged
+            .entry(response.name.clone())
+            .or_insert_with(|| DescribeResponse {
+                name: response.name.clone(),
+                dimensions: response.dimensions,
+                ttl_seconds: response.ttl_seconds,
+                ..Default::default()
+            });
+        total.count += response.count;
+        total.pending_expiry += response.pending_expiry;
+        total.dead_count += response.dead_count;
+        total.memory_size += response.memory_size;
+        // Every shard enforces its quotas separately, so the limits add up like the usage does.
+        for usage in response.quotas {
+            match total.quotas.iter_mut().find(|q| q.pattern == usage.pattern) {
+                Some(q) => {
+                    q.max_points += usage.max_points;
+                    q.max_bytes += usage.max_bytes;
+                    q.points += usage.points;
+                    q.bytes += usage.bytes;
+                }
+                None => total.quotas.push(usage),
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+/// Keeps the `k` results with the smallest distances, nearest first.
+pub fn nearest<T>(
+    results: impl IntoIterator<Item = T>,
+    k: usize,
+    distance: impl Fn(&T) -> f32,
+) -> Vec<T> {
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for result in results {
+        heap.push(ByDistance(distance(&result), result));
+        if heap.len() > k {
+            // This is a max heap, so this drops the furthest result.
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|ByDistance(_, result)| result)
+        .collect()
+}
+
+struct ByDistance<T>(f32, T);
+
+impl<T> PartialEq for ByDistance<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for ByDistance<T> {}
+
+impl<T> PartialOrd for ByDistance<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for ByDistance<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// FNV-1a over the bits of each coordinate. This has to give the same answer on every server,
+/// whatever version of Rust it was built with, so `DefaultHasher` won't do.
+fn hash(coords: &[f32]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for coord in coords {
+        for byte in coord.to_bits().to_le_bytes().iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proximity_grpc::QuotaUsage;
+
+    fn shards(count: usize) -> Shards {
+        let addresses = (0..count).map(|i| format!("http://shard-{}", i)).collect();
+        Shards::new(0, addresses)
+    }
+
+    #[test]
+    fn test_route_points() {
+        let shards = shards(4);
+        let points: Vec<GrpcPoint> = (0..100)
+            .map(|i| GrpcPoint {
+                coords: vec![i as f32; 4],
+            })
+            .collect();
+        let routed = shards.route(SkyCommand::Add(AddRequest {
+            name: "hello".into(),
+            points,
+            ttl_seconds: 60,
+            ..Default::default()
+        }));
+        assert_eq!(routed.len(), 4);
+
+        let mut total = 0;
+        for (shard, command) in routed {
+            match command {
+                SkyCommand::Add(request) => {
+                    assert_eq!(request.name, "hello");
+                    assert_eq!(request.ttl_seconds, 60);
+                    assert!(request
+                        .points
+                        .iter()
+                        .all(|p| shards.shard_for(&p.coords) == shard));
+                    total += request.points.len();
+                }
+                _ => panic!("Expected an add"),
+            }
+        }
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn test_route_broadcasts_create() {
+        let routed = shards(3).route(SkyCommand::Create(CreateRequest {
+            name: "hello".into(),
+            dimensions: 8,
+            ..Default::default()
+        }));
+        let targets: Vec<usize> = routed.iter().map(|(shard, _)| *shard).collect();
+        assert_eq!(targets, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let distances = vec![5., 1., 4., 2., 3.];
+        assert_eq!(nearest(distances.clone(), 3, |d| *d), vec![1., 2., 3.]);
+        assert_eq!(nearest(distances, 10, |d| *d), vec![1., 2., 3., 4., 5.]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let response = |name: &str, count| DescribeResponse {
+            name: name.into(),
+            dimensions: 8,
+            count,
+            memory_size: count * 32,
+            quotas: vec![QuotaUsage {
+                pattern: "*".into(),
+                max_points: 10,
+                points: count,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let merged = merge(vec![response("a", 1), response("b", 2), response("a", 3)]);
+        assert_eq!(merged.len(), 2);
+        assert_eq!((merged[0].count, merged[0].memory_size), (4, 128));
+        assert_eq!(merged[0].quotas.len(), 1);
+        assert_eq!(
+            (merged[0].quotas[0].max_points, merged[0].quotas[0].points),
+            (20, 4)
+        );
+        assert_eq!(merged[1].count, 2);
+    }
+
+    #[test]
+    fn test_skip_missing() {
+        let not_found = || Status::new(Code::NotFound, "nope");
+        let found = skip_missing(vec![Ok(1), Err(not_found())]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].as_ref().unwrap(), &1);
+
+        let missing = skip_missing::<usize>(vec![Err(not_found()), Err(not_found())]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].as_ref().unwrap_err().code(), Code::NotFound);
+    }
+}
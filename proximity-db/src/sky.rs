@@ -1,8 +1,15 @@
 use crate::constellation_builder::ConstellationBuilder;
+use crate::quota::{Quota, QuotaUsage};
+use crate::shards;
 use crate::SupportedSize;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
-use proximity::{Constellation, QueryIterator};
+use proximity::{unix_time, Constellation, QueryIterator};
+use proximity_grpc::command::Command;
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use thiserror::Error;
 use tonic::{Code, Status};
@@ -21,6 +28,22 @@ pub enum SkyError {
     },
     #[error("A constellation with the name {0} does not exist.")]
     NotFound(String),
+    #[error("A constellation with the name {0} already exists.")]
+    AlreadyExists(String),
+    #[error("Adding to {name:?} would exceed the quota of {limit} for {pattern:?}")]
+    QuotaExceeded {
+        name: String,
+        pattern: String,
+        limit: String,
+    },
+    #[error(
+        "Adding {adding} bytes would go over the memory limit of {limit} bytes, {used} are in use"
+    )]
+    MemoryLimitExceeded {
+        adding: usize,
+        used: usize,
+        limit: usize,
+    },
 }
 
 impl From<SkyError> for Status {
@@ -30,6 +53,9 @@ impl From<SkyError> for Status {
             SkyError::InvalidSize(..) => Status::new(Code::InvalidArgument, msg),
             SkyError::NotFound(..) => Status::new(Code::NotFound, msg),
             SkyError::IncorrectSize { .. } => Status::new(Code::InvalidArgument, msg),
+            SkyError::AlreadyExists(..) => Status::new(Code::AlreadyExists, msg),
+            SkyError::QuotaExceeded { .. } => Status::new(Code::ResourceExhausted, msg),
+            SkyError::MemoryLimitExceeded { .. } => Status::new(Code::ResourceExhausted, msg),
         }
     }
 }
@@ -38,21 +64,241 @@ impl From<SkyError> for Status {
 // <S: Into<String>>
 #[derive(Default)]
 pub struct Sky {
-    constellations: DashMap<String, Box<dyn Constellation>>,
+    constellations: DashMap<String, Arc<dyn Constellation>>,
+    /// The default TTL of points added to each constellation, if they expire.
+    ttls: DashMap<String, Duration>,
+    quotas: RwLock<Vec<Quota>>,
+    memory_limit: Option<usize>,
 }
 
 impl<'a> Sky {
+    /// Limits how much the constellations can hold.
+    pub fn with_quotas(self, quotas: Vec<Quota>) -> Self {
+        self.set_quotas(quotas);
+        self
+    }
+
+    /// Replaces the quotas. Points already added are kept, even if they're over the new quotas.
+    pub fn set_quotas(&self, quotas: Vec<Quota>) {
+        *self.quotas.write().unwrap() = quotas;
+    }
+
+    /// Rejects adds that would take the memory used by every constellation past `limit` bytes.
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            memory_limit: self.memory_limit,
+            ..Default::default()
+        };
+        for kv in self.constellations.iter() {
+            stats.constellations += 1;
+            stats.count += kv.value().count();
+            stats.memory_size += kv.value().memory_size();
+        }
+        stats
+    }
+
+    fn check_memory(&self, bytes: usize) -> Result<(), SkyError> {
+        if let Some(limit) = self.memory_limit {
+            let used = self.stats().memory_size;
+            if used + bytes > limit {
+                return Err(SkyError::MemoryLimitExceeded {
+                    adding: bytes,
+                    used,
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// How much of each quota that applies to a constellation is used.
+    pub fn quotas_for(&self, name: &str) -> Vec<QuotaUsage> {
+        self.quotas
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|quota| quota.applies_to(name))
+            .map(|quota| self.usage(quota))
+            .collect()
+    }
+
+    fn usage(&self, quota: &Quota) -> QuotaUsage {
+        let mut usage = QuotaUsage {
+            quota: quota.clone(),
+            points: 0,
+            bytes: 0,
+        };
+        for kv in self.constellations.iter() {
+            if quota.applies_to(kv.key()) {
+                usage.points += kv.value().count();
+                usage.bytes += kv.value().memory_size();
+            }
+        }
+        usage
+    }
+
+    /// Checks that adding points to a constellation won't take it over any of its quotas.
+    ///
+    /// Concurrent adds are checked independently, so together they can go slightly over.
+    fn check_quotas(&self, name: &str, points: usize, bytes: usize) -> Result<(), SkyError> {
+        for usage in self.quotas_for(name) {
+            if let Some(limit) = usage.exceeded_by(points, bytes) {
+                return Err(SkyError::QuotaExceeded {
+                    name: name.into(),
+                    pattern: usage.quota.pattern.to_string(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an empty constellation. Adding points creates constellations implicitly, but this
+    /// lets the number of dimensions be fixed up front, along with a default TTL for its points.
+    #[tracing::instrument(level = "debug", skip(self, name), fields(constellation = %name))]
+    pub fn create(
+        &self,
+        name: String,
+        dimensions: usize,
+        ttl: Option<Duration>,
+    ) -> Result<(), SkyError> {
+        let supported_size = SupportedSize::try_from_primitive(dimensions)?;
+        match self.constellations.entry(name.clone()) {
+            Entry::Occupied(_) => Err(SkyError::AlreadyExists(name)),
+            Entry::Vacant(entry) => {
+                if let Some(ttl) = ttl {
+                    self.ttls.insert(name, ttl);
+                }
+                entry.insert(ConstellationBuilder::from(supported_size).build().into());
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes a constellation and all of its points.
+    #[tracing::instrument(level = "debug", skip_all, fields(constellation = %name))]
+    pub fn remove(&self, name: &String) -> Result<(), SkyError> {
+        self.ttls.remove(name);
+        self.constellations
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| SkyError::NotFound(name.clone()))
+    }
+
+    /// Checks a write against the quotas and memory limit, and works out when any points it adds
+    /// expire. A write must be prepared once, before it's applied.
+    ///
+    /// These depend on the state of this sky and on the clock, so for replicated writes only the
+    /// leader prepares them.
+    pub fn prepare(&self, command: Command) -> Result<Command, SkyError> {
+        match command {
+            Command::Add(mut request) => {
+                let dimensions = request.points.first().map_or(0, |p| p.coords.len());
+                let bytes = request.points.len() * dimensions * std::mem::size_of::<f32>();
+                self.check_quotas(&request.name, request.points.len(), bytes)?;
+                self.check_memory(bytes)?;
+                request.expires_at = ttl(request.ttl_seconds)
+                    .or_else(|| self.ttl(&request.name))
+                    .map_or(0, expires_at);
+                Ok(Command::Add(request))
+            }
+            command => Ok(command),
+        }
+    }
+
+    /// Removes every constellation. Searches running at the same time may see some of them gone
+    /// and not others.
+    pub fn clear(&self) {
+        self.constellations.clear();
+        self.ttls.clear();
+    }
+
+    /// Applies a prepared write, returning the number of points that were added or deleted.
+    ///
+    /// This is how replicated writes reach the sky on every node, so it must give the same result
+    /// wherever it's applied.
+    pub fn apply(&self, command: Command) -> Result<usize, SkyError> {
+        match command {
+            Command::Add(request) => self.insert(
+                request.name,
+                request.points.into_iter().map(|p| p.coords).collect(),
+                match request.expires_at {
+                    0 => None,
+                    expires_at => Some(expires_at),
+                },
+            ),
+            Command::Delete(request) => self.delete(
+                request.name,
+                request.points.into_iter().map(|p| p.coords).collect(),
+            ),
+            Command::Create(request) => self
+                .create(
+                    request.name,
+                    request.dimensions as usize,
+                    ttl(request.ttl_seconds),
+                )
+                .map(|_| 0),
+            Command::Drop(request) => self.remove(&request.name).map(|_| 0),
+        }
+    }
+
     pub fn add(&self, name: String, values: Vec<Vec<f32>>) -> Result<usize, SkyError> {
-        if !values.len() == 0 {
+        self.add_with_ttl(name, values, None)
+    }
+
+    /// Adds points that expire after `ttl`, or the constellation's default TTL if that's `None`.
+    #[tracing::instrument(
+        level = "debug",
+        skip(self, name, values),
+        fields(
+            constellation = %name,
+            dimensions = values.first().map_or(0, Vec::len),
+            points = values.len(),
+        )
+    )]
+    pub fn add_with_ttl(
+        &self,
+        name: String,
+        values: Vec<Vec<f32>>,
+        ttl: Option<Duration>,
+    ) -> Result<usize, SkyError> {
+        if values.is_empty() {
             return Ok(0);
         }
 
-        let supported_size = SupportedSize::try_from_primitive(values.first().unwrap().len())?;
+        let dimensions = values.first().unwrap().len();
+        SupportedSize::try_from_primitive(dimensions)?;
+        let bytes = values.len() * dimensions * std::mem::size_of::<f32>();
+        self.check_quotas(&name, values.len(), bytes)?;
+        self.check_memory(bytes)?;
+        let expires_at = ttl.or_else(|| self.ttl(&name)).map(expires_at);
+        self.insert(name, values, expires_at)
+    }
 
+    /// Adds points without checking any limits, expiring them at `expires_at` if it's given.
+    fn insert(
+        &self,
+        name: String,
+        values: Vec<Vec<f32>>,
+        expires_at: Option<u32>,
+    ) -> Result<usize, SkyError> {
+        if values.is_empty() {
+            return Ok(0);
+        }
+        let supported_size = SupportedSize::try_from_primitive(values[0].len())?;
+
+        // Clone the constellation out of the map, so the shard isn't locked while points are added.
         let constellation_rw = self
             .constellations
             .entry(name.clone())
-            .or_insert_with(|| ConstellationBuilder::from(supported_size).build());
+            .or_insert_with(|| ConstellationBuilder::from(supported_size).build().into())
+            .value()
+            .clone();
 
         let expected = constellation_rw.dimensions();
         for value in &values {
@@ -65,10 +311,18 @@ impl<'a> Sky {
             }
         }
         let total_points = values.len();
-        constellation_rw.add_points(values);
+        match expires_at {
+            Some(expires_at) => constellation_rw.add_expiring_points(values, expires_at),
+            None => constellation_rw.add_points(values),
+        }
         Ok(total_points)
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip(self, name, values),
+        fields(constellation = %name, dimensions = values.len())
+    )]
     pub fn query(
         &self,
         name: String,
@@ -78,7 +332,9 @@ impl<'a> Sky {
         let constellation = self
             .constellations
             .get(&name)
-            .ok_or_else(|| SkyError::NotFound(name.clone()))?;
+            .ok_or_else(|| SkyError::NotFound(name.clone()))?
+            .value()
+            .clone();
 
         if constellation.dimensions() != values.len() {
             return Err(SkyError::IncorrectSize {
@@ -91,44 +347,227 @@ impl<'a> Sky {
         Ok(constellation.find(values, within_distance))
     }
 
+    /// Like `query`, but with a `limit` only the `limit` nearest points are found, nearest first.
+    /// A limit of 0 finds them all, in no particular order.
+    pub fn search(
+        &self,
+        name: String,
+        within_distance: f32,
+        values: Vec<f32>,
+        limit: usize,
+    ) -> Result<QueryIterator, SkyError> {
+        let neighbours = self.query(name, within_distance, values)?;
+        Ok(match limit {
+            0 => neighbours,
+            limit => Box::new(shards::nearest(neighbours, limit, |n| n.distance).into_iter()),
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.constellations.contains_key(name)
+    }
+
+    /// How many points a search of a constellation compares against, including deleted ones that
+    /// haven't been compacted away yet.
+    pub fn stored(&self, name: &str) -> Option<usize> {
+        let constellation = self.constellations.get(name)?.value().clone();
+        Some(constellation.count() + constellation.deleted_count())
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(constellation = %name, points = values.len())
+    )]
+    pub fn delete(&self, name: String, values: Vec<Vec<f32>>) -> Result<usize, SkyError> {
+        let constellation = self
+            .constellations
+            .get(&name)
+            .ok_or_else(|| SkyError::NotFound(name.clone()))?
+            .value()
+            .clone();
+
+        let expected = constellation.dimensions();
+        for value in &values {
+            if value.len() != expected {
+                return Err(SkyError::IncorrectSize {
+                    name,
+                    expected,
+                    given: value.len(),
+                });
+            }
+        }
+        Ok(constellation.delete_points(values))
+    }
+
+    /// Physically removes deleted points from a constellation, returning how many were removed.
+    #[tracing::instrument(level = "debug", skip_all, fields(constellation = %name))]
+    pub fn compact(&self, name: &String) -> Result<usize, SkyError> {
+        let constellation = self
+            .constellations
+            .get(name)
+            .ok_or_else(|| SkyError::NotFound(name.clone()))?
+            .value()
+            .clone();
+
+        Ok(constellation.compact())
+    }
+
+    /// Compacts every constellation where at least `min_dead_ratio` of the points are deleted.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn compact_all(&self, min_dead_ratio: f32) -> usize {
+        let constellations: Vec<Arc<dyn Constellation>> = self
+            .constellations
+            .iter()
+            .map(|kv| kv.value().clone())
+            .collect();
+
+        constellations
+            .into_iter()
+            .filter(|c| dead_ratio(c.as_ref()) >= min_dead_ratio)
+            .map(|c| c.compact())
+            .sum()
+    }
+
+    /// Deletes expired points from every constellation and compacts them away, returning how many
+    /// were removed.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn expire_all(&self) -> usize {
+        let constellations: Vec<Arc<dyn Constellation>> = self
+            .constellations
+            .iter()
+            .map(|kv| kv.value().clone())
+            .collect();
+
+        let now = unix_time();
+        constellations
+            .into_iter()
+            .filter(|c| c.expiring_count() > 0)
+            .map(|c| {
+                let expired = c.expire(now);
+                if expired > 0 {
+                    c.compact();
+                }
+                expired
+            })
+            .sum()
+    }
+
+    /// The default TTL of points added to a constellation.
+    pub(crate) fn ttl(&self, name: &str) -> Option<Duration> {
+        self.ttls.get(name).map(|ttl| *ttl.value())
+    }
+
+    pub(crate) fn constellation(&self, name: &str) -> Result<Arc<dyn Constellation>, SkyError> {
+        Ok(self
+            .constellations
+            .get(name)
+            .ok_or_else(|| SkyError::NotFound(name.into()))?
+            .value()
+            .clone())
+    }
+
+    /// Every constellation, sorted by name.
+    pub(crate) fn constellations(&self) -> Vec<(String, Arc<dyn Constellation>)> {
+        let mut constellations: Vec<_> = self
+            .constellations
+            .iter()
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect();
+        constellations.sort_by(|a, b| a.0.cmp(&b.0));
+        constellations
+    }
+
     pub fn list(&self, prefix: &String) -> Vec<Metrics> {
-        self.constellations
+        let mut metrics: Vec<Metrics> = self
+            .constellations
             .iter()
             .filter_map(|kv| {
                 if kv.key().starts_with(prefix) {
                     let value = kv.value();
-                    Some(Metrics::from_constellation(kv.key().clone(), value))
+                    Some(Metrics::from_constellation(
+                        kv.key().clone(),
+                        value.as_ref(),
+                    ))
                 } else {
                     None
                 }
             })
-            .collect()
+            .collect();
+        for metrics in metrics.iter_mut() {
+            metrics.quotas = self.quotas_for(&metrics.name);
+            metrics.ttl = self.ttl(&metrics.name);
+        }
+        metrics
     }
 
     pub fn describe(&self, name: &String) -> Result<Metrics, SkyError> {
         let constellation = self
             .constellations
             .get(name)
-            .ok_or_else(|| SkyError::NotFound(name.clone()))?;
+            .ok_or_else(|| SkyError::NotFound(name.clone()))?
+            .value()
+            .clone();
+
+        let mut metrics = Metrics::from_constellation(name.clone(), constellation.as_ref());
+        metrics.quotas = self.quotas_for(name);
+        metrics.ttl = self.ttl(name);
+        Ok(metrics)
+    }
+}
 
-        Ok(Metrics::from_constellation(name.clone(), &constellation))
+/// A TTL given in seconds, where zero means there isn't one.
+pub fn ttl(seconds: u64) -> Option<Duration> {
+    match seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
     }
 }
 
+fn expires_at(ttl: Duration) -> u32 {
+    unix_time().saturating_add(u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX))
+}
+
+fn dead_ratio(constellation: &dyn Constellation) -> f32 {
+    let dead = constellation.deleted_count();
+    dead as f32 / (constellation.count() + dead) as f32
+}
+
+/// Totals across every constellation.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub constellations: usize,
+    pub count: usize,
+    pub memory_size: usize,
+    pub memory_limit: Option<usize>,
+}
+
 pub struct Metrics {
     pub name: String,
+    /// The number of live points.
     pub count: usize,
+    /// The number of deleted points that haven't been compacted away yet.
+    pub dead_count: usize,
     pub dimensions: usize,
     pub memory_size: usize,
+    pub quotas: Vec<QuotaUsage>,
+    /// The default TTL of added points.
+    pub ttl: Option<Duration>,
+    /// The number of live points that will be removed once they expire.
+    pub pending_expiry: usize,
 }
 
 impl Metrics {
-    pub fn from_constellation(name: String, constellation: &Box<dyn Constellation>) -> Self {
+    pub fn from_constellation(name: String, constellation: &dyn Constellation) -> Self {
         Self {
             name,
             count: constellation.count(),
+            dead_count: constellation.deleted_count(),
             dimensions: constellation.dimensions(),
             memory_size: constellation.memory_size(),
+            quotas: vec![],
+            ttl: None,
+            pending_expiry: constellation.expiring_count(),
         }
     }
 }
@@ -146,6 +585,7 @@ mod tests {
             vec![vec![1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0]],
         )
         .unwrap();
+        assert_eq!(sky.add("hello".into(), vec![]).unwrap(), 0);
     }
 
     #[test]
@@ -155,7 +595,197 @@ mod tests {
         sky.add("hello".into(), vec![values.clone()]).unwrap();
         let receiver = sky.query("hello".into(), 0.0, values.clone()).unwrap();
 
-        let items: Vec<(f32, Vec<f32>)> = receiver.collect();
+        let items: Vec<(f32, Vec<f32>)> = receiver
+            .map(|n| (n.distance, n.coords().to_vec()))
+            .collect();
         assert_eq!(items, vec![(0.0, values)]);
     }
+
+    #[test]
+    fn test_search() {
+        let sky = Sky::default();
+        sky.add("hello".into(), (0..10).map(|i| vec![i as f32; 8]).collect())
+            .unwrap();
+        let nearest: Vec<f32> = sky
+            .search("hello".into(), 100., vec![4.; 8], 3)
+            .unwrap()
+            .map(|n| n.coords()[0])
+            .collect();
+        assert_eq!(nearest[0], 4.);
+        assert_eq!(nearest.len(), 3);
+        assert_eq!(
+            sky.search("hello".into(), 100., vec![4.; 8], 0)
+                .unwrap()
+                .count(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_delete_and_compact() {
+        let values = vec![1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0];
+        let sky = Sky::default();
+        sky.add("hello".into(), vec![values.clone(), values.clone()])
+            .unwrap();
+        sky.add("hello".into(), vec![vec![0.; 8]]).unwrap();
+
+        assert_eq!(sky.delete("hello".into(), vec![values.clone()]).unwrap(), 2);
+        let metrics = sky.describe(&"hello".into()).unwrap();
+        assert_eq!((metrics.count, metrics.dead_count), (1, 2));
+
+        // A third of the points are live, so a higher threshold leaves it alone.
+        assert_eq!(sky.compact_all(0.9), 0);
+        assert_eq!(sky.compact_all(0.5), 2);
+        let metrics = sky.describe(&"hello".into()).unwrap();
+        assert_eq!((metrics.count, metrics.dead_count), (1, 0));
+        assert_eq!(sky.query("hello".into(), 0.0, values).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_delete_not_found() {
+        let sky = Sky::default();
+        assert!(matches!(
+            sky.delete("nope".into(), vec![]),
+            Err(SkyError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_quotas() {
+        let sky = Sky::default().with_quotas(vec![
+            "logs-*=points:3".parse().unwrap(),
+            "logs-today=bytes:32".parse().unwrap(),
+        ]);
+        sky.add("logs-today".into(), vec![vec![0.; 8]]).unwrap();
+        assert!(matches!(
+            sky.add("logs-today".into(), vec![vec![1.; 8]]),
+            Err(SkyError::QuotaExceeded { limit, .. }) if limit == "32 bytes"
+        ));
+        sky.add("logs-old".into(), vec![vec![0.; 8], vec![1.; 8]])
+            .unwrap();
+        // The prefix quota covers both constellations together.
+        assert!(matches!(
+            sky.add("logs-old".into(), vec![vec![2.; 8]]),
+            Err(SkyError::QuotaExceeded { limit, .. }) if limit == "3 points"
+        ));
+        sky.add("other".into(), vec![vec![0.; 8]; 10]).unwrap();
+
+        let quotas = sky.describe(&"logs-today".into()).unwrap().quotas;
+        assert_eq!(quotas.len(), 2);
+        assert_eq!((quotas[0].points, quotas[0].bytes), (3, 96));
+        assert_eq!((quotas[1].points, quotas[1].bytes), (1, 32));
+
+        sky.set_quotas(vec![]);
+        sky.add("logs-old".into(), vec![vec![2.; 8]]).unwrap();
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let sky = Sky::default().with_memory_limit(100);
+        sky.add("hello".into(), vec![vec![0.; 8]; 3]).unwrap();
+        assert!(matches!(
+            sky.add("other".into(), vec![vec![0.; 8]]),
+            Err(SkyError::MemoryLimitExceeded { used: 96, .. })
+        ));
+        let stats = sky.stats();
+        assert_eq!((stats.constellations, stats.count), (1, 3));
+        assert_eq!(stats.memory_limit, Some(100));
+    }
+
+    #[test]
+    fn test_prepare_and_apply() {
+        use proximity_grpc::{AddRequest, Point as GrpcPoint};
+        let add = |count| {
+            Command::Add(AddRequest {
+                name: "hello".into(),
+                points: vec![
+                    GrpcPoint {
+                        coords: vec![0.; 8]
+                    };
+                    count
+                ],
+                ..Default::default()
+            })
+        };
+        let leader = Sky::default().with_memory_limit(100);
+        leader
+            .create("hello".into(), 8, Some(Duration::from_secs(3600)))
+            .unwrap();
+        assert!(matches!(
+            leader.prepare(add(4)),
+            Err(SkyError::MemoryLimitExceeded { .. })
+        ));
+        let prepared = leader.prepare(add(1)).unwrap();
+        match &prepared {
+            Command::Add(request) => assert!(request.expires_at >= unix_time() + 3599),
+            _ => unreachable!(),
+        }
+
+        // Other nodes apply the write as it was prepared, whatever their own limits and TTLs.
+        let follower = Sky::default().with_memory_limit(0);
+        assert_eq!(follower.apply(prepared).unwrap(), 1);
+        assert_eq!(
+            follower.describe(&"hello".into()).unwrap().pending_expiry,
+            1
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        let values = vec![1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0];
+        let sky = Sky::default();
+        sky.create("hello".into(), 8, Some(Duration::from_secs(3600)))
+            .unwrap();
+        sky.add("hello".into(), vec![values.clone()]).unwrap();
+        sky.add_with_ttl(
+            "hello".into(),
+            vec![vec![0.; 8]],
+            Some(Duration::from_secs(0)),
+        )
+        .unwrap();
+        sky.add("other".into(), vec![values.clone()]).unwrap();
+
+        let metrics = sky.describe(&"hello".into()).unwrap();
+        assert_eq!(metrics.ttl, Some(Duration::from_secs(3600)));
+        assert_eq!(metrics.pending_expiry, 2);
+        assert_eq!(sky.describe(&"other".into()).unwrap().pending_expiry, 0);
+
+        // A zero TTL has already expired, so it can't be found and is reaped straight away.
+        assert_eq!(
+            sky.query("hello".into(), 0.0, vec![0.; 8]).unwrap().count(),
+            0
+        );
+        assert_eq!(sky.expire_all(), 1);
+        let metrics = sky.describe(&"hello".into()).unwrap();
+        assert_eq!((metrics.count, metrics.dead_count), (1, 0));
+        assert_eq!(metrics.pending_expiry, 1);
+        assert_eq!(sky.query("hello".into(), 0.0, values).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let sky = Sky::default();
+        sky.create("hello".into(), 8, None).unwrap();
+        assert!(matches!(
+            sky.create("hello".into(), 8, None),
+            Err(SkyError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            sky.create("other".into(), 7, None),
+            Err(SkyError::InvalidSize(_))
+        ));
+        assert_eq!(sky.describe(&"hello".into()).unwrap().dimensions, 8);
+
+        sky.remove(&"hello".into()).unwrap();
+        assert!(matches!(
+            sky.remove(&"hello".into()),
+            Err(SkyError::NotFound(_))
+        ));
+
+        sky.create("other".into(), 8, Some(Duration::from_secs(60)))
+            .unwrap();
+        sky.clear();
+        assert!(sky.list(&"".into()).is_empty());
+        assert_eq!(sky.ttl("other"), None);
+    }
 }
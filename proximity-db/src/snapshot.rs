@@ -0,0 +1,381 @@
+//! Saving the sky to a data directory, so it survives restarts.
+//!
+//! The whole sky is written to a single snapshot file. Each new snapshot is written alongside the
+//! previous one and renamed over it once it's complete, so a crash part way through a save leaves
+//! the previous snapshot intact. A `Snapshotter` saves one periodically, so a crash only loses the
+//! writes made since the last one.
+//!
+//! The file starts with `MAGIC`, followed by each constellation: a 1 byte, the length of its name
+//! and the name, its dimensions and default TTL in seconds, then each of its points as a 1 byte,
+//! the time it expires and its coordinates, ending with a 0 byte. A final 0 byte ends the file.
+//! Numbers are little endian.
+use crate::sky::{self, Sky, SkyError};
+use proximity::{unix_time, Constellation};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"PRXSKY01";
+
+const SNAPSHOT_FILE: &str = "sky.snapshot";
+
+const LOCK_FILE: &str = "lock";
+
+/// Points are restored in batches of up to this many.
+const BATCH_SIZE: usize = 10_000;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Could not read or write the snapshot: {0}")]
+    Io(#[from] io::Error),
+    #[error("The snapshot is invalid: {0}")]
+    Invalid(&'static str),
+    #[error("Could not restore the snapshot: {0}")]
+    Sky(#[from] SkyError),
+    #[error("The data directory {0:?} is already in use")]
+    Locked(PathBuf),
+}
+
+pub struct DataDir {
+    path: PathBuf,
+    /// Held while saving, since every save writes to the same partial file.
+    saving: Mutex<()>,
+    /// Exclusively locked until this is dropped, so only one server or database uses the
+    /// directory at a time.
+    _lock: File,
+}
+
+impl DataDir {
+    /// Uses the directory at `path`, creating it if it doesn't exist. Fails if it's already open,
+    /// in this process or another one.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, SnapshotError> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = match lock(&path.join(LOCK_FILE))? {
+            Some(lock) => lock,
+            None => return Err(SnapshotError::Locked(path)),
+        };
+        Ok(DataDir {
+            path,
+            saving: Mutex::new(()),
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Restores the last snapshot into `sky`, returning how many points were restored. Points
+    /// that have expired since the snapshot was saved are skipped.
+    pub fn load(&self, sky: &Sky) -> Result<usize, SnapshotError> {
+        let file = match File::open(self.path.join(SNAPSHOT_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        read_sky(sky, &mut BufReader::new(file))
+    }
+
+    /// Saves a snapshot of `sky`, returning how many points it holds.
+    ///
+    /// Writes that happen during a save may or may not be included.
+    pub fn save(&self, sky: &Sky) -> Result<usize, SnapshotError> {
+        let _saving = self.saving.lock().unwrap();
+        let partial = self.path.join(format!("{}.partial", SNAPSHOT_FILE));
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        let points = write_sky(sky, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&partial, self.path.join(SNAPSHOT_FILE))?;
+        // Make sure the rename itself is on disk.
+        File::open(&self.path)?.sync_all()?;
+        Ok(points)
+    }
+}
+
+/// Opens the file at `path`, exclusively locked until it's closed, or `None` if it's already
+/// locked. The lock is released even if the process is killed.
+#[cfg(unix)]
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::io::AsRawFd;
+    let file = File::create(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let error = io::Error::last_os_error();
+    match error.kind() {
+        io::ErrorKind::WouldBlock => Ok(None),
+        _ => Err(error),
+    }
+}
+
+#[cfg(windows)]
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+    /// The error for opening a file that's open elsewhere without being shared.
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    // Not sharing the file stops anyone else opening it until it's closed.
+    let opened = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .share_mode(0)
+        .open(path);
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// There's no way to lock files elsewhere, so the directory can be opened more than once.
+#[cfg(not(any(unix, windows)))]
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    File::create(path).map(Some)
+}
+
+/// Periodically saves the sky to a data directory.
+pub struct Snapshotter {
+    sky: Arc<Sky>,
+    data_dir: Arc<DataDir>,
+    interval: Duration,
+}
+
+impl Snapshotter {
+    pub fn new(sky: Arc<Sky>, data_dir: Arc<DataDir>, interval: Duration) -> Self {
+        Snapshotter {
+            sky,
+            data_dir,
+            interval,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        // The first tick is immediate, when there's nothing new to save.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let sky = self.sky.clone();
+            let data_dir = self.data_dir.clone();
+            match tokio::task::spawn_blocking(move || data_dir.save(&sky)).await {
+                Ok(Ok(saved)) => tracing::debug!("Saved {} points", saved),
+                Ok(Err(e)) => tracing::error!("Could not save a snapshot: {}", e),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Writes every constellation in `sky`, returning how many points were written.
+pub(crate) fn write_sky(sky: &Sky, writer: &mut impl Write) -> io::Result<usize> {
+    writer.write_all(MAGIC)?;
+    let mut total = 0;
+    for (name, constellation) in sky.constellations() {
+        writer.write_all(&[1])?;
+        writer.write_all(&(name.len() as u32).to_le_bytes())?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(&(constellation.dimensions() as u32).to_le_bytes())?;
+        let ttl = sky.ttl(&name).map_or(0, |ttl| ttl.as_secs());
+        writer.write_all(&ttl.to_le_bytes())?;
+
+        let mut result = Ok(());
+        constellation.for_each_point(&mut |coords, expires_at| {
+            if result.is_ok() {
+                result = write_point(writer, coords, expires_at);
+                total += 1;
+            }
+        });
+        result?;
+        writer.write_all(&[0])?;
+    }
+    writer.write_all(&[0])?;
+    Ok(total)
+}
+
+fn write_point(writer: &mut impl Write, coords: &[f32], expires_at: u32) -> io::Result<()> {
+    writer.write_all(&[1])?;
+    writer.write_all(&expires_at.to_le_bytes())?;
+    for coord in coords {
+        writer.write_all(&coord.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Adds the constellations written by `write_sky` to `sky`, returning how many points were added.
+pub(crate) fn read_sky(sky: &Sky, reader: &mut impl Read) -> Result<usize, SnapshotError> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::Invalid("This isn't a snapshot file"));
+    }
+    let now = unix_time();
+    let mut total = 0;
+    while read_more(reader)? {
+        let mut name = vec![0; read_u32(reader)? as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| SnapshotError::Invalid("A constellation name isn't UTF-8"))?;
+        let dimensions = read_u32(reader)? as usize;
+        let mut ttl = [0; 8];
+        reader.read_exact(&mut ttl)?;
+        sky.create(name.clone(), dimensions, sky::ttl(u64::from_le_bytes(ttl)))?;
+        let constellation = sky.constellation(&name)?;
+
+        // Points are saved in the order they were added, so runs of them expire together.
+        let mut batch = vec![];
+        let mut batch_expires_at = 0;
+        let mut coords = vec![0; dimensions * 4];
+        while read_more(reader)? {
+            let expires_at = read_u32(reader)?;
+            reader.read_exact(&mut coords)?;
+            if expires_at != 0 && expires_at <= now {
+                continue;
+            }
+            if expires_at != batch_expires_at || batch.len() == BATCH_SIZE {
+                restore(
+                    constellation.as_ref(),
+                    std::mem::take(&mut batch),
+                    batch_expires_at,
+                );
+                batch_expires_at = expires_at;
+            }
+            batch.push(
+                coords
+                    .chunks_exact(4)
+                    .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                    .collect(),
+            );
+            total += 1;
+        }
+        restore(constellation.as_ref(), batch, batch_expires_at);
+    }
+    Ok(total)
+}
+
+fn restore(constellation: &dyn Constellation, points: Vec<Vec<f32>>, expires_at: u32) {
+    if points.is_empty() {
+        return;
+    }
+    match expires_at {
+        0 => constellation.add_points(points),
+        expires_at => constellation.add_expiring_points(points, expires_at),
+    }
+}
+
+/// Reads the byte that says whether another constellation or point follows.
+fn read_more(reader: &mut impl Read) -> Result<bool, SnapshotError> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    match byte[0] {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(SnapshotError::Invalid("Unexpected marker")),
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn points(sky: &Sky, name: &str) -> Vec<(Vec<f32>, u32)> {
+        let mut points = vec![];
+        sky.constellation(name)
+            .unwrap()
+            .for_each_point(&mut |coords, expires_at| points.push((coords.to_vec(), expires_at)));
+        points
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sky = Sky::default();
+        sky.add("hello".into(), vec![vec![1.; 8], vec![2.; 8]])
+            .unwrap();
+        sky.delete("hello".into(), vec![vec![2.; 8]]).unwrap();
+        sky.create("expiring".into(), 8, Some(Duration::from_secs(60)))
+            .unwrap();
+        sky.add("expiring".into(), vec![vec![3.; 8]]).unwrap();
+        sky.create("empty".into(), 64, None).unwrap();
+
+        let mut saved = vec![];
+        assert_eq!(write_sky(&sky, &mut saved).unwrap(), 2);
+
+        let restored = Sky::default();
+        assert_eq!(read_sky(&restored, &mut &saved[..]).unwrap(), 2);
+        assert_eq!(
+            restored.list(&String::new()).len(),
+            sky.list(&String::new()).len()
+        );
+        assert_eq!(points(&restored, "hello"), vec![(vec![1.; 8], 0)]);
+        assert_eq!(points(&restored, "expiring"), points(&sky, "expiring"));
+        assert_eq!(restored.ttl("expiring"), Some(Duration::from_secs(60)));
+        assert_eq!(restored.describe(&"empty".into()).unwrap().dimensions, 64);
+    }
+
+    #[test]
+    fn test_skips_expired_points() {
+        let sky = Sky::default();
+        sky.add_with_ttl(
+            "hello".into(),
+            vec![vec![1.; 8]],
+            Some(Duration::from_secs(1)),
+        )
+        .unwrap();
+        let mut saved = vec![];
+        write_sky(&sky, &mut saved).unwrap();
+        // Pretend the point expired while the server was down. Its expiry is followed by its 8
+        // coordinates and the two end markers.
+        let expiry = saved.len() - 2 - 8 * 4 - 4;
+        saved[expiry..expiry + 4].copy_from_slice(&1u32.to_le_bytes());
+
+        let restored = Sky::default();
+        assert_eq!(read_sky(&restored, &mut &saved[..]).unwrap(), 0);
+        assert_eq!(restored.describe(&"hello".into()).unwrap().count, 0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = read_sky(&Sky::default(), &mut &b"not a snapshot"[..]).unwrap_err();
+        assert!(matches!(error, SnapshotError::Invalid(_)));
+        let mut saved = vec![];
+        write_sky(&Sky::default(), &mut saved).unwrap();
+        saved.pop();
+        let error = read_sky(&Sky::default(), &mut &saved[..]).unwrap_err();
+        assert!(matches!(error, SnapshotError::Io(_)));
+    }
+
+    #[test]
+    fn test_data_dir() {
+        let path = std::env::temp_dir().join(format!("proximity-data-{}", std::process::id()));
+        let data_dir = DataDir::open(&path).unwrap();
+        assert_eq!(data_dir.load(&Sky::default()).unwrap(), 0);
+
+        let sky = Sky::default();
+        sky.add("hello".into(), vec![vec![1.; 8]]).unwrap();
+        assert_eq!(data_dir.save(&sky).unwrap(), 1);
+        sky.add("hello".into(), vec![vec![2.; 8]]).unwrap();
+        assert_eq!(data_dir.save(&sky).unwrap(), 2);
+
+        // The directory stays locked until it's closed.
+        assert!(matches!(
+            DataDir::open(&path),
+            Err(SnapshotError::Locked(_))
+        ));
+        drop(data_dir);
+        let restored = Sky::default();
+        assert_eq!(DataDir::open(&path).unwrap().load(&restored).unwrap(), 2);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
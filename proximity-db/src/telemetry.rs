@@ -0,0 +1,217 @@
+//! Logging and tracing.
+//!
+//! Requests are traced with spans down to the scan of each constellation, which are logged when
+//! they close along with how long they took. They can also be exported with OpenTelemetry to a
+//! Jaeger agent, to follow a request across shards.
+//!
+//! Searches slower than a threshold are logged separately, as JSON lines, to the main log or their
+//! own rotating files.
+use opentelemetry::trace::TraceError;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+use thiserror::Error;
+use tracing::Level;
+use tracing_appender::rolling::{InitError, RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{EnvFilter, ParseError, Targets};
+use tracing_subscriber::fmt::{self, format::FmtSpan};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+use tracing_subscriber::{reload, Registry};
+
+/// The target that slow searches are logged under.
+pub const SLOW_QUERY_TARGET: &str = "proximity_db::slow_query";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("The log format must be text or json, not {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct LogOptions {
+    #[structopt(long, default_value = "info", env = "PROXIMITY_LOG_LEVEL")]
+    /// What to log, as a level or comma separated directives, e.g. proximity=debug,info
+    pub log_level: String,
+    #[structopt(long, default_value = "text", env = "PROXIMITY_LOG_FORMAT")]
+    /// Log as text, or as one JSON object per line
+    pub log_format: LogFormat,
+    #[structopt(long, env = "PROXIMITY_JAEGER_AGENT")]
+    /// Export spans with OpenTelemetry to the Jaeger agent at this address, e.g. 127.0.0.1:6831
+    pub jaeger_agent: Option<String>,
+    #[structopt(long, default_value = "proximity-db", env = "PROXIMITY_SERVICE_NAME")]
+    /// The service name to export spans under
+    pub service_name: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SlowQueryOptions {
+    #[structopt(long, env = "PROXIMITY_SLOW_QUERY_THRESHOLD")]
+    /// Log searches that take at least this many milliseconds, along with who made them
+    pub slow_query_threshold: Option<u64>,
+    #[structopt(long, env = "PROXIMITY_SLOW_QUERY_LOG")]
+    /// Log slow searches to files starting with this path, rather than the main log, e.g.
+    /// /var/log/proximity/slow.log
+    pub slow_query_log: Option<PathBuf>,
+    #[structopt(
+        long,
+        default_value = "daily",
+        parse(try_from_str = rotation),
+        env = "PROXIMITY_SLOW_QUERY_ROTATION"
+    )]
+    /// How often to start a new slow query log file: minutely, hourly, daily or never
+    pub slow_query_rotation: Rotation,
+}
+
+impl SlowQueryOptions {
+    pub fn threshold(&self) -> Option<Duration> {
+        self.slow_query_threshold.map(Duration::from_millis)
+    }
+
+    fn appender(&self, path: &Path) -> Result<RollingFileAppender, InitError> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut builder = RollingFileAppender::builder().rotation(self.slow_query_rotation.clone());
+        if let Some(prefix) = path.file_name() {
+            builder = builder.filename_prefix(prefix.to_string_lossy());
+        }
+        builder.build(directory)
+    }
+}
+
+fn rotation(s: &str) -> anyhow::Result<Rotation> {
+    match s {
+        "minutely" => Ok(Rotation::MINUTELY),
+        "hourly" => Ok(Rotation::HOURLY),
+        "daily" => Ok(Rotation::DAILY),
+        "never" => Ok(Rotation::NEVER),
+        _ => anyhow::bail!(
+            "The rotation must be minutely, hourly, daily or never, not {:?}",
+            s
+        ),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("Invalid log level: {0}")]
+    InvalidLevel(#[from] ParseError),
+    #[error("Could not export spans: {0}")]
+    Export(#[from] TraceError),
+    #[error("Logging has already been set up: {0}")]
+    AlreadyInitialized(#[from] TryInitError),
+    #[error("Could not open the slow query log: {0}")]
+    SlowQueryLog(#[from] InitError),
+    #[error("Could not change the log level: {0}")]
+    Reload(#[from] reload::Error),
+}
+
+/// Changes what's logged while the server is running.
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    separate_slow_queries: bool,
+}
+
+impl LogHandle {
+    pub fn set_level(&self, level: &str) -> Result<(), TelemetryError> {
+        Ok(self
+            .filter
+            .reload(filter(level, self.separate_slow_queries)?)?)
+    }
+}
+
+fn filter(level: &str, separate_slow_queries: bool) -> Result<EnvFilter, ParseError> {
+    let filter = EnvFilter::try_new(level)?;
+    if !separate_slow_queries {
+        return Ok(filter);
+    }
+    // Slow queries go to their own log instead.
+    Ok(filter.add_directive(format!("{}=off", SLOW_QUERY_TARGET).parse()?))
+}
+
+impl LogOptions {
+    /// Starts logging for the whole process. Call `shutdown` before exiting, so the last spans
+    /// are exported.
+    pub fn init(
+        &self,
+        slow_queries: Option<&SlowQueryOptions>,
+    ) -> Result<LogHandle, TelemetryError> {
+        let log = match self.log_format {
+            LogFormat::Text => fmt::layer().with_span_events(FmtSpan::CLOSE).boxed(),
+            LogFormat::Json => fmt::layer().json().with_span_events(FmtSpan::CLOSE).boxed(),
+        };
+        let export = match &self.jaeger_agent {
+            Some(agent) => {
+                let tracer = opentelemetry_jaeger::new_pipeline()
+                    .with_service_name(&self.service_name)
+                    .with_agent_endpoint(agent)
+                    .install_simple()?;
+                Some(tracing_opentelemetry::layer().with_tracer(tracer))
+            }
+            None => None,
+        };
+        let slow_log = match slow_queries {
+            Some(options) => match &options.slow_query_log {
+                Some(path) => Some(options.appender(path)?),
+                None => None,
+            },
+            None => None,
+        };
+        let separate_slow_queries = slow_log.is_some();
+        let (filter, handle) = reload::Layer::new(filter(&self.log_level, separate_slow_queries)?);
+        tracing_subscriber::registry()
+            .with(log.and_then(export).with_filter(filter))
+            .with(slow_log.map(|appender| {
+                fmt::layer()
+                    .json()
+                    .with_current_span(false)
+                    .with_span_list(false)
+                    .with_writer(appender)
+                    .with_filter(Targets::new().with_target(SLOW_QUERY_TARGET, Level::WARN))
+            }))
+            .try_init()?;
+        Ok(LogHandle {
+            filter: handle,
+            separate_slow_queries,
+        })
+    }
+}
+
+/// Exports any spans that haven't been yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        assert_eq!(rotation("hourly").unwrap(), Rotation::HOURLY);
+        assert_eq!(rotation("never").unwrap(), Rotation::NEVER);
+        assert!(rotation("yearly").is_err());
+    }
+}
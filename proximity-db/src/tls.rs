@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+/// Options for serving gRPC over TLS, and for connecting to other servers over it, shared by every
+/// binary that runs a server.
+#[derive(Debug, Default, StructOpt)]
+pub struct TlsOptions {
+    #[structopt(long, env = "PROXIMITY_TLS_CERT", requires = "tls-key")]
+    /// A PEM encoded certificate chain to serve TLS with. Without one, connections are plaintext
+    pub tls_cert: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_KEY", requires = "tls-cert")]
+    /// The PEM encoded private key for the certificate
+    pub tls_key: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_CLIENT_CA", requires = "tls-cert")]
+    /// Only accept clients with a certificate signed by this PEM encoded CA
+    pub tls_client_ca: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_CA")]
+    /// Connect to other servers, such as shards and peers, over TLS and only trust certificates
+    /// signed by this PEM encoded CA. Their addresses must start with https://
+    pub tls_ca: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_CLIENT_CERT", requires = "tls-client-key")]
+    /// A PEM encoded certificate chain to present to other servers, when they require one
+    pub tls_client_cert: Option<PathBuf>,
+    #[structopt(long, env = "PROXIMITY_TLS_CLIENT_KEY", requires = "tls-client-cert")]
+    /// The PEM encoded private key for the client certificate
+    pub tls_client_key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// Loads the certificates, or returns `None` if TLS isn't enabled.
+    pub fn server_config(&self) -> std::io::Result<Option<ServerTlsConfig>> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (std::fs::read(cert)?, std::fs::read(key)?),
+            _ => return Ok(None),
+        };
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(client_ca) = &self.tls_client_ca {
+            config = config.client_ca_root(Certificate::from_pem(std::fs::read(client_ca)?));
+        }
+        Ok(Some(config))
+    }
+
+    /// Loads the certificates for connecting to other servers, or returns `None` if none are
+    /// given.
+    pub fn client_config(&self) -> std::io::Result<Option<ClientTlsConfig>> {
+        if self.tls_ca.is_none() && self.tls_client_cert.is_none() {
+            return Ok(None);
+        }
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.tls_ca {
+            config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_client_cert, &self.tls_client_key) {
+            config = config.identity(Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ));
+        }
+        Ok(Some(config))
+    }
+}
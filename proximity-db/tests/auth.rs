@@ -0,0 +1,185 @@
+mod common;
+
+use proximity_db::acl::Acl;
+use proximity_db::auth::{self, Keys};
+use proximity_db::cluster::{Cluster, GrpcTransport, RaftService};
+use proximity_db::handler::ProximityDBHandler;
+use proximity_db::sky::Sky;
+use proximity_grpc::command::Command as SkyCommand;
+use proximity_grpc::proximity_db_client::ProximityDbClient;
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use proximity_grpc::raft_client::RaftClient;
+use proximity_grpc::raft_server::RaftServer;
+use proximity_grpc::{
+    AddRequest, Command, CreateRequest, DropRequest, ListRequest, Point as GrpcPoint,
+    ProposeRequest,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Interceptor, Request};
+
+async fn start_server(keys: Arc<Keys>, handler: ProximityDBHandler) -> String {
+    let (addr, incoming) = common::listen().await;
+    tokio::spawn(
+        Server::builder()
+            .add_service(ProximityDbServer::with_interceptor(
+                handler,
+                keys.interceptor(None),
+            ))
+            .serve_with_incoming(incoming),
+    );
+    format!("http://{}", addr)
+}
+
+fn keys(name: &str, contents: &str) -> (PathBuf, Arc<Keys>) {
+    let path = std::env::temp_dir().join(format!("proximity-{}-{}", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    let keys = Arc::new(Keys::load(path.clone()).unwrap());
+    (path, keys)
+}
+
+async fn connect(address: &str, token: Option<&str>) -> ProximityDbClient<Channel> {
+    let channel = Channel::from_shared(address.to_string())
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let authorization = token.map(|token| auth::bearer(token).unwrap());
+    ProximityDbClient::with_interceptor(
+        channel,
+        Interceptor::new(move |mut request: Request<()>| {
+            if let Some(authorization) = &authorization {
+                request
+                    .metadata_mut()
+                    .insert("authorization", authorization.clone());
+            }
+            Ok(request)
+        }),
+    )
+}
+
+async fn list(address: &str, token: Option<&str>) -> Result<Vec<String>, tonic::Status> {
+    let mut client = connect(address, token).await;
+    let mut stream = client.list(ListRequest::default()).await?.into_inner();
+    let mut names = vec![];
+    while let Some(response) = stream.message().await? {
+        names.push(response.name);
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[tokio::test]
+async fn test_bearer_tokens() {
+    let (path, keys) = keys("auth", "alice abc\n");
+    let address = start_server(keys.clone(), ProximityDBHandler::new(Sky::default())).await;
+
+    list(&address, Some("abc")).await.unwrap();
+    let error = list(&address, None).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unauthenticated);
+    let error = list(&address, Some("def")).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unauthenticated);
+
+    // Revoked keys stop working once the file is reloaded.
+    std::fs::write(&path, "bob def\n").unwrap();
+    keys.reload().unwrap();
+    list(&address, Some("def")).await.unwrap();
+    let error = list(&address, Some("abc")).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unauthenticated);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_access_control() {
+    let (path, keys) = keys("acl", "alice abc\nops def\n");
+    let acl: Acl = "alice write logs-*\nops admin *\n".parse().unwrap();
+    let handler = ProximityDBHandler::new(Sky::default()).with_acl(acl);
+    let address = start_server(keys, handler).await;
+
+    let mut ops = connect(&address, Some("def")).await;
+    let mut alice = connect(&address, Some("abc")).await;
+    for name in &["logs-today", "metrics"] {
+        let create = |dimensions| CreateRequest {
+            name: name.to_string(),
+            dimensions,
+            ..Default::default()
+        };
+        let error = alice.create(create(8)).await.unwrap_err();
+        assert_eq!(error.code(), Code::PermissionDenied);
+        ops.create(create(8)).await.unwrap();
+    }
+
+    let add = |name: &str| {
+        futures::stream::iter(vec![AddRequest {
+            name: name.into(),
+            points: vec![GrpcPoint {
+                coords: vec![1.; 8],
+            }],
+            ..Default::default()
+        }])
+    };
+    alice.add(add("logs-today")).await.unwrap();
+    let error = alice.add(add("metrics")).await.unwrap_err();
+    assert_eq!(error.code(), Code::PermissionDenied);
+
+    // Callers only see the constellations they can read.
+    assert_eq!(
+        list(&address, Some("abc")).await.unwrap(),
+        vec!["logs-today"]
+    );
+    assert_eq!(
+        list(&address, Some("def")).await.unwrap(),
+        vec!["logs-today", "metrics"]
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_raft_requires_authentication() {
+    let (path, keys) = keys("raft", "alice abc\nnode def\n");
+    // Node 2 doesn't exist, so this node never becomes the leader.
+    let transport = Arc::new(GrpcTransport::new(HashMap::new()));
+    let cluster = Cluster::start(1, &[2], Arc::new(Sky::default()), Arc::new(transport)).unwrap();
+    let acl: Acl = "alice admin logs-*\nnode admin *\n".parse().unwrap();
+    let (addr, incoming) = common::listen().await;
+    tokio::spawn(
+        Server::builder()
+            .add_service(RaftServer::with_interceptor(
+                RaftService::new(cluster).with_acl(acl),
+                keys.interceptor(None),
+            ))
+            .serve_with_incoming(incoming),
+    );
+
+    let propose = |token: Option<&str>| {
+        let mut request = Request::new(ProposeRequest {
+            command: Some(Command {
+                command: Some(SkyCommand::Drop(DropRequest {
+                    name: "logs-today".into(),
+                })),
+            }),
+        });
+        if let Some(token) = token {
+            request
+                .metadata_mut()
+                .insert("authorization", auth::bearer(token).unwrap());
+        }
+        request
+    };
+    let mut client = RaftClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let error = client.propose(propose(None)).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unauthenticated);
+    let error = client.propose(propose(Some("xyz"))).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unauthenticated);
+    // Peers can change any constellation, so they need access to all of them.
+    let error = client.propose(propose(Some("abc"))).await.unwrap_err();
+    assert_eq!(error.code(), Code::PermissionDenied);
+    // Other nodes get as far as finding out this one isn't the leader.
+    let error = client.propose(propose(Some("def"))).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unavailable);
+    std::fs::remove_file(path).unwrap();
+}
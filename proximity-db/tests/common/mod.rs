@@ -0,0 +1,19 @@
+//! Helpers shared by the integration tests.
+use futures::Stream;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Binds a free local port, returning its address and the connections to it. The listener is
+/// already bound, so serving these with `serve_with_incoming` accepts connections straight away.
+pub async fn listen() -> (SocketAddr, impl Stream<Item = io::Result<TcpStream>>) {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let incoming = futures::stream::unfold(listener, |mut listener| async move {
+        let stream = listener.accept().await.map(|(stream, _)| stream);
+        Some((stream, listener))
+    });
+    (addr, incoming)
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use prost::Message;
+use prost_types::FileDescriptorProto;
+use proximity_db::reflection::ReflectionService;
+use proximity_grpc::reflection::server_reflection_client::ServerReflectionClient;
+use proximity_grpc::reflection::server_reflection_request::MessageRequest;
+use proximity_grpc::reflection::server_reflection_response::MessageResponse;
+use proximity_grpc::reflection::ServerReflectionRequest;
+use proximity_grpc::FILE_DESCRIPTOR_SET;
+use tonic::transport::{Channel, Server};
+
+async fn start_server() -> ServerReflectionClient<Channel> {
+    let (addr, incoming) = common::listen().await;
+    let services = [
+        "grpc.ProximityDB",
+        "grpc.reflection.v1alpha.ServerReflection",
+    ];
+    let reflection = ReflectionService::new(FILE_DESCRIPTOR_SET, &services).unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(reflection.server())
+            .serve_with_incoming(incoming),
+    );
+    ServerReflectionClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_reflection() {
+    let mut client = start_server().await;
+    let requests = vec![
+        MessageRequest::ListServices(String::new()),
+        MessageRequest::FileContainingSymbol("grpc.ProximityDB".into()),
+    ];
+    let mut responses = client
+        .server_reflection_info(futures::stream::iter(requests.into_iter().map(
+            |message_request| ServerReflectionRequest {
+                host: String::new(),
+                message_request: Some(message_request),
+            },
+        )))
+        .await
+        .unwrap()
+        .into_inner();
+
+    match responses.message().await.unwrap().unwrap().message_response {
+        Some(MessageResponse::ListServicesResponse(response)) => {
+            let names: Vec<&str> = response.service.iter().map(|s| s.name.as_str()).collect();
+            assert_eq!(
+                names,
+                vec![
+                    "grpc.ProximityDB",
+                    "grpc.reflection.v1alpha.ServerReflection"
+                ]
+            );
+        }
+        other => panic!("Expected services, not {:?}", other),
+    }
+    match responses.message().await.unwrap().unwrap().message_response {
+        Some(MessageResponse::FileDescriptorResponse(response)) => {
+            let file = FileDescriptorProto::decode(&response.file_descriptor_proto[0][..]).unwrap();
+            let services: Vec<&str> = file.service.iter().map(|s| s.name()).collect();
+            assert!(services.contains(&"ProximityDB"));
+        }
+        other => panic!("Expected a file, not {:?}", other),
+    }
+    assert!(responses.message().await.unwrap().is_none());
+}
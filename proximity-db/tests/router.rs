@@ -0,0 +1,212 @@
+mod common;
+
+use proximity_db::handler::ProximityDBHandler;
+use proximity_db::router::Router;
+use proximity_db::sky::Sky;
+use proximity_grpc::proximity_db_client::ProximityDbClient;
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use proximity_grpc::{
+    AddRequest, CreateRequest, DeleteRequest, DescribeRequest, DropRequest, ListRequest,
+    Point as GrpcPoint, SearchRequest, SearchResponse,
+};
+use tonic::transport::{Channel, Server};
+use tonic::Code;
+
+async fn start_backend() -> String {
+    let (addr, incoming) = common::listen().await;
+    tokio::spawn(
+        Server::builder()
+            .add_service(ProximityDbServer::new(ProximityDBHandler::new(
+                Sky::default(),
+            )))
+            .serve_with_incoming(incoming),
+    );
+    format!("http://{}", addr)
+}
+
+async fn start_router(router: Router) -> String {
+    let (addr, incoming) = common::listen().await;
+    tokio::spawn(
+        Server::builder()
+            .add_service(ProximityDbServer::new(router))
+            .serve_with_incoming(incoming),
+    );
+    format!("http://{}", addr)
+}
+
+async fn connect(address: &str) -> ProximityDbClient<Channel> {
+    ProximityDbClient::connect(address.to_string())
+        .await
+        .unwrap()
+}
+
+fn points(count: usize) -> Vec<GrpcPoint> {
+    (0..count)
+        .map(|i| GrpcPoint {
+            coords: vec![i as f32; 8],
+        })
+        .collect()
+}
+
+async fn search(
+    client: &mut ProximityDbClient<Channel>,
+    request: SearchRequest,
+) -> Vec<SearchResponse> {
+    let mut stream = client.search(request).await.unwrap().into_inner();
+    let mut results = vec![];
+    while let Some(result) = stream.message().await.unwrap() {
+        results.push(result);
+    }
+    results
+}
+
+async fn count(client: &mut ProximityDbClient<Channel>, name: &str) -> u64 {
+    client
+        .describe(DescribeRequest {
+            name: name.into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .count
+}
+
+#[tokio::test]
+async fn test_router_shards_across_backends() {
+    let backends = vec![
+        start_backend().await,
+        start_backend().await,
+        start_backend().await,
+    ];
+    let mut router = connect(&start_router(Router::new(backends.clone())).await).await;
+
+    router
+        .create(CreateRequest {
+            name: "hello".into(),
+            dimensions: 8,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let added = router
+        .add(futures::stream::iter(vec![AddRequest {
+            name: "hello".into(),
+            points: points(100),
+            ..Default::default()
+        }]))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(added.total_added, 100);
+    assert_eq!(count(&mut router, "hello").await, 100);
+
+    // Every backend holds some of the points.
+    let mut total = 0;
+    for backend in &backends {
+        let held = count(&mut connect(backend).await, "hello").await;
+        assert!(held > 0);
+        total += held;
+    }
+    assert_eq!(total, 100);
+
+    // Radius searches return every match from every backend.
+    let results = search(
+        &mut router,
+        SearchRequest {
+            name: "hello".into(),
+            distance: 10.,
+            point: Some(points(11).pop().unwrap()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(results.len(), 7);
+
+    // Top-k searches return the nearest points overall, nearest first.
+    let results = search(
+        &mut router,
+        SearchRequest {
+            name: "hello".into(),
+            distance: 1000.,
+            point: Some(points(1).pop().unwrap()),
+            limit: 3,
+            ..Default::default()
+        },
+    )
+    .await;
+    let nearest: Vec<f32> = results
+        .iter()
+        .map(|r| r.point.as_ref().unwrap().coords[0])
+        .collect();
+    assert_eq!(nearest, vec![0., 1., 2.]);
+
+    let deleted = router
+        .delete(DeleteRequest {
+            name: "hello".into(),
+            points: points(10),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(deleted.deleted_count, 10);
+    assert_eq!(count(&mut router, "hello").await, 90);
+
+    router
+        .drop(DropRequest {
+            name: "hello".into(),
+        })
+        .await
+        .unwrap();
+    let error = router
+        .describe(DescribeRequest {
+            name: "hello".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn test_router_routes_by_prefix() {
+    let default = start_backend().await;
+    let logs = start_backend().await;
+    let mut router = connect(
+        &start_router(Router::new(vec![default.clone()]).route("logs-", vec![logs.clone()])).await,
+    )
+    .await;
+
+    for name in &["logs-today", "other"] {
+        router
+            .add(futures::stream::iter(vec![AddRequest {
+                name: name.to_string(),
+                points: points(5),
+                ..Default::default()
+            }]))
+            .await
+            .unwrap();
+    }
+
+    let mut stream = router
+        .list(ListRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let mut names = vec![];
+    while let Some(response) = stream.message().await.unwrap() {
+        names.push(response.name);
+    }
+    assert_eq!(names, vec!["logs-today", "other"]);
+
+    let mut logs = connect(&logs).await;
+    assert_eq!(count(&mut logs, "logs-today").await, 5);
+    let error = logs
+        .describe(DescribeRequest {
+            name: "other".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Code::NotFound);
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use proximity_db::handler::ProximityDBHandler;
+use proximity_db::shards::{Shards, LOCAL_ONLY_HEADER};
+use proximity_db::sky::Sky;
+use proximity_grpc::proximity_db_client::ProximityDbClient;
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use proximity_grpc::{
+    AddRequest, CompactRequest, DeleteRequest, DescribeRequest, Point as GrpcPoint,
+};
+use tonic::metadata::MetadataValue;
+use tonic::transport::Server;
+use tonic::{Code, Request};
+
+fn points(count: usize) -> Vec<GrpcPoint> {
+    (0..count)
+        .map(|i| GrpcPoint {
+            coords: vec![i as f32; 8],
+        })
+        .collect()
+}
+
+/// Starts two shards, returning their addresses.
+async fn start_shards() -> Vec<String> {
+    let (first, first_incoming) = common::listen().await;
+    let (second, second_incoming) = common::listen().await;
+    let addresses = vec![format!("http://{}", first), format!("http://{}", second)];
+    for (index, incoming) in vec![first_incoming, second_incoming]
+        .into_iter()
+        .enumerate()
+    {
+        let handler =
+            ProximityDBHandler::new(Sky::default()).sharded(Shards::new(index, addresses.clone()));
+        tokio::spawn(
+            Server::builder()
+                .add_service(ProximityDbServer::new(handler))
+                .serve_with_incoming(incoming),
+        );
+    }
+    addresses
+}
+
+#[tokio::test]
+async fn test_first_add_creates_every_shard() {
+    let addresses = start_shards().await;
+    let mut first = ProximityDbClient::connect(addresses[0].clone())
+        .await
+        .unwrap();
+    first
+        .add(futures::stream::iter(vec![AddRequest {
+            name: "hello".into(),
+            points: points(1),
+            ..Default::default()
+        }]))
+        .await
+        .unwrap();
+
+    // Both shards have the constellation, even though only one of them has the point.
+    let mut counts = vec![];
+    for address in &addresses {
+        let mut client = ProximityDbClient::connect(address.clone()).await.unwrap();
+        let mut request = Request::new(DescribeRequest {
+            name: "hello".into(),
+            ..Default::default()
+        });
+        request
+            .metadata_mut()
+            .insert(LOCAL_ONLY_HEADER, MetadataValue::from_static("1"));
+        let described = client.describe(request).await.unwrap().into_inner();
+        assert_eq!(described.dimensions, 8);
+        counts.push(described.count);
+    }
+    assert_eq!(counts.iter().sum::<u64>(), 1);
+
+    // So points with other dimensions are rejected by the other coordinator too.
+    let mut second = ProximityDbClient::connect(addresses[1].clone())
+        .await
+        .unwrap();
+    let status = second
+        .add(futures::stream::iter(vec![AddRequest {
+            name: "hello".into(),
+            points: vec![GrpcPoint {
+                coords: vec![1.; 16],
+            }],
+            ..Default::default()
+        }]))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_compact_every_shard() {
+    let addresses = start_shards().await;
+    let mut client = ProximityDbClient::connect(addresses[0].clone())
+        .await
+        .unwrap();
+    client
+        .add(futures::stream::iter(vec![AddRequest {
+            name: "hello".into(),
+            points: points(100),
+            ..Default::default()
+        }]))
+        .await
+        .unwrap();
+    client
+        .delete(DeleteRequest {
+            name: "hello".into(),
+            points: points(50),
+        })
+        .await
+        .unwrap();
+
+    // The deleted points are spread across both shards, and are purged from each of them.
+    let purged = client
+        .compact(CompactRequest {
+            name: "hello".into(),
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .purged_count;
+    assert_eq!(purged, 50);
+}
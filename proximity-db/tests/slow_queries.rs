@@ -0,0 +1,72 @@
+use proximity_db::handler::ProximityDBHandler;
+use proximity_db::sky::Sky;
+use proximity_db::telemetry::{LogFormat, LogOptions, SlowQueryOptions};
+use proximity_grpc::proximity_db_server::ProximityDb;
+use proximity_grpc::{Point, SearchRequest};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Request;
+use tracing_appender::rolling::Rotation;
+
+async fn search(handler: &ProximityDBHandler, distance: f32) {
+    let mut stream = handler
+        .search(Request::new(SearchRequest {
+            name: "hello".into(),
+            distance,
+            point: Some(Point {
+                coords: vec![0.; 8],
+            }),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    while stream.recv().await.is_some() {}
+}
+
+#[tokio::test]
+async fn test_slow_queries_are_logged_to_file() {
+    let directory = std::env::temp_dir().join(format!("proximity-slow-{}", std::process::id()));
+    let path = directory.join("slow.log");
+    LogOptions {
+        log_level: "info".into(),
+        log_format: LogFormat::Text,
+        jaeger_agent: None,
+        service_name: "proximity-test".into(),
+    }
+    .init(Some(&SlowQueryOptions {
+        slow_query_threshold: None,
+        slow_query_log: Some(path.clone()),
+        slow_query_rotation: Rotation::NEVER,
+    }))
+    .unwrap();
+
+    let sky = Arc::new(Sky::default());
+    sky.add("hello".into(), (0..10).map(|i| vec![i as f32; 8]).collect())
+        .unwrap();
+    let fast =
+        ProximityDBHandler::new(sky.clone()).with_slow_query_threshold(Duration::from_secs(3600));
+    search(&fast, 1.).await;
+    let slow = ProximityDBHandler::new(sky).with_slow_query_threshold(Duration::from_millis(0));
+    search(&slow, 100.).await;
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&directory).ok();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 1);
+    for expected in &[
+        r#""constellation":"hello""#,
+        r#""radius":100.0"#,
+        r#""dimensions":8"#,
+        r#""scanned":10"#,
+        r#""results":10"#,
+        r#""wall_time_ms":"#,
+    ] {
+        assert!(
+            lines[0].contains(expected),
+            "{} not in {}",
+            expected,
+            lines[0]
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use proximity_db::sky::Sky;
+use proximity_db::telemetry::{self, LogFormat, LogOptions};
+use std::net::UdpSocket;
+use std::time::Duration;
+
+fn contains(packet: &[u8], text: &str) -> bool {
+    packet
+        .windows(text.len())
+        .any(|window| window == text.as_bytes())
+}
+
+#[test]
+fn test_exports_spans_to_agent() {
+    // Stands in for a Jaeger agent, which receives batches of spans as UDP packets.
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    LogOptions {
+        log_level: "debug".into(),
+        log_format: LogFormat::Json,
+        jaeger_agent: Some(collector.local_addr().unwrap().to_string()),
+        service_name: "proximity-test".into(),
+    }
+    .init(None)
+    .unwrap();
+
+    let sky = Sky::default();
+    sky.add("hello".into(), vec![vec![1.; 8]]).unwrap();
+    telemetry::shutdown();
+
+    let mut buffer = [0; 65_536];
+    loop {
+        let size = collector
+            .recv(&mut buffer)
+            .expect("The span was not exported");
+        let packet = &buffer[..size];
+        if contains(packet, "add_with_ttl") {
+            assert!(contains(packet, "proximity-test"));
+            assert!(contains(packet, "hello"));
+            break;
+        }
+    }
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use proximity_db::handler::ProximityDBHandler;
+use proximity_db::router::Router;
+use proximity_db::sky::Sky;
+use proximity_db::tls::TlsOptions;
+use proximity_grpc::proximity_db_client::ProximityDbClient;
+use proximity_grpc::proximity_db_server::ProximityDbServer;
+use proximity_grpc::{CreateRequest, ListRequest};
+use rcgen::{BasicConstraints, Certificate as GeneratedCertificate, CertificateParams, IsCa};
+use std::path::PathBuf;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity, Server};
+
+struct Pki {
+    ca: GeneratedCertificate,
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("proximity-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Pki {
+            ca: GeneratedCertificate::from_params(params).unwrap(),
+            dir,
+        }
+    }
+
+    fn ca_pem(&self) -> String {
+        self.ca.serialize_pem().unwrap()
+    }
+
+    /// Issues a certificate for `localhost` signed by the CA, returning the certificate and key.
+    fn issue(&self) -> (String, String) {
+        let cert =
+            GeneratedCertificate::from_params(CertificateParams::new(
+                vec!["localhost".to_string()],
+            ))
+            .unwrap();
+        (
+            cert.serialize_pem_with_signer(&self.ca).unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    fn write(&self, name: &str, pem: &str) -> PathBuf {
+        let path = self.dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+}
+
+async fn start_server(tls: &TlsOptions) -> String {
+    let (addr, incoming) = common::listen().await;
+    let config = tls.server_config().unwrap().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .tls_config(config)
+            .add_service(ProximityDbServer::new(ProximityDBHandler::new(
+                Sky::default(),
+            )))
+            .serve_with_incoming(incoming),
+    );
+    format!("https://localhost:{}", addr.port())
+}
+
+async fn list(address: &str, tls: ClientTlsConfig) -> anyhow::Result<()> {
+    let channel = Channel::from_shared(address.to_string())?
+        .tls_config(tls)
+        .connect()
+        .await?;
+    ProximityDbClient::new(channel)
+        .list(ListRequest::default())
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let pki = Pki::new("server");
+    let (cert, key) = pki.issue();
+    let address = start_server(&TlsOptions {
+        tls_cert: Some(pki.write("server.pem", &cert)),
+        tls_key: Some(pki.write("server.key", &key)),
+        ..Default::default()
+    })
+    .await;
+
+    let trusted = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(pki.ca_pem()))
+        .domain_name("localhost");
+    list(&address, trusted).await.unwrap();
+
+    // A client that doesn't trust the server's CA can't connect.
+    let untrusted = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(Pki::new("other").ca_pem()))
+        .domain_name("localhost");
+    assert!(list(&address, untrusted).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    let pki = Pki::new("mutual");
+    let (cert, key) = pki.issue();
+    let address = start_server(&TlsOptions {
+        tls_cert: Some(pki.write("server.pem", &cert)),
+        tls_key: Some(pki.write("server.key", &key)),
+        tls_client_ca: Some(pki.write("ca.pem", &pki.ca_pem())),
+        ..Default::default()
+    })
+    .await;
+
+    let client_config = || {
+        ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(pki.ca_pem()))
+            .domain_name("localhost")
+    };
+    assert!(list(&address, client_config()).await.is_err());
+
+    let (client_cert, client_key) = pki.issue();
+    let with_identity = client_config().identity(Identity::from_pem(client_cert, client_key));
+    list(&address, with_identity).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_router_connects_over_tls() {
+    let pki = Pki::new("router");
+    let (cert, key) = pki.issue();
+    let ca = pki.write("ca.pem", &pki.ca_pem());
+    let backend = start_server(&TlsOptions {
+        tls_cert: Some(pki.write("server.pem", &cert)),
+        tls_key: Some(pki.write("server.key", &key)),
+        tls_client_ca: Some(ca.clone()),
+        ..Default::default()
+    })
+    .await;
+
+    let (client_cert, client_key) = pki.issue();
+    let options = TlsOptions {
+        tls_ca: Some(ca),
+        tls_client_cert: Some(pki.write("client.pem", &client_cert)),
+        tls_client_key: Some(pki.write("client.key", &client_key)),
+        ..Default::default()
+    };
+    let tls = options.client_config().unwrap().unwrap();
+    assert!(create_through(Router::new(vec![backend.clone()]))
+        .await
+        .is_err());
+    create_through(Router::new(vec![backend]).with_tls(tls))
+        .await
+        .unwrap();
+}
+
+async fn create_through(router: Router) -> Result<(), tonic::Status> {
+    let (addr, incoming) = common::listen().await;
+    tokio::spawn(
+        Server::builder()
+            .add_service(ProximityDbServer::new(router))
+            .serve_with_incoming(incoming),
+    );
+    ProximityDbClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap()
+        .create(CreateRequest {
+            name: "hello".into(),
+            dimensions: 8,
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
@@ -1,7 +1,32 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+const PROTOS: &[&str] = &[
+    "proto/grpc.proto",
+    "proto/health.proto",
+    "proto/reflection.proto",
+];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
-        .type_attribute(".", "#[derive(Serialize)]")
+        .type_attribute(".", "#[derive(serde::Serialize)]")
         .type_attribute(".", "#[serde(rename_all = \"camelCase\")]")
-        .compile(&["proto/grpc.proto"], &["proto/"])?;
+        .compile(PROTOS, &["proto/"])?;
+
+    // The reflection service describes the protos to clients with this.
+    let descriptor_set = PathBuf::from(env::var("OUT_DIR")?).join("descriptor_set.bin");
+    let status = Command::new(prost_build::protoc())
+        .arg("--include_imports")
+        .arg("-I")
+        .arg("proto/")
+        .arg("-I")
+        .arg(prost_build::protoc_include())
+        .arg(format!("--descriptor_set_out={}", descriptor_set.display()))
+        .args(PROTOS)
+        .status()?;
+    if !status.success() {
+        return Err(format!("protoc failed to write the descriptor set: {}", status).into());
+    }
     Ok(())
 }
@@ -1,2 +1 @@
-use serde::Serialize;
 tonic::include_proto!("grpc");
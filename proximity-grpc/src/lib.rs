@@ -1,2 +1,8 @@
 mod grpc;
+pub mod health;
+pub mod reflection;
 pub use crate::grpc::*;
+
+/// An encoded `FileDescriptorSet` describing every proto file, for the reflection service.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/descriptor_set.bin"));
@@ -0,0 +1 @@
+tonic::include_proto!("grpc.reflection.v1alpha");
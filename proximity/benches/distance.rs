@@ -1,8 +1,10 @@
 use criterion::{
-    criterion_group, criterion_main, measurement::WallTime, BatchSize, BenchmarkGroup, BenchmarkId,
-    Criterion, Throughput,
+    black_box, criterion_group, criterion_main, measurement::WallTime, BatchSize, BenchmarkGroup,
+    BenchmarkId, Criterion, Throughput,
+};
+use proximity::{
+    sizes::*, Constellation, Kernel, Neighbour, SIMDConstellation, SimpleConstellation,
 };
-use proximity::{sizes::*, Constellation, SIMDConstellation, SimpleConstellation};
 use rand::{distributions::Standard, Rng};
 use std::time::Duration;
 
@@ -30,7 +32,7 @@ fn bench_search(
             |b| {
                 b.iter_batched(
                     || random_point.clone(),
-                    |p| constellation.find(p, 0.).collect::<Vec<(f32, Vec<f32>)>>(),
+                    |p| constellation.find(p, 0.).collect::<Vec<Neighbour>>(),
                     BatchSize::PerIteration,
                 );
             },
@@ -38,6 +40,23 @@ fn bench_search(
     }
 }
 
+fn bench_kernels(c: &mut Criterion) {
+    let mut kernels = c.benchmark_group("kernels");
+    for dimension in &[8, 64, 128, 256, 512] {
+        let points = random_points(2, *dimension);
+        kernels.throughput(Throughput::Elements(*dimension as u64));
+        for kernel in Kernel::available() {
+            let distance = kernel.squared_euclidean();
+            kernels.bench_with_input(
+                BenchmarkId::new(kernel.name(), dimension),
+                &points,
+                |b, points| b.iter(|| distance(black_box(&points[0]), black_box(&points[1]))),
+            );
+        }
+    }
+    kernels.finish();
+}
+
 fn run_bench(c: &mut Criterion) {
     rayon::ThreadPoolBuilder::new()
         .num_threads(num_cpus::get() - 1)
@@ -72,5 +91,5 @@ fn run_bench(c: &mut Criterion) {
     simd.finish();
 }
 
-criterion_group!(benches, run_bench);
+criterion_group!(benches, bench_kernels, run_bench);
 criterion_main!(benches);
@@ -0,0 +1,257 @@
+//! Hand written distance kernels.
+//!
+//! The widest instruction set the CPU supports is picked at runtime with [`Kernel::detect`], so a
+//! single binary gets AVX-512 on servers that have it and still runs on machines that don't.
+
+/// Computes the squared euclidean distance between two slices of the same length.
+pub type DistanceFn = fn(&[f32], &[f32]) -> f32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Portable,
+    Avx2,
+    Avx512,
+    Neon,
+}
+
+impl Kernel {
+    /// The fastest kernel supported by the current CPU.
+    pub fn detect() -> Kernel {
+        Kernel::available()
+            .into_iter()
+            .last()
+            .unwrap_or(Kernel::Portable)
+    }
+
+    /// Every kernel supported by the current CPU, slowest first.
+    pub fn available() -> Vec<Kernel> {
+        [Kernel::Portable, Kernel::Neon, Kernel::Avx2, Kernel::Avx512]
+            .iter()
+            .copied()
+            .filter(|k| k.is_supported())
+            .collect()
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Portable => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kernel::Portable => "portable",
+            Kernel::Avx2 => "avx2",
+            Kernel::Avx512 => "avx512",
+            Kernel::Neon => "neon",
+        }
+    }
+
+    /// Returns the squared euclidean distance function for this kernel.
+    ///
+    /// Panics if the kernel is not supported by the current CPU, as calling it would be undefined
+    /// behaviour.
+    pub fn squared_euclidean(self) -> DistanceFn {
+        assert!(
+            self.is_supported(),
+            "The {} kernel is not supported by this CPU",
+            self.name()
+        );
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx2 => x86::squared_euclidean_avx2,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx512 => x86::squared_euclidean_avx512,
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => aarch64::squared_euclidean_neon,
+            _ => squared_euclidean_portable,
+        }
+    }
+}
+
+fn squared_euclidean_portable(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    // Eight independent accumulators let the compiler vectorise this without needing to reorder
+    // floating point additions.
+    let mut acc = [0f32; 8];
+    let chunks_a = a.chunks_exact(8);
+    let chunks_b = b.chunks_exact(8);
+    let remainder: f32 = chunks_a
+        .remainder()
+        .iter()
+        .zip(chunks_b.remainder())
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum();
+    for (ca, cb) in chunks_a.zip(chunks_b) {
+        for i in 0..8 {
+            let d = ca[i] - cb[i];
+            acc[i] += d * d;
+        }
+    }
+    acc.iter().sum::<f32>() + remainder
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    pub fn squared_euclidean_avx2(a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        // Safety: this is only handed out by `Kernel::squared_euclidean` once avx2 and fma have
+        // been detected, and both slices have been checked to be the same length.
+        unsafe { avx2(a, b) }
+    }
+
+    pub fn squared_euclidean_avx512(a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        // Safety: as above, but for avx512f.
+        unsafe { avx512(a, b) }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn avx2(a: &[f32], b: &[f32]) -> f32 {
+        let len = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 16 <= len {
+            let d0 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+            let d1 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i + 8)), _mm256_loadu_ps(pb.add(i + 8)));
+            acc0 = _mm256_fmadd_ps(d0, d0, acc0);
+            acc1 = _mm256_fmadd_ps(d1, d1, acc1);
+            i += 16;
+        }
+        if i + 8 <= len {
+            let d0 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+            acc0 = _mm256_fmadd_ps(d0, d0, acc0);
+            i += 8;
+        }
+        let acc = _mm256_add_ps(acc0, acc1);
+        let sum4 = _mm_add_ps(_mm256_castps256_ps128(acc), _mm256_extractf128_ps(acc, 1));
+        let sum2 = _mm_add_ps(sum4, _mm_movehl_ps(sum4, sum4));
+        let sum1 = _mm_add_ss(sum2, _mm_shuffle_ps(sum2, sum2, 0b01));
+        let mut total = _mm_cvtss_f32(sum1);
+        while i < len {
+            let d = *pa.add(i) - *pb.add(i);
+            total += d * d;
+            i += 1;
+        }
+        total
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn avx512(a: &[f32], b: &[f32]) -> f32 {
+        let len = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut acc0 = _mm512_setzero_ps();
+        let mut acc1 = _mm512_setzero_ps();
+        let mut i = 0;
+        while i + 32 <= len {
+            let d0 = _mm512_sub_ps(_mm512_loadu_ps(pa.add(i)), _mm512_loadu_ps(pb.add(i)));
+            let d1 = _mm512_sub_ps(
+                _mm512_loadu_ps(pa.add(i + 16)),
+                _mm512_loadu_ps(pb.add(i + 16)),
+            );
+            acc0 = _mm512_fmadd_ps(d0, d0, acc0);
+            acc1 = _mm512_fmadd_ps(d1, d1, acc1);
+            i += 32;
+        }
+        if i + 16 <= len {
+            let d0 = _mm512_sub_ps(_mm512_loadu_ps(pa.add(i)), _mm512_loadu_ps(pb.add(i)));
+            acc0 = _mm512_fmadd_ps(d0, d0, acc0);
+            i += 16;
+        }
+        if i < len {
+            // Masked loads zero the lanes past the end of the slices, which contribute nothing.
+            let mask: __mmask16 = (1 << (len - i)) - 1;
+            let d0 = _mm512_sub_ps(
+                _mm512_maskz_loadu_ps(mask, pa.add(i)),
+                _mm512_maskz_loadu_ps(mask, pb.add(i)),
+            );
+            acc1 = _mm512_fmadd_ps(d0, d0, acc1);
+        }
+        _mm512_reduce_add_ps(_mm512_add_ps(acc0, acc1))
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use std::arch::aarch64::*;
+
+    pub fn squared_euclidean_neon(a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        // Safety: only handed out once neon has been detected, with equal length slices.
+        unsafe { neon(a, b) }
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn neon(a: &[f32], b: &[f32]) -> f32 {
+        let len = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut acc0 = vdupq_n_f32(0.);
+        let mut acc1 = vdupq_n_f32(0.);
+        let mut i = 0;
+        while i + 8 <= len {
+            let d0 = vsubq_f32(vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+            let d1 = vsubq_f32(vld1q_f32(pa.add(i + 4)), vld1q_f32(pb.add(i + 4)));
+            acc0 = vfmaq_f32(acc0, d0, d0);
+            acc1 = vfmaq_f32(acc1, d1, d1);
+            i += 8;
+        }
+        let mut total = vaddvq_f32(vaddq_f32(acc0, acc1));
+        while i < len {
+            let d = *pa.add(i) - *pb.add(i);
+            total += d * d;
+            i += 1;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| (x - y).powf(2.)).sum()
+    }
+
+    #[test]
+    fn test_kernels_match_reference() {
+        for kernel in Kernel::available() {
+            let distance = kernel.squared_euclidean();
+            for dims in &[1, 4, 7, 8, 15, 16, 17, 33, 64, 100, 512] {
+                let a: Vec<f32> = (0..*dims).map(|i| i as f32 * 0.5).collect();
+                let b: Vec<f32> = (0..*dims).map(|i| (i % 7) as f32).collect();
+                let expected = reference(&a, &b);
+                let given = distance(&a, &b);
+                assert!(
+                    (expected - given).abs() <= expected * 1e-5,
+                    "{} kernel with {} dims: expected {}, got {}",
+                    kernel.name(),
+                    dims,
+                    expected,
+                    given
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_is_supported() {
+        assert!(Kernel::detect().is_supported());
+        assert_eq!(Kernel::available().first(), Some(&Kernel::Portable));
+    }
+}
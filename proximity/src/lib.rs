@@ -1,5 +1,11 @@
+pub mod kernels;
+mod query_pool;
+mod segments;
 mod simple;
+mod store;
 
+pub use kernels::Kernel;
+pub use query_pool::QueryPool;
 pub use simple::SimpleConstellation;
 pub use typenum::consts as sizes;
 
@@ -9,26 +15,92 @@ mod simd_vec;
 #[cfg(feature = "simd")]
 pub use simd_vec::SIMDConstellation;
 
-pub type QueryIterator = Box<dyn Iterator<Item = (f32, Vec<f32>)>>;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use store::VectorStore;
+
+/// A point matched by a search, along with its distance from the query.
+///
+/// The coordinates are borrowed from the constellation's storage rather than copied.
+pub struct Neighbour {
+    pub distance: f32,
+    store: Arc<VectorStore>,
+    index: usize,
+}
+
+impl Neighbour {
+    pub(crate) fn new(distance: f32, store: Arc<VectorStore>, index: usize) -> Self {
+        Neighbour {
+            distance,
+            store,
+            index,
+        }
+    }
+
+    pub fn coords(&self) -> &[f32] {
+        self.store.get(self.index)
+    }
+}
+
+impl fmt::Debug for Neighbour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Neighbour")
+            .field("distance", &self.distance)
+            .field("coords", &self.coords())
+            .finish()
+    }
+}
+
+pub type QueryIterator = Box<dyn Iterator<Item = Neighbour> + Send>;
+
+/// The current time in seconds since the Unix epoch, which is how expiry times are given.
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
 
 pub trait Constellation: Sync + Send {
     fn add_points(&self, points: Vec<Vec<f32>>);
+    /// Adds points that stop matching searches once `expires_at` has passed, given in seconds
+    /// since the Unix epoch.
+    fn add_expiring_points(&self, points: Vec<Vec<f32>>, expires_at: u32);
     fn find(&self, point: Vec<f32>, within: f32) -> QueryIterator;
-
+    /// Deletes every point equal to one of `points`, returning how many were deleted.
+    fn delete_points(&self, points: Vec<Vec<f32>>) -> usize;
+    /// Physically removes deleted points, returning how many were removed.
+    fn compact(&self) -> usize;
+    /// Deletes every point that expired by `now`, returning how many were deleted.
+    fn expire(&self, now: u32) -> usize;
+    /// Calls `f` with every point that hasn't been deleted, and when it expires, or 0 if it
+    /// doesn't.
+    fn for_each_point(&self, f: &mut dyn FnMut(&[f32], u32));
+
+    /// The number of live points.
     fn count(&self) -> usize;
+    /// The number of deleted points that are still taking up space until the next compaction.
+    fn deleted_count(&self) -> usize;
+    /// The number of live points with an expiry time, including ones that have expired but
+    /// haven't been deleted yet.
+    fn expiring_count(&self) -> usize;
     fn dimensions(&self) -> usize;
     fn memory_size(&self) -> usize;
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Constellation;
+    use crate::{unix_time, Constellation, QueryIterator};
     use std::iter;
 
     fn make_vec(dims: usize, value: f32) -> Vec<f32> {
         iter::repeat(value).take(dims).collect()
     }
 
+    fn collect(results: QueryIterator) -> Vec<(f32, Vec<f32>)> {
+        results.map(|n| (n.distance, n.coords().to_vec())).collect()
+    }
+
     pub fn test_length(constellation: &dyn Constellation) {
         assert_eq!(constellation.count(), 0);
         let dims = constellation.dimensions();
@@ -49,6 +121,62 @@ mod tests {
         assert_eq!(constellation.count(), 2);
     }
 
+    pub fn test_delete(constellation: &dyn Constellation) {
+        let dims = constellation.dimensions();
+        constellation.add_points(vec![
+            make_vec(dims, 1.),
+            make_vec(dims, 2.),
+            make_vec(dims, 1.),
+        ]);
+        assert_eq!(constellation.delete_points(vec![make_vec(dims, 1.)]), 2);
+        assert_eq!(constellation.count(), 1);
+        assert_eq!(constellation.deleted_count(), 2);
+        assert_eq!(collect(constellation.find(make_vec(dims, 1.), 0.)), vec![]);
+
+        assert_eq!(constellation.compact(), 2);
+        assert_eq!(constellation.deleted_count(), 0);
+        assert_eq!(constellation.memory_size(), dims * 4);
+        assert_eq!(
+            collect(constellation.find(make_vec(dims, 2.), 0.)),
+            vec![(0., make_vec(dims, 2.))]
+        );
+    }
+
+    pub fn test_expiry(constellation: &dyn Constellation) {
+        let dims = constellation.dimensions();
+        let now = unix_time();
+        constellation.add_points(vec![make_vec(dims, 1.)]);
+        constellation.add_expiring_points(vec![make_vec(dims, 2.)], now - 1);
+        constellation.add_expiring_points(vec![make_vec(dims, 3.)], now + 3600);
+        assert_eq!(constellation.expiring_count(), 2);
+
+        // Expired points don't match, even before they're deleted.
+        assert_eq!(collect(constellation.find(make_vec(dims, 2.), 0.)), vec![]);
+        assert_eq!(collect(constellation.find(make_vec(dims, 3.), 0.)).len(), 1);
+
+        assert_eq!(constellation.expire(now), 1);
+        assert_eq!(constellation.expiring_count(), 1);
+        assert_eq!(constellation.count(), 2);
+        assert_eq!(constellation.expire(now + 3600), 1);
+        assert_eq!(constellation.count(), 1);
+    }
+
+    pub fn test_for_each_point(constellation: &dyn Constellation) {
+        let dims = constellation.dimensions();
+        constellation.add_points(vec![make_vec(dims, 1.), make_vec(dims, 2.)]);
+        constellation.add_expiring_points(vec![make_vec(dims, 3.)], 100);
+        constellation.delete_points(vec![make_vec(dims, 2.)]);
+
+        let mut points = vec![];
+        constellation.for_each_point(&mut |coords, expires_at| {
+            points.push((coords.to_vec(), expires_at));
+        });
+        assert_eq!(
+            points,
+            vec![(make_vec(dims, 1.), 0), (make_vec(dims, 3.), 100)]
+        );
+    }
+
     pub fn test_query(constellation: &dyn Constellation) {
         assert_eq!(constellation.dimensions(), 16);
         let dims = constellation.dimensions();
@@ -56,7 +184,7 @@ mod tests {
         // Insert two vectors with repeated elements (1 and 10)
         constellation.add_points(vec![make_vec(dims, 1.), make_vec(dims, 10.)]);
         // Match against the vector full of 1's
-        let items: Vec<(f32, Vec<f32>)> = constellation.find(make_vec(dims, 1.), 0.).collect();
+        let items = collect(constellation.find(make_vec(dims, 1.), 0.));
         assert_eq!(items, vec![(0., make_vec(dims, 1.))]);
 
         let inner = vec![
@@ -64,7 +192,7 @@ mod tests {
         ];
 
         // The threaded version of this has a race condition where these are not always ordered.
-        let mut items2: Vec<(f32, Vec<f32>)> = constellation.find(inner, 36.).collect();
+        let mut items2 = collect(constellation.find(inner, 36.));
         items2.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
         assert_eq!(
             items2,
@@ -74,7 +202,7 @@ mod tests {
             ]
         );
 
-        let items3: Vec<(f32, Vec<f32>)> = constellation.find(make_vec(dims, 0.), 0.).collect();
+        let items3 = collect(constellation.find(make_vec(dims, 0.), 0.));
         assert_eq!(items3, vec![]);
     }
 }
@@ -0,0 +1,118 @@
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+static GLOBAL_POOL: OnceCell<QueryPool> = OnceCell::new();
+
+/// Runs searches directly on the rayon pool, limiting how many can scan at the same time.
+///
+/// Every search used to get its own OS thread which then waited on rayon. Under load these piled
+/// up faster than they finished, so instead callers now wait here for a free slot and the scan is
+/// queued straight onto the pool.
+pub struct QueryPool {
+    max_concurrent: usize,
+    running: Mutex<usize>,
+    finished: Condvar,
+    busy_nanos: AtomicU64,
+}
+
+impl QueryPool {
+    pub fn new(max_concurrent: usize) -> Self {
+        assert!(max_concurrent > 0, "At least one query must be allowed");
+        QueryPool {
+            max_concurrent,
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+            busy_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Makes this the pool used by every constellation. This can only be done once, before the
+    /// first search, and the pool is given back if one has already been set.
+    pub fn build_global(self) -> Result<(), QueryPool> {
+        GLOBAL_POOL.set(self)
+    }
+
+    /// The global pool, which allows as many concurrent queries as rayon has threads unless
+    /// `build_global` was called first.
+    pub fn global() -> &'static QueryPool {
+        GLOBAL_POOL.get_or_init(|| QueryPool::new(rayon::current_num_threads()))
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// The number of scans that are currently running.
+    pub fn running(&self) -> usize {
+        *self.running.lock().unwrap()
+    }
+
+    /// The total time rayon threads have spent scanning for searches. Its rate divided by the
+    /// number of rayon threads is how busy the pool is.
+    pub fn busy_time(&self) -> Duration {
+        Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Adds the time a rayon thread spent on part of a scan.
+    pub(crate) fn record_busy(&self, busy: Duration) {
+        self.busy_nanos
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Blocks until fewer than `max_concurrent` scans are running, then queues `scan` on the
+    /// rayon pool.
+    pub fn spawn<F>(&'static self, scan: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.max_concurrent {
+            running = self.finished.wait(running).unwrap();
+        }
+        *running += 1;
+        drop(running);
+
+        rayon::spawn(move || {
+            let _slot = Slot(self);
+            scan();
+        });
+    }
+}
+
+/// Gives a slot back to the pool when a scan finishes, even if it panicked.
+struct Slot(&'static QueryPool);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_limits_concurrency() {
+        let pool: &'static QueryPool = Box::leak(Box::new(QueryPool::new(2)));
+        let (tx, rx) = channel();
+        for _ in 0..8 {
+            let tx = tx.clone();
+            pool.spawn(move || {
+                tx.send(pool.running()).unwrap();
+            });
+        }
+        drop(tx);
+        let observed: Vec<usize> = rx.iter().collect();
+        assert_eq!(observed.len(), 8);
+        assert!(observed.iter().all(|running| *running <= 2));
+
+        pool.record_busy(Duration::from_millis(3));
+        pool.record_busy(Duration::from_millis(4));
+        assert_eq!(pool.busy_time(), Duration::from_millis(7));
+    }
+}
@@ -0,0 +1,268 @@
+use crate::store::VectorStore;
+use arc_swap::ArcSwap;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Segments are sized so that each is a reasonable unit of work for a rayon task.
+const SEGMENT_BYTES: usize = 16 * 1024 * 1024;
+
+/// New tails start out small, so that tiny constellations don't reserve a whole segment.
+const MIN_CAPACITY: usize = 64;
+
+/// An append-only list of `VectorStore` segments.
+///
+/// Only the last segment, the tail, is ever written to. Once it reaches the segment capacity it
+/// is sealed and a new tail is started, so sealed segments are immutable and are never copied
+/// again. Readers load the current list of segments without taking a lock, and writers only hold
+/// a lock against each other, so ingest never blocks a search and a long search never blocks
+/// ingest.
+///
+/// Deletes and compaction also take the writer lock, so that a delete can't mark a point in a
+/// segment that compaction has already copied.
+pub struct Segments {
+    dims: usize,
+    segment_capacity: usize,
+    segments: ArcSwap<Vec<Arc<VectorStore>>>,
+    writer: Mutex<()>,
+}
+
+impl Segments {
+    pub fn new(dims: usize) -> Self {
+        let segment_capacity = (SEGMENT_BYTES / (dims * std::mem::size_of::<f32>())).max(1);
+        Segments::with_segment_capacity(dims, segment_capacity)
+    }
+
+    pub fn with_segment_capacity(dims: usize, segment_capacity: usize) -> Self {
+        Segments {
+            dims,
+            segment_capacity,
+            segments: ArcSwap::from_pointee(vec![]),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The segments as they are right now. Points added afterwards may or may not be visible in
+    /// the tail, but every visible point is fully written.
+    pub fn snapshot(&self) -> Arc<Vec<Arc<VectorStore>>> {
+        self.segments.load_full()
+    }
+
+    pub fn append<P: AsRef<[f32]>>(&self, points: &[P]) {
+        self.append_expiring(points, 0)
+    }
+
+    /// Appends points that expire at `expires_at`, in seconds since the Unix epoch, or never if
+    /// it's 0.
+    pub fn append_expiring<P: AsRef<[f32]>>(&self, points: &[P], expires_at: u32) {
+        let _writer = self.writer.lock().unwrap();
+        let mut remaining = points;
+        loop {
+            let segments = self.snapshot();
+            if let Some(tail) = segments.last() {
+                // Safety: appends are serialised by the writer lock.
+                let added = unsafe { tail.append(remaining, |_| expires_at) };
+                remaining = &remaining[added..];
+            }
+            if remaining.is_empty() {
+                return;
+            }
+
+            // The tail is full. Grow it if it's smaller than a segment, otherwise seal it.
+            let mut next: Vec<Arc<VectorStore>> = segments.iter().cloned().collect();
+            match next.last_mut() {
+                Some(tail) if tail.capacity() < self.segment_capacity => {
+                    let capacity = self.next_capacity(tail.capacity(), remaining.len());
+                    *tail = Arc::new(tail.grow(capacity));
+                }
+                _ => {
+                    let capacity = self.next_capacity(0, remaining.len());
+                    next.push(Arc::new(VectorStore::with_capacity(self.dims, capacity)));
+                }
+            }
+            self.segments.store(Arc::new(next));
+        }
+    }
+
+    fn next_capacity(&self, current: usize, needed: usize) -> usize {
+        (current * 2)
+            .max(current + needed)
+            .max(MIN_CAPACITY)
+            .min(self.segment_capacity)
+    }
+
+    /// Deletes every point equal to one of `points`, returning how many were deleted.
+    pub fn delete<P: AsRef<[f32]>>(&self, points: &[P]) -> usize {
+        let targets: HashSet<Vec<u32>> = points
+            .iter()
+            .map(|p| p.as_ref().iter().map(|c| c.to_bits()).collect())
+            .collect();
+        let _writer = self.writer.lock().unwrap();
+        self.snapshot()
+            .par_iter()
+            .map(|segment| {
+                segment
+                    .live()
+                    .filter(|(index, coords)| {
+                        let bits: Vec<u32> = coords.iter().map(|c| c.to_bits()).collect();
+                        targets.contains(&bits) && segment.delete(*index)
+                    })
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Deletes every point that expired at or before `now`, returning how many were deleted.
+    pub fn expire(&self, now: u32) -> usize {
+        let _writer = self.writer.lock().unwrap();
+        self.snapshot()
+            .par_iter()
+            .filter(|segment| segment.expiring() > 0)
+            .map(|segment| {
+                segment
+                    .live()
+                    .filter(|(index, _)| segment.is_expired(*index, now) && segment.delete(*index))
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Physically removes deleted points and merges small segments, returning how many points
+    /// were removed.
+    ///
+    /// Full segments without any deleted points are kept as they are. The live points from every
+    /// other segment are copied into new, densely packed segments, which replace the old ones in
+    /// a single swap. Searches that are already running keep the segments they started with.
+    pub fn compact(&self) -> usize {
+        let _writer = self.writer.lock().unwrap();
+        let segments = self.snapshot();
+        let (mut next, rewrite): (Vec<_>, Vec<_>) = segments
+            .iter()
+            .cloned()
+            .partition(|s| s.deleted() == 0 && s.len() == self.segment_capacity);
+
+        let purged: usize = rewrite.iter().map(|s| s.deleted()).sum();
+        if purged == 0 && rewrite.len() <= 1 {
+            return 0;
+        }
+
+        let live: Vec<(&[f32], u32)> = rewrite
+            .iter()
+            .flat_map(|s| {
+                s.live()
+                    .map(move |(index, coords)| (coords, s.expires_at(index)))
+            })
+            .collect();
+        for chunk in live.chunks(self.segment_capacity) {
+            let segment = VectorStore::with_capacity(self.dims, self.next_capacity(0, chunk.len()));
+            let coords: Vec<&[f32]> = chunk.iter().map(|(coords, _)| *coords).collect();
+            // Safety: the new segment isn't shared with anyone yet.
+            unsafe { segment.append(&coords, |offset| chunk[offset].1) };
+            next.push(Arc::new(segment));
+        }
+        self.segments.store(Arc::new(next));
+        purged
+    }
+
+    /// Calls `f` with every point that hasn't been deleted, and when it expires.
+    pub fn for_each_live(&self, f: &mut dyn FnMut(&[f32], u32)) {
+        for segment in self.snapshot().iter() {
+            for (index, coords) in segment.live() {
+                f(coords, segment.expires_at(index));
+            }
+        }
+    }
+
+    /// The number of points in every segment, including deleted points that haven't been
+    /// compacted yet.
+    pub fn len(&self) -> usize {
+        self.snapshot().iter().map(|s| s.len()).sum()
+    }
+
+    pub fn deleted(&self) -> usize {
+        self.snapshot().iter().map(|s| s.deleted()).sum()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.snapshot().iter().map(|s| s.memory_size()).sum()
+    }
+
+    /// The number of points with an expiry time that haven't been deleted yet.
+    pub fn expiring(&self) -> usize {
+        self.snapshot().iter().map(|s| s.expiring()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seals_full_segments() {
+        let segments = Segments::with_segment_capacity(2, 100);
+        let points: Vec<Vec<f32>> = (0..250).map(|i| vec![i as f32; 2]).collect();
+        segments.append(&points);
+        assert_eq!(segments.len(), 250);
+
+        let snapshot = segments.snapshot();
+        let capacities: Vec<usize> = snapshot.iter().map(|s| s.capacity()).collect();
+        assert_eq!(capacities, vec![100, 100, 64]);
+        assert_eq!(snapshot[2].get(49), &[249., 249.]);
+    }
+
+    #[test]
+    fn test_delete_and_compact() {
+        let segments = Segments::with_segment_capacity(1, 100);
+        let points: Vec<Vec<f32>> = (0..250).map(|i| vec![(i % 10) as f32]).collect();
+        segments.append(&points);
+
+        assert_eq!(segments.delete(&[vec![3.], vec![4.]]), 50);
+        assert_eq!(segments.delete(&[vec![3.]]), 0);
+        assert_eq!(segments.deleted(), 50);
+
+        let before = segments.snapshot();
+        assert_eq!(segments.compact(), 50);
+        assert_eq!(segments.len(), 200);
+        assert_eq!(segments.deleted(), 0);
+        assert_eq!(segments.snapshot().len(), 2);
+        assert!(segments
+            .snapshot()
+            .iter()
+            .all(|s| s.live().all(|(_, c)| c != [3.] && c != [4.])));
+
+        // Nothing left to do.
+        assert_eq!(segments.compact(), 0);
+        // The old segments are untouched for anyone still reading them.
+        assert_eq!(before.iter().map(|s| s.len()).sum::<usize>(), 250);
+    }
+
+    #[test]
+    fn test_expire() {
+        let segments = Segments::with_segment_capacity(1, 100);
+        segments.append(&(0..150).map(|i| vec![i as f32]).collect::<Vec<_>>());
+        segments.append_expiring(&[vec![1000.], vec![1001.]], 10);
+        segments.append_expiring(&[vec![1002.]], 20);
+        assert_eq!(segments.expiring(), 3);
+
+        assert_eq!(segments.expire(9), 0);
+        assert_eq!(segments.expire(10), 2);
+        assert_eq!(segments.expiring(), 1);
+
+        // Compaction keeps the expiry times of the points it copies.
+        assert_eq!(segments.compact(), 2);
+        assert_eq!(segments.expiring(), 1);
+        assert_eq!(segments.expire(20), 1);
+    }
+
+    #[test]
+    fn test_snapshot_is_stable() {
+        let segments = Segments::with_segment_capacity(1, 100);
+        segments.append(&[vec![1.]]);
+        let before = segments.snapshot();
+        segments.append(&(0..200).map(|i| vec![i as f32]).collect::<Vec<_>>());
+        // The earlier snapshot doesn't see the segments that were added after it was taken.
+        assert_eq!(before.len(), 1);
+        assert_eq!(before[0].get(0), &[1.]);
+        assert_eq!(segments.len(), 201);
+    }
+}
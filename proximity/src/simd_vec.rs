@@ -1,113 +1,269 @@
-use crate::Constellation;
-use bytemuck::cast;
-use crossbeam_channel::bounded;
-use nalgebra::{
-    allocator::Allocator, distance, DefaultAllocator, DimName, NamedDim, Point, VectorN,
-};
+use crate::kernels::{DistanceFn, Kernel};
+use crate::segments::Segments;
+use crate::store::VectorStore;
+use crate::{unix_time, Constellation, Neighbour, QueryIterator, QueryPool};
+use crossbeam_channel::{bounded, select, unbounded, Receiver, Sender, TrySendError};
 use rayon::prelude::*;
-use simba::simd::{SimdValue, WideF32x4};
-use std::sync::{Arc, RwLock};
-
-pub type Point32<DimX> = Point<WideF32x4, DimX>;
-
-fn make_point<DimX>(point: Vec<f32>) -> Point32<DimX::Name>
-where
-    DimX: NamedDim,
-    DefaultAllocator: Allocator<WideF32x4, DimX::Name>,
-{
-    let wide_vec: Vec<WideF32x4> = point
-        .chunks(4)
-        .map(|c| WideF32x4::from([c[0], c[1], c[2], c[3]]))
-        .collect();
-    VectorN::<WideF32x4, DimX::Name>::from_vec(wide_vec).into()
-}
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use typenum::Unsigned;
+
+/// The number of f32s in a 128 bit lane. Constellation sizes are expressed in lanes.
+const LANES: usize = 4;
+
+/// The number of points each rayon task scans.
+const BATCH_SIZE: usize = 1024;
+
+/// The number of batches of matches buffered for a search before the scan waits for the reader.
+const BUFFERED_BATCHES: usize = 16;
 
 /// A constellation contains lots of points.
-pub struct SIMDConstellation<DimX>
-where
-    DimX: NamedDim,
-    DefaultAllocator: Allocator<WideF32x4, DimX::Name>,
-{
-    points: Arc<RwLock<Vec<Point32<DimX::Name>>>>,
+///
+/// `DimX` is the number of 128 bit lanes in each point, so a `SIMDConstellation<U16>` holds 64
+/// dimensional points.
+pub struct SIMDConstellation<DimX: Unsigned> {
+    points: Segments,
+    distance: DistanceFn,
+    dims: PhantomData<fn() -> DimX>,
 }
 
-impl<DimX> Default for SIMDConstellation<DimX>
-where
-    DimX: NamedDim,
-    DefaultAllocator: Allocator<WideF32x4, DimX::Name>,
-{
+impl<DimX: Unsigned> Default for SIMDConstellation<DimX> {
     fn default() -> Self {
         SIMDConstellation {
-            points: Arc::new(RwLock::new(Vec::new())),
+            points: Segments::new(DimX::to_usize() * LANES),
+            distance: Kernel::detect().squared_euclidean(),
+            dims: PhantomData,
         }
     }
 }
 
-impl<DimX> Constellation for SIMDConstellation<DimX>
-where
-    DimX: NamedDim + Sync,
-    DefaultAllocator: Allocator<WideF32x4, DimX::Name>,
-    <DefaultAllocator as Allocator<WideF32x4, DimX::Name>>::Buffer: Send + Sync,
-{
+impl<DimX: Unsigned> Constellation for SIMDConstellation<DimX> {
+    #[tracing::instrument(level = "debug", skip_all, fields(points = points.len()))]
     fn add_points(&self, points: Vec<Vec<f32>>) {
-        self.points
-            .clone()
-            .write()
-            .unwrap()
-            .extend(points.into_iter().map(make_point::<DimX>))
-    }
-
-    fn find(&self, point: Vec<f32>, within: f32) -> Box<dyn Iterator<Item = (f32, Vec<f32>)>> {
-        let point = make_point::<DimX>(point);
-        let (tx, rx) = bounded(100);
-        let points = self.points.clone();
-
-        std::thread::Builder::new()
-            .name("find_iterate".to_string())
-            .spawn(move || {
-                points
-                    .read()
-                    .unwrap()
-                    .par_iter()
-                    .try_for_each_with(tx.clone(), |tx, p| {
-                        let result = distance(&point, &p);
-                        let dist: f32 = cast::<_, [f32; 4]>(result.0)
-                            .iter()
-                            .map(|i| i.powf(2.))
-                            .sum::<f32>()
-                            .sqrt();
-                        if dist <= within {
-                            // This seems absolutely horrible. Is there really not a better way?
-                            let flat_coords: Vec<f32> = p
-                                .coords
-                                .iter()
-                                .map(|p| cast::<_, [f32; 4]>(p.0).to_vec())
-                                .flatten()
-                                .collect();
-                            return tx.send((dist, flat_coords));
-                        }
-                        Ok(())
-                    })
-                    .ok();
-                // This is really important. Without this line there are sporadic stack overflows
-                // with the benchmark - this thread doesn't terminate fast enough after `par_iter()`
-                // finishes, and threads pile up.
-                std::mem::drop(tx);
+        self.points.append(&points);
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, points), fields(points = points.len()))]
+    fn add_expiring_points(&self, points: Vec<Vec<f32>>, expires_at: u32) {
+        self.points.append_expiring(&points, expires_at);
+    }
+
+    fn find(&self, point: Vec<f32>, within: f32) -> QueryIterator {
+        if within < 0. {
+            return Box::new(std::iter::empty());
+        }
+        let dims = self.dimensions();
+        let segments = self.points.snapshot();
+        let batches = segments
+            .iter()
+            .enumerate()
+            .flat_map(|(index, segment)| {
+                let len = segment.as_slice().len() / dims;
+                (0..len)
+                    .step_by(BATCH_SIZE)
+                    .map(move |start| (index, start, (start + BATCH_SIZE).min(len)))
             })
-            .expect("Error spawning iterator thread");
-        Box::new(rx.into_iter())
+            .collect();
+        let (tx, rx) = bounded(BUFFERED_BATCHES);
+        let (stopped_tx, stopped) = unbounded();
+        let scan = Arc::new(Scan {
+            point,
+            // Comparing squared distances saves a square root for every point that doesn't match.
+            within_squared: within * within,
+            distance: self.distance,
+            dims,
+            now: unix_time(),
+            segments,
+            batches,
+            next: AtomicUsize::new(0),
+            tx,
+            parked: Mutex::new(Vec::new()),
+            stopped: stopped_tx,
+            // The scan outlives this call, so its span is entered on the query pool.
+            span: tracing::debug_span!("scan", dimensions = dims, scanned = self.points.len()),
+        });
+        let running = scan.clone().resume();
+        Box::new(Matches {
+            scan,
+            rx,
+            stopped,
+            running,
+            parked: VecDeque::new(),
+            current: Vec::new().into_iter(),
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(points = points.len()))]
+    fn delete_points(&self, points: Vec<Vec<f32>>) -> usize {
+        self.points.delete(&points)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn compact(&self) -> usize {
+        self.points.compact()
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn expire(&self, now: u32) -> usize {
+        self.points.expire(now)
+    }
+
+    fn for_each_point(&self, f: &mut dyn FnMut(&[f32], u32)) {
+        self.points.for_each_live(f)
     }
 
     fn count(&self) -> usize {
-        self.points.read().unwrap().len()
+        self.points.len() - self.points.deleted()
+    }
+
+    fn deleted_count(&self) -> usize {
+        self.points.deleted()
+    }
+
+    fn expiring_count(&self) -> usize {
+        self.points.expiring()
     }
 
     fn dimensions(&self) -> usize {
-        DimX::Name::dim() * WideF32x4::lanes()
+        DimX::to_usize() * LANES
     }
 
     fn memory_size(&self) -> usize {
-        std::mem::size_of::<Point32<DimX::Name>>() * self.count()
+        self.points.memory_size()
+    }
+}
+
+/// A search's scan of a constellation, which runs on the query pool until the reader falls
+/// behind, then gives its rayon threads back until the reader catches up.
+struct Scan {
+    point: Vec<f32>,
+    within_squared: f32,
+    distance: DistanceFn,
+    dims: usize,
+    now: u32,
+    segments: Arc<Vec<Arc<VectorStore>>>,
+    /// The segment and range of points in each batch.
+    batches: Vec<(usize, usize, usize)>,
+    /// The next batch to scan.
+    next: AtomicUsize,
+    tx: Sender<Vec<Neighbour>>,
+    /// Matches that were scanned after the channel filled up.
+    parked: Mutex<Vec<Vec<Neighbour>>>,
+    /// Told each time the scan stops, having either finished or filled the channel.
+    stopped: Sender<()>,
+    span: tracing::Span,
+}
+
+impl Scan {
+    /// Queues the rest of the scan on the query pool, returning whether there was any left.
+    fn resume(self: Arc<Self>) -> bool {
+        if self.next.load(Ordering::Relaxed) >= self.batches.len() {
+            return false;
+        }
+        let pool = QueryPool::global();
+        pool.spawn(move || {
+            let _entered = self.span.enter();
+            (0..rayon::current_num_threads())
+                .into_par_iter()
+                .for_each(|_| self.scan_batches(pool));
+            self.stopped.send(()).ok();
+        });
+        true
+    }
+
+    /// Scans batches until they run out or one of them doesn't fit in the channel.
+    fn scan_batches(&self, pool: &QueryPool) {
+        loop {
+            let batch = self.next.fetch_add(1, Ordering::Relaxed);
+            let &(segment, start, end) = match self.batches.get(batch) {
+                Some(batch) => batch,
+                None => return,
+            };
+            let started = Instant::now();
+            let segment = &self.segments[segment];
+            let matches: Vec<Neighbour> = segment.as_slice()[start * self.dims..end * self.dims]
+                .chunks_exact(self.dims)
+                .zip(start..end)
+                .filter_map(|(coords, index)| {
+                    let dist = (self.distance)(&self.point, coords);
+                    if dist > self.within_squared
+                        || segment.is_deleted(index)
+                        || segment.is_expired(index, self.now)
+                    {
+                        return None;
+                    }
+                    Some(Neighbour::new(dist.sqrt(), segment.clone(), index))
+                })
+                .collect();
+            pool.record_busy(started.elapsed());
+            if matches.is_empty() {
+                continue;
+            }
+            match self.tx.try_send(matches) {
+                Ok(()) => {}
+                Err(TrySendError::Full(matches)) => {
+                    self.parked.lock().unwrap().push(matches);
+                    return;
+                }
+                // The reader has gone away, so there's no point scanning the rest.
+                Err(TrySendError::Disconnected(_)) => {
+                    self.next.store(self.batches.len(), Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The reading end of a scan, which resumes it once half the buffered matches have been read.
+struct Matches {
+    scan: Arc<Scan>,
+    rx: Receiver<Vec<Neighbour>>,
+    stopped: Receiver<()>,
+    running: bool,
+    parked: VecDeque<Vec<Neighbour>>,
+    current: std::vec::IntoIter<Neighbour>,
+}
+
+impl Iterator for Matches {
+    type Item = Neighbour;
+
+    fn next(&mut self) -> Option<Neighbour> {
+        loop {
+            if let Some(neighbour) = self.current.next() {
+                return Some(neighbour);
+            }
+            if let Some(matches) = self.parked.pop_front() {
+                self.current = matches.into_iter();
+                continue;
+            }
+            if !self.running && self.rx.len() <= BUFFERED_BATCHES / 2 {
+                self.parked
+                    .extend(self.scan.parked.lock().unwrap().drain(..));
+                self.running = self.scan.clone().resume();
+                if !self.parked.is_empty() {
+                    continue;
+                }
+                if !self.running && self.rx.is_empty() {
+                    return None;
+                }
+            }
+            let matches = if self.running {
+                select! {
+                    recv(self.rx) -> matches => matches,
+                    recv(self.stopped) -> _ => {
+                        self.running = false;
+                        continue;
+                    }
+                }
+            } else {
+                self.rx.recv()
+            };
+            self.current = matches.ok()?.into_iter();
+        }
     }
 }
 
@@ -134,8 +290,61 @@ mod tests {
         crate::tests::test_add_multiple(&SIMDConstellation::<U16>::default());
     }
 
+    #[test]
+    fn test_delete() {
+        crate::tests::test_delete(&SIMDConstellation::<U16>::default());
+    }
+
     #[test]
     fn test_query() {
         crate::tests::test_query(&SIMDConstellation::<U4>::default());
     }
+
+    #[test]
+    fn test_expiry() {
+        crate::tests::test_expiry(&SIMDConstellation::<U1>::default());
+    }
+
+    #[test]
+    fn test_for_each_point() {
+        crate::tests::test_for_each_point(&SIMDConstellation::<U1>::default());
+    }
+
+    #[test]
+    fn test_grows() {
+        let constellation = SIMDConstellation::<U1>::default();
+        for i in 0..10 {
+            constellation.add_points(vec![vec![i as f32; 4]]);
+        }
+        assert_eq!(constellation.count(), 10);
+        let results: Vec<Neighbour> = constellation.find(vec![9.; 4], 0.).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].coords(), &[9.; 4]);
+    }
+
+    #[test]
+    fn test_more_matches_than_buffered() {
+        let constellation = SIMDConstellation::<U1>::default();
+        let count = BATCH_SIZE * BUFFERED_BATCHES * 2;
+        constellation.add_points(vec![vec![0.; 4]; count]);
+        let results = constellation.find(vec![0.; 4], 1.);
+        // Give the scan time to fill the channel before reading anything.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(results.count(), count);
+    }
+
+    #[test]
+    fn test_unread_searches_release_the_pool() {
+        let constellation = SIMDConstellation::<U1>::default();
+        let count = BATCH_SIZE * BUFFERED_BATCHES * 2;
+        constellation.add_points(vec![vec![0.; 4]; count]);
+        // More searches than the pool has threads or slots, none of which have been read.
+        let unread: Vec<QueryIterator> = (0..QueryPool::global().max_concurrent() + 1)
+            .map(|_| constellation.find(vec![0.; 4], 1.))
+            .collect();
+        assert_eq!(constellation.find(vec![0.; 4], 1.).count(), count);
+        for results in unread {
+            assert_eq!(results.count(), count);
+        }
+    }
 }
@@ -1,53 +1,104 @@
-use crate::Constellation;
+use crate::segments::Segments;
+use crate::{unix_time, Constellation, Neighbour, QueryIterator};
 use generic_array::{ArrayLength, GenericArray};
 use rayon::prelude::*;
-use std::sync::RwLock;
+use std::marker::PhantomData;
 
 /// A slow, reference constellation.
-#[derive(Default)]
 pub struct SimpleConstellation<N: ArrayLength<f32>> {
-    points: RwLock<Vec<GenericArray<f32, N>>>,
+    points: Segments,
+    dims: PhantomData<fn() -> N>,
+}
+
+impl<N: ArrayLength<f32>> Default for SimpleConstellation<N> {
+    fn default() -> Self {
+        SimpleConstellation {
+            points: Segments::new(N::to_usize()),
+            dims: PhantomData,
+        }
+    }
 }
 
 impl<N: ArrayLength<f32>> Constellation for SimpleConstellation<N> {
+    #[tracing::instrument(level = "debug", skip_all, fields(points = points.len()))]
     fn add_points(&self, points: Vec<Vec<f32>>) {
-        self.points
-            .write()
-            .expect("Error getting write lock")
-            .extend(
-                points
-                    .into_iter()
-                    .map(|p| GenericArray::<f32, N>::from_exact_iter(p).expect("Incorrect length")),
-            );
+        self.points.append(&points);
     }
 
-    fn find(&self, point: Vec<f32>, within: f32) -> Box<dyn Iterator<Item = (f32, Vec<f32>)>> {
+    #[tracing::instrument(level = "debug", skip(self, points), fields(points = points.len()))]
+    fn add_expiring_points(&self, points: Vec<Vec<f32>>, expires_at: u32) {
+        self.points.append_expiring(&points, expires_at);
+    }
+
+    fn find(&self, point: Vec<f32>, within: f32) -> QueryIterator {
         let arr = GenericArray::<f32, N>::from_exact_iter(point).expect("Incorrect length");
-        // let results = arr![];
-        let things: Vec<(f32, Vec<f32>)> = self
+        let now = unix_time();
+        let span = tracing::debug_span!(
+            "scan",
+            dimensions = N::to_usize(),
+            scanned = self.points.len()
+        );
+        let _entered = span.enter();
+        let things: Vec<Neighbour> = self
             .points
-            .read()
-            .expect("Error unwrapping points")
+            .snapshot()
             .par_iter()
-            .filter_map(|p| {
-                let distance = p
-                    .iter()
-                    .zip(&arr)
-                    .map(|(a, b)| (a - b).powf(2.))
-                    .sum::<f32>()
-                    .sqrt();
-                if distance <= within {
-                    return Some((distance, p.clone().into_iter().collect()));
-                }
-                None
+            .flat_map(|segment| {
+                let arr = &arr;
+                segment
+                    .as_slice()
+                    .par_chunks(N::to_usize())
+                    .enumerate()
+                    .filter_map(move |(index, p)| {
+                        let distance = p
+                            .iter()
+                            .zip(arr)
+                            .map(|(a, b)| (a - b).powf(2.))
+                            .sum::<f32>()
+                            .sqrt();
+                        if distance <= within
+                            && !segment.is_deleted(index)
+                            && !segment.is_expired(index, now)
+                        {
+                            return Some(Neighbour::new(distance, segment.clone(), index));
+                        }
+                        None
+                    })
             })
             .collect();
 
         Box::new(things.into_iter())
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(points = points.len()))]
+    fn delete_points(&self, points: Vec<Vec<f32>>) -> usize {
+        self.points.delete(&points)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn compact(&self) -> usize {
+        self.points.compact()
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn expire(&self, now: u32) -> usize {
+        self.points.expire(now)
+    }
+
+    fn for_each_point(&self, f: &mut dyn FnMut(&[f32], u32)) {
+        self.points.for_each_live(f)
+    }
+
     fn count(&self) -> usize {
-        self.points.read().expect("Error getting read lock").len()
+        self.points.len() - self.points.deleted()
+    }
+
+    fn deleted_count(&self) -> usize {
+        self.points.deleted()
+    }
+
+    fn expiring_count(&self) -> usize {
+        self.points.expiring()
     }
 
     fn dimensions(&self) -> usize {
@@ -55,7 +106,7 @@ impl<N: ArrayLength<f32>> Constellation for SimpleConstellation<N> {
     }
 
     fn memory_size(&self) -> usize {
-        std::mem::size_of::<GenericArray<f32, N>>() * self.count()
+        self.points.memory_size()
     }
 }
 
@@ -82,8 +133,23 @@ mod tests {
         crate::tests::test_add_multiple(&SimpleConstellation::<U16>::default());
     }
 
+    #[test]
+    fn test_delete() {
+        crate::tests::test_delete(&SimpleConstellation::<U16>::default());
+    }
+
     #[test]
     fn test_query() {
         crate::tests::test_query(&SimpleConstellation::<U16>::default());
     }
+
+    #[test]
+    fn test_expiry() {
+        crate::tests::test_expiry(&SimpleConstellation::<U4>::default());
+    }
+
+    #[test]
+    fn test_for_each_point() {
+        crate::tests::test_for_each_point(&SimpleConstellation::<U4>::default());
+    }
 }
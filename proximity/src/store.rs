@@ -0,0 +1,281 @@
+use once_cell::sync::OnceCell;
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Stores are aligned to a cache line, which is also the width of an AVX-512 register.
+const ALIGNMENT: usize = 64;
+
+/// A fixed capacity, append-only arena of vectors that all have the same number of dimensions.
+///
+/// Vectors are stored back to back in a single aligned allocation with a stride of `dims`, so a
+/// scan is one streaming pass over memory and a point can be handed out as a plain slice.
+/// Appending only ever writes past the published length, which means readers never observe a
+/// partially written point and can keep scanning while a single writer adds to the store.
+///
+/// Deleted points are only marked in a tombstone bitmap, and are physically removed by copying
+/// the live points into a new store when the constellation is compacted.
+///
+/// Points can also have an expiry time, in seconds since the Unix epoch. Stores where no point
+/// expires don't allocate any space for them.
+pub struct VectorStore {
+    data: NonNull<f32>,
+    dims: usize,
+    capacity: usize,
+    len: AtomicUsize,
+    tombstones: Box<[AtomicU64]>,
+    deleted: AtomicUsize,
+    /// When each point expires, or 0 if it never does.
+    expiries: OnceCell<Box<[AtomicU32]>>,
+    /// The number of points with an expiry time that haven't been deleted.
+    expiring: AtomicUsize,
+}
+
+// The only mutation of `data` goes through `append`, which writes to memory no reader can see yet.
+unsafe impl Send for VectorStore {}
+unsafe impl Sync for VectorStore {}
+
+impl VectorStore {
+    pub fn with_capacity(dims: usize, capacity: usize) -> Self {
+        assert!(dims > 0, "A store must have at least one dimension");
+        let layout = Self::layout(dims, capacity);
+        // Safety: the layout is never zero sized.
+        let data = unsafe { alloc_zeroed(layout) } as *mut f32;
+        VectorStore {
+            data: NonNull::new(data).unwrap_or_else(|| handle_alloc_error(layout)),
+            dims,
+            capacity,
+            len: AtomicUsize::new(0),
+            tombstones: (0..capacity.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            deleted: AtomicUsize::new(0),
+            expiries: OnceCell::new(),
+            expiring: AtomicUsize::new(0),
+        }
+    }
+
+    fn layout(dims: usize, capacity: usize) -> Layout {
+        let size = (dims * capacity * std::mem::size_of::<f32>()).max(ALIGNMENT);
+        Layout::from_size_align(size, ALIGNMENT).expect("Store is too large")
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of points that have been published to readers.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// The number of published points that have been deleted but not yet compacted away.
+    pub fn deleted(&self) -> usize {
+        self.deleted.load(Ordering::Acquire)
+    }
+
+    pub fn is_deleted(&self, index: usize) -> bool {
+        self.tombstones[index / 64].load(Ordering::Relaxed) & (1 << (index % 64)) != 0
+    }
+
+    /// Marks a point as deleted, returning false if it already was.
+    pub fn delete(&self, index: usize) -> bool {
+        assert!(index < self.len());
+        let bit = 1 << (index % 64);
+        let previous = self.tombstones[index / 64].fetch_or(bit, Ordering::Relaxed);
+        if previous & bit != 0 {
+            return false;
+        }
+        if self.expires_at(index) != 0 {
+            self.expiring.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.deleted.fetch_add(1, Ordering::Release);
+        true
+    }
+
+    /// When a point expires, or 0 if it never does.
+    pub fn expires_at(&self, index: usize) -> u32 {
+        self.expiries
+            .get()
+            .map_or(0, |expiries| expiries[index].load(Ordering::Relaxed))
+    }
+
+    pub fn is_expired(&self, index: usize, now: u32) -> bool {
+        let expires_at = self.expires_at(index);
+        expires_at != 0 && expires_at <= now
+    }
+
+    /// The number of points that haven't been deleted and have an expiry time.
+    pub fn expiring(&self) -> usize {
+        self.expiring.load(Ordering::Relaxed)
+    }
+
+    fn expiries(&self) -> &[AtomicU32] {
+        self.expiries
+            .get_or_init(|| (0..self.capacity).map(|_| AtomicU32::new(0)).collect())
+    }
+
+    /// Every published point that hasn't been deleted, with its index.
+    pub fn live(&self) -> impl Iterator<Item = (usize, &[f32])> {
+        self.as_slice()
+            .chunks_exact(self.dims)
+            .enumerate()
+            .filter(move |(index, _)| !self.is_deleted(*index))
+    }
+
+    /// All published points as one contiguous slice.
+    pub fn as_slice(&self) -> &[f32] {
+        // Safety: everything below `len` has been written and is never written again.
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.len() * self.dims) }
+    }
+
+    pub fn get(&self, index: usize) -> &[f32] {
+        let start = index * self.dims;
+        &self.as_slice()[start..start + self.dims]
+    }
+
+    /// The number of bytes used by published points, excluding spare capacity.
+    pub fn memory_size(&self) -> usize {
+        self.len() * self.dims * std::mem::size_of::<f32>()
+    }
+
+    /// Appends as many of `points` as will fit, returning how many were added. `expires_at` gives
+    /// the expiry time of each point by its position in `points`, or 0 if it never expires.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may append to a store at a time.
+    pub unsafe fn append<P: AsRef<[f32]>>(
+        &self,
+        points: &[P],
+        expires_at: impl Fn(usize) -> u32,
+    ) -> usize {
+        let len = self.len.load(Ordering::Relaxed);
+        let added = points.len().min(self.capacity - len);
+        for (offset, point) in points[..added].iter().enumerate() {
+            let point = point.as_ref();
+            assert_eq!(point.len(), self.dims, "Incorrect length");
+            ptr::copy_nonoverlapping(
+                point.as_ptr(),
+                self.data.as_ptr().add((len + offset) * self.dims),
+                self.dims,
+            );
+            let expires_at = expires_at(offset);
+            if expires_at != 0 {
+                self.expiries()[len + offset].store(expires_at, Ordering::Relaxed);
+                self.expiring.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.len.store(len + added, Ordering::Release);
+        added
+    }
+
+    /// Copies every published point into a new store with a larger capacity.
+    pub fn grow(&self, capacity: usize) -> VectorStore {
+        let len = self.len();
+        assert!(capacity >= len);
+        let grown = VectorStore::with_capacity(self.dims, capacity);
+        // Safety: `grown` is not shared with anyone yet, and has room for `len` points.
+        unsafe {
+            ptr::copy_nonoverlapping(self.data.as_ptr(), grown.data.as_ptr(), len * self.dims);
+        }
+        for (word, grown_word) in self.tombstones.iter().zip(grown.tombstones.iter()) {
+            grown_word.store(word.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        grown.deleted.store(self.deleted(), Ordering::Relaxed);
+        if let Some(expiries) = self.expiries.get() {
+            for (expiry, grown_expiry) in expiries.iter().zip(grown.expiries().iter()) {
+                grown_expiry.store(expiry.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+        }
+        grown.expiring.store(self.expiring(), Ordering::Relaxed);
+        grown.len.store(len, Ordering::Release);
+        grown
+    }
+}
+
+impl Drop for VectorStore {
+    fn drop(&mut self) {
+        // Safety: allocated in `with_capacity` with the same layout.
+        unsafe {
+            dealloc(
+                self.data.as_ptr() as *mut u8,
+                Self::layout(self.dims, self.capacity),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let store = VectorStore::with_capacity(2, 3);
+        assert_eq!(store.len(), 0);
+        let added = unsafe { store.append(&[vec![1., 2.], vec![3., 4.]], |_| 0) };
+        assert_eq!(added, 2);
+        assert_eq!(store.as_slice(), &[1., 2., 3., 4.]);
+        assert_eq!(store.get(1), &[3., 4.]);
+        assert_eq!(store.memory_size(), 16);
+
+        // Only one more point fits.
+        let added = unsafe { store.append(&[vec![5., 6.], vec![7., 8.]], |_| 0) };
+        assert_eq!(added, 1);
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn test_grow() {
+        let store = VectorStore::with_capacity(2, 1);
+        unsafe { store.append(&[vec![1., 2.]], |_| 0) };
+        let grown = store.grow(4);
+        assert_eq!(grown.capacity(), 4);
+        assert_eq!(grown.as_slice(), &[1., 2.]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let store = VectorStore::with_capacity(1, 100);
+        unsafe { store.append(&(0..70).map(|i| vec![i as f32]).collect::<Vec<_>>(), |_| 0) };
+        assert!(store.delete(1));
+        assert!(store.delete(65));
+        assert!(!store.delete(65));
+        assert_eq!(store.deleted(), 2);
+        assert!(store.is_deleted(65));
+
+        let live: Vec<usize> = store.live().map(|(index, _)| index).collect();
+        assert_eq!(live.len(), 68);
+        assert!(!live.contains(&1));
+
+        // Tombstones survive the store being grown.
+        let grown = store.grow(200);
+        assert!(grown.is_deleted(1));
+        assert_eq!(grown.deleted(), 2);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let store = VectorStore::with_capacity(1, 10);
+        unsafe { store.append(&[vec![1.]], |_| 0) };
+        assert!(store.expiries.get().is_none());
+        unsafe { store.append(&[vec![2.], vec![3.]], |offset| 100 + offset as u32) };
+        assert_eq!(store.expiring(), 2);
+        assert!(!store.is_expired(0, 1000));
+        assert!(store.is_expired(1, 100));
+        assert!(!store.is_expired(2, 100));
+
+        let grown = store.grow(20);
+        assert_eq!(grown.expires_at(2), 101);
+        assert_eq!(grown.expiring(), 2);
+        grown.delete(1);
+        assert_eq!(grown.expiring(), 1);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let store = VectorStore::with_capacity(3, 10);
+        assert_eq!(store.as_slice().as_ptr() as usize % ALIGNMENT, 0);
+    }
+}